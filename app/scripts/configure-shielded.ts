@@ -0,0 +1,36 @@
+import type { Araza } from '@generated-types/araza'
+import { PublicKey } from '@solana/web3.js'
+
+import * as anchor from '@coral-xyz/anchor'
+
+// Before executing this script,
+//   * set the ANCHOR_WALLET environment variable to the path of your keypair
+//   * set the ANCHOR_PROVIDER environment variable to the URL of the Solana RPC node
+const provider = anchor.AnchorProvider.env()
+anchor.setProvider(provider)
+
+const program = anchor.workspace.Araza as anchor.Program<Araza>
+
+const main = async () => {
+	// The vault has to live under the same token program as DD,
+	// whichever one `configure` or `configure_confidential` made it with:
+	const [ddMint] = PublicKey.findProgramAddressSync(
+		[Buffer.from('mint/dd')],
+		program.programId,
+	)
+	const mint = await provider.connection.getAccountInfo(ddMint)
+	if (!mint) {
+		throw new Error('DD is not minted yet: configure the program first')
+	}
+
+	const tx = await program.methods
+		.configureShielded()
+		.accounts({
+			signer: provider.wallet.publicKey,
+			tokenProgram: mint.owner,
+		})
+		.rpc()
+	console.log(tx)
+}
+
+main().catch(console.error)
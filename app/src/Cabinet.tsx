@@ -57,7 +57,7 @@ const Cabinet = () => {
 				<br />
 				<code>123456789</code>
 				<br />
-				And the amount is always one hundred.
+				And the amount is always one hundred ĐĐ at two USD apiece.
 			</p>
 			<p>
 				Here is the list of all the active offers:
@@ -14,8 +14,12 @@ import {
 	offerFiat,
 	redeem,
 	untilFinalized,
+	type Quote,
 } from './transactions'
 
+/** Two dollars per DD, so that a hundred DD is what the sample statements transfer */
+const demoQuote: Quote = { rail: 'c2c', currency: 'USD', price: '2' }
+
 const Actions = (args: {
 	publicKey: string
 	pendingTx: Accessor<string | null>
@@ -60,7 +64,12 @@ const Actions = (args: {
 					type="button"
 					onClick={async () => {
 						await doAndWait(() =>
-							offerDd(new PublicKey(args.publicKey), 100_000000n, '987654321'),
+							offerDd(
+								new PublicKey(args.publicKey),
+								100_000000n,
+								'987654321',
+								demoQuote,
+							),
 						)
 					}}
 				>
@@ -74,6 +83,7 @@ const Actions = (args: {
 								new PublicKey(args.publicKey),
 								100_000000n,
 								'123456789',
+								demoQuote,
 							),
 						)
 					}}
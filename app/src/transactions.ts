@@ -81,19 +81,35 @@ export const redeem = async (publicKey: PublicKey, amount: bigint) => {
 		.rpc()
 }
 
+/** How the fiat side of a deal moves */
+export type PaymentRail = 'sepa' | 'fasterPayments' | 'pix' | 'upi' | 'c2c'
+
+/** What an offer asks for in fiat: the rail, an ISO 4217 currency, and its units per one DD */
+export type Quote = { rail: PaymentRail; currency: string; price: string }
+
 /** Generate a byte string that represents a deal type and its author */
 const seal = ({
 	amount,
 	bankAccount,
 	cryptoAddress,
-}: { bankAccount: string; cryptoAddress: string; amount: string }) => {
+	currency,
+	price,
+	rail,
+}: { bankAccount: string; cryptoAddress: string; amount: string } & Quote) => {
 	const encoder = new TextEncoder()
-	// Values sorted in lexicographical order
-	return encoder.encode(`${amount}\n${bankAccount}\n${cryptoAddress}`)
+	// Values sorted in lexicographical order of their names in the request,
+	// where the crypto address goes by `publicKey`
+	return encoder.encode(
+		`${amount}\n${bankAccount}\n${currency}\n${price}\n${cryptoAddress}\n${rail}`,
+	)
 }
 
 /** Ask the user to generate a proof that associates their bank account with their crypto address */
-const askUserForSignature = async (amount: bigint, bankAccount: string) => {
+const askUserForSignature = async (
+	amount: bigint,
+	bankAccount: string,
+	quote: Quote,
+) => {
 	const solana = self.phantom?.solana
 	if (!solana) {
 		throw new Error('Solana wallet expected')
@@ -105,6 +121,7 @@ const askUserForSignature = async (amount: bigint, bankAccount: string) => {
 				amount: `${amount}`,
 				bankAccount,
 				cryptoAddress: publicKey.toBase58(),
+				...quote,
 			}),
 		)
 		return bs58.encode(signature)
@@ -119,8 +136,9 @@ export const offerDd = async (
 	publicKey: PublicKey,
 	amount: bigint,
 	bankAccount: string,
+	quote: Quote,
 ) => {
-	const signature = await askUserForSignature(amount, bankAccount)
+	const signature = await askUserForSignature(amount, bankAccount, quote)
 	if (!signature) {
 		return null
 	}
@@ -133,6 +151,7 @@ export const offerDd = async (
 			amount: `${amount}`,
 			bankAccount,
 			publicKey: publicKey.toBase58(),
+			...quote,
 			signature,
 		}),
 	})
@@ -152,27 +171,54 @@ export const offerFiat = async (
 	publicKey: PublicKey,
 	amount: bigint,
 	bankAccount: string,
+	quote: Quote,
 ) => {
-	const signature = await askUserForSignature(amount, bankAccount)
+	const signature = await askUserForSignature(amount, bankAccount, quote)
 	if (!signature) {
 		return null
 	}
-	await fetch('/offer-fiat', {
-		method: 'POST',
-		headers: {
-			'Content-Type': 'application/json',
-		},
-		body: JSON.stringify({
-			amount: `${amount}`,
-			bankAccount,
-			publicKey: publicKey.toBase58(),
-			signature,
-		}),
-	})
-	return await program()
+	const offer = () =>
+		fetch('/offer-fiat', {
+			method: 'POST',
+			headers: {
+				'Content-Type': 'application/json',
+			},
+			body: JSON.stringify({
+				amount: `${amount}`,
+				bankAccount,
+				publicKey: publicKey.toBase58(),
+				...quote,
+				signature,
+			}),
+		})
+	const response = await offer()
+	if (response.ok) {
+		// Our bond already covers this offer too
+		return null
+	}
+	if (response.status !== 402) {
+		throw new Error(await response.text())
+	}
+	// The offer is only taken once we have put up the bond the daemon asks for
+	const { bond } = (await response.json()) as { bond: string }
+	const postBond = await program()
+		.methods.postBond(new anchor.BN(bond))
+		.accounts({
+			user: publicKey,
+			fromAccount: getAssociatedTokenAddressSync(ddMint, publicKey),
+			tokenProgram,
+		})
+		.instruction()
+	const tx = await program()
 		.methods.offerFiat(new anchor.BN(`${amount}`))
 		.accounts({
 			user: publicKey,
 		})
-		.rpc()
+		.postInstructions([postBond])
+		.rpc({ commitment: 'confirmed' })
+	const taken = await offer()
+	if (!taken.ok) {
+		throw new Error(await taken.text())
+	}
+	return tx
 }
@@ -26,9 +26,9 @@ fn make_client() -> Result<TreasuryControl, String> {
     let treasurer_secret_key = Arc::new(treasurer_secret_key);
 
     let client = Client::new_with_options(
-        Cluster::Devnet,
+        cluster_from_env()?,
         Clone::clone(&treasurer_secret_key),
-        CommitmentConfig::confirmed(),
+        commitment_from_env()?,
     );
 
     let Ok(program) = client.program(araza::ID_CONST) else {
@@ -38,19 +38,38 @@ fn make_client() -> Result<TreasuryControl, String> {
     Ok((client, program, treasurer_secret_key))
 }
 
-/// Read the envs and make out what we've been passed
-fn parse_args() -> Result<(Pubkey, Pubkey, Pubkey), String> {
-    let token_program = std::env::var("TOKEN_PROGRAM")
-        .map_err(|_| "Missing env `TOKEN_PROGRAM`".to_string())
-        .and_then(|key| Pubkey::from_str(&key).map_err(|_| "Bad `TOKEN_PROGRAM`".to_string()))?;
-    let user = std::env::var("USER")
-        .map_err(|_| "Missing env `USER`".to_string())
-        .and_then(|key| Pubkey::from_str(&key).map_err(|_| "Bad `USER`".to_string()))?;
-    let beneficiary = std::env::var("BENEFICIARY")
-        .map_err(|_| "Missing env `BENEFICIARY`".to_string())
-        .and_then(|key| Pubkey::from_str(&key).map_err(|_| "Bad `BENEFICIARY`".to_string()))?;
-
-    Ok((user, beneficiary, token_program))
+/// Read the cluster to talk to the same way the daemon does:
+/// `CLUSTER` names it, and `SOLANA_RPC_URL` with `SOLANA_WS_URL` override its endpoints
+fn cluster_from_env() -> Result<Cluster, String> {
+    let cluster = match std::env::var("CLUSTER") {
+        Ok(name) => Cluster::from_str(&name).map_err(|_| "Bad `CLUSTER`".to_string())?,
+        Err(_) => Cluster::Devnet,
+    };
+    let Ok(rpc_url) = std::env::var("SOLANA_RPC_URL") else {
+        return Ok(cluster);
+    };
+    let ws_url = std::env::var("SOLANA_WS_URL").unwrap_or_else(|_| cluster.ws_url().to_string());
+    Ok(Cluster::Custom(rpc_url, ws_url))
+}
+
+/// Read the commitment level from the env `COMMITMENT`, `confirmed` by default
+fn commitment_from_env() -> Result<CommitmentConfig, String> {
+    match std::env::var("COMMITMENT") {
+        Ok(level) => CommitmentConfig::from_str(&level).map_err(|_| "Bad `COMMITMENT`".to_string()),
+        Err(_) => Ok(CommitmentConfig::confirmed()),
+    }
+}
+
+/// Read a public key from the env `name`
+fn pubkey_from_env(name: &str) -> Result<Pubkey, String> {
+    std::env::var(name)
+        .map_err(|_| format!("Missing env `{name}`"))
+        .and_then(|key| Pubkey::from_str(&key).map_err(|_| format!("Bad `{name}`")))
+}
+
+/// Where the program keeps the stats of `wallet`
+fn stats_of(wallet: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"stats", wallet.as_ref()], &araza::ID_CONST).0
 }
 
 /// Release funds by invoking the `release_funds` method on a specified Solana program
@@ -59,7 +78,15 @@ async fn release_funds() -> Result<String, String> {
 
     let (_client, program, treasurer) = make_client()?;
 
-    let (user, beneficiary, token_program) = parse_args()?;
+    let user = pubkey_from_env("USER")?;
+    let buyer = pubkey_from_env("BUYER")?;
+    let beneficiary = pubkey_from_env("BENEFICIARY")?;
+    let token_program = pubkey_from_env("TOKEN_PROGRAM")?;
+    let fiat_commitment = std::env::var("FIAT_COMMITMENT")
+        .map_err(|_| "Missing env `FIAT_COMMITMENT`".to_string())
+        .and_then(|commitment| {
+            serde_json::from_str(&commitment).map_err(|_| "Bad `FIAT_COMMITMENT`".to_string())
+        })?;
 
     Ok(program
         .request()
@@ -68,11 +95,15 @@ async fn release_funds() -> Result<String, String> {
             treasurer: treasurer.pubkey(),
             dd_mint: Pubkey::find_program_address(&[b"mint/dd"], &araza::ID_CONST).0,
             escrow: Pubkey::find_program_address(&[b"escrow", user.as_ref()], &araza::ID_CONST).0,
+            user_stats: stats_of(&user),
+            buyer_stats: stats_of(&buyer),
             beneficiary,
+            buyer,
             user,
             token_program,
+            system_program: anchor_client::solana_sdk::system_program::ID,
         })
-        .args(instruction::ReleaseFunds {})
+        .args(instruction::ReleaseFunds { fiat_commitment })
         .signer(&treasurer)
         .send()
         .await
@@ -80,8 +111,140 @@ async fn release_funds() -> Result<String, String> {
         .to_string())
 }
 
+/// Release `AMOUNT` out of the escrow of `USER`, leaving the rest for other deals
+///
+/// With `SIGN_ONLY` set, print the signed transaction instead of sending it,
+/// so that the caller can keep it before it goes out.
+async fn release_portion() -> Result<String, String> {
+    use anchor_client::solana_sdk::signature::Signer;
+
+    let (_client, program, treasurer) = make_client()?;
+
+    let user = pubkey_from_env("USER")?;
+    let buyer = pubkey_from_env("BUYER")?;
+    let beneficiary = pubkey_from_env("BENEFICIARY")?;
+    let token_program = pubkey_from_env("TOKEN_PROGRAM")?;
+    let amount = std::env::var("AMOUNT")
+        .map_err(|_| "Missing env `AMOUNT`".to_string())
+        .and_then(|amount| amount.parse().map_err(|_| "Bad `AMOUNT`".to_string()))?;
+    let fiat_commitment = std::env::var("FIAT_COMMITMENT")
+        .map_err(|_| "Missing env `FIAT_COMMITMENT`".to_string())
+        .and_then(|commitment| {
+            serde_json::from_str(&commitment).map_err(|_| "Bad `FIAT_COMMITMENT`".to_string())
+        })?;
+
+    let request = program
+        .request()
+        .accounts(accounts::ReleaseFunds {
+            state: Pubkey::find_program_address(&[b""], &araza::ID_CONST).0,
+            treasurer: treasurer.pubkey(),
+            dd_mint: Pubkey::find_program_address(&[b"mint/dd"], &araza::ID_CONST).0,
+            escrow: Pubkey::find_program_address(&[b"escrow", user.as_ref()], &araza::ID_CONST).0,
+            user_stats: stats_of(&user),
+            buyer_stats: stats_of(&buyer),
+            beneficiary,
+            buyer,
+            user,
+            token_program,
+            system_program: anchor_client::solana_sdk::system_program::ID,
+        })
+        .args(instruction::ReleasePortion {
+            amount,
+            fiat_commitment,
+        })
+        .signer(&treasurer);
+
+    if std::env::var_os("SIGN_ONLY").is_some() {
+        let transaction = request
+            .signed_transaction()
+            .await
+            .map_err(|err| format!("While signing a transaction to release a portion: {err:#?}"))?;
+        let wire = bincode::serialize(&transaction).map_err(|err| err.to_string())?;
+        println!("Transaction: {}", bs58::encode(wire).into_string());
+        return Ok(transaction.signatures[0].to_string());
+    }
+
+    Ok(request
+        .send()
+        .await
+        .map_err(|err| format!("While sending a transaction to release a portion: {err:#?}"))?
+        .to_string())
+}
+
+/// Give the bond of `USER` back to them
+async fn return_bond() -> Result<String, String> {
+    use anchor_client::solana_sdk::signature::Signer;
+
+    let (_client, program, treasurer) = make_client()?;
+
+    let user = pubkey_from_env("USER")?;
+    let refund_account = pubkey_from_env("BENEFICIARY")?;
+    let token_program = pubkey_from_env("TOKEN_PROGRAM")?;
+
+    Ok(program
+        .request()
+        .accounts(accounts::ReturnBond {
+            state: Pubkey::find_program_address(&[b""], &araza::ID_CONST).0,
+            treasurer: treasurer.pubkey(),
+            dd_mint: Pubkey::find_program_address(&[b"mint/dd"], &araza::ID_CONST).0,
+            bond: Pubkey::find_program_address(&[b"bond", user.as_ref()], &araza::ID_CONST).0,
+            refund_account,
+            user,
+            token_program,
+        })
+        .args(instruction::ReturnBond {})
+        .signer(&treasurer)
+        .send()
+        .await
+        .map_err(|err| format!("While sending a transaction to return a bond: {err:#?}"))?
+        .to_string())
+}
+
+/// Hand `AMOUNT` of the bond of `USER` over to `SELLER`
+async fn slash_bond() -> Result<String, String> {
+    use anchor_client::solana_sdk::signature::Signer;
+
+    let (_client, program, treasurer) = make_client()?;
+
+    let user = pubkey_from_env("USER")?;
+    let seller = pubkey_from_env("SELLER")?;
+    let beneficiary = pubkey_from_env("BENEFICIARY")?;
+    let token_program = pubkey_from_env("TOKEN_PROGRAM")?;
+    let amount = std::env::var("AMOUNT")
+        .map_err(|_| "Missing env `AMOUNT`".to_string())
+        .and_then(|amount| amount.parse().map_err(|_| "Bad `AMOUNT`".to_string()))?;
+
+    Ok(program
+        .request()
+        .accounts(accounts::SlashBond {
+            state: Pubkey::find_program_address(&[b""], &araza::ID_CONST).0,
+            treasurer: treasurer.pubkey(),
+            dd_mint: Pubkey::find_program_address(&[b"mint/dd"], &araza::ID_CONST).0,
+            bond: Pubkey::find_program_address(&[b"bond", user.as_ref()], &araza::ID_CONST).0,
+            user_stats: stats_of(&user),
+            beneficiary,
+            seller,
+            user,
+            token_program,
+        })
+        .args(instruction::SlashBond { amount })
+        .signer(&treasurer)
+        .send()
+        .await
+        .map_err(|err| format!("While sending a transaction to slash a bond: {err:#?}"))?
+        .to_string())
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let signature = release_funds().await.unwrap();
+    // Releasing funds is what we were made for, so it's the default
+    let signature = match std::env::args().nth(1).as_deref() {
+        None | Some("release-funds") => release_funds().await,
+        Some("release-portion") => release_portion().await,
+        Some("return-bond") => return_bond().await,
+        Some("slash-bond") => slash_bond().await,
+        Some(other) => Err(format!("Unknown command `{other}`")),
+    }
+    .unwrap();
     println!("Signature: {}", signature);
 }
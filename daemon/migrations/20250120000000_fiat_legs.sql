@@ -0,0 +1,18 @@
+-- Which bank transactions settled each fiat leg of a match,
+-- and the salt we hide them behind when committing to them on chain
+ALTER TABLE match ADD COLUMN buyer_transaction_id TEXT;
+ALTER TABLE match ADD COLUMN seller_transaction_id TEXT;
+ALTER TABLE match ADD COLUMN fiat_salt BYTEA NOT NULL DEFAULT gen_random_bytes(32);
+
+-- Everything needed to open the on-chain fiat commitment of a released deal to an auditor
+CREATE TABLE settlement (
+    id BIGINT PRIMARY KEY,
+    onramp_public_key TEXT NOT NULL,
+    offramp_public_key TEXT NOT NULL,
+    amount NUMERIC NOT NULL,
+    buyer_transaction_id TEXT NOT NULL,
+    seller_transaction_id TEXT NOT NULL,
+    fiat_salt BYTEA NOT NULL,
+    fiat_commitment BYTEA NOT NULL,
+    created_at TIMESTAMP DEFAULT NOW()
+);
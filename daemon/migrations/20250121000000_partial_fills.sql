@@ -0,0 +1,6 @@
+-- A sell offer may now be split among several buy offers,
+-- each match taking its own quantity out of it
+ALTER TABLE match ADD COLUMN quantity NUMERIC;
+UPDATE match SET quantity = offer.amount FROM offer WHERE offer.id = match.onramp_offer_id;
+ALTER TABLE match ALTER COLUMN quantity SET NOT NULL;
+ALTER TABLE match DROP CONSTRAINT unique_offramp_offer;
@@ -0,0 +1,20 @@
+-- Offers name their fiat currency and how much of it they want per one DD;
+-- everything made before was implicitly one DD for one dollar
+ALTER TABLE preoffer ADD COLUMN currency TEXT NOT NULL DEFAULT 'USD';
+ALTER TABLE preoffer ADD COLUMN price NUMERIC NOT NULL DEFAULT 1;
+ALTER TABLE preoffer ALTER COLUMN currency DROP DEFAULT;
+ALTER TABLE preoffer ALTER COLUMN price DROP DEFAULT;
+
+ALTER TABLE offer ADD COLUMN currency TEXT NOT NULL DEFAULT 'USD';
+ALTER TABLE offer ADD COLUMN price NUMERIC NOT NULL DEFAULT 1;
+ALTER TABLE offer ALTER COLUMN currency DROP DEFAULT;
+ALTER TABLE offer ALTER COLUMN price DROP DEFAULT;
+
+-- The price a match settles at, which is what the seller asked for
+ALTER TABLE match ADD COLUMN price NUMERIC NOT NULL DEFAULT 1;
+ALTER TABLE match ALTER COLUMN price DROP DEFAULT;
+
+ALTER TABLE settlement ADD COLUMN currency TEXT NOT NULL DEFAULT 'USD';
+ALTER TABLE settlement ADD COLUMN price NUMERIC NOT NULL DEFAULT 1;
+ALTER TABLE settlement ALTER COLUMN currency DROP DEFAULT;
+ALTER TABLE settlement ALTER COLUMN price DROP DEFAULT;
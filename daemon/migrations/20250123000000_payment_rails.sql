@@ -0,0 +1,8 @@
+-- How the fiat side of an offer moves; everything made before went card to card
+CREATE TYPE payment_rail AS ENUM ('sepa', 'faster_payments', 'pix', 'upi', 'c2c');
+
+ALTER TABLE preoffer ADD COLUMN rail payment_rail NOT NULL DEFAULT 'c2c';
+ALTER TABLE preoffer ALTER COLUMN rail DROP DEFAULT;
+
+ALTER TABLE offer ADD COLUMN rail payment_rail NOT NULL DEFAULT 'c2c';
+ALTER TABLE offer ALTER COLUMN rail DROP DEFAULT;
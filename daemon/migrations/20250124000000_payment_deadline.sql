@@ -0,0 +1,5 @@
+-- When the buyer has to have paid by, fixed at the time of matching
+-- so that changing the payment window does not move it for the matches already made
+ALTER TABLE match ADD COLUMN payment_deadline TIMESTAMP;
+UPDATE match SET payment_deadline = created_at + INTERVAL '30 minutes';
+ALTER TABLE match ALTER COLUMN payment_deadline SET NOT NULL;
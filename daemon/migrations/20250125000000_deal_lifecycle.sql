@@ -0,0 +1,59 @@
+CREATE TYPE deal_state AS ENUM (
+    'pending_deposit',
+    'open',
+    'matched',
+    'buyer_paid',
+    'seller_confirmed',
+    'releasing',
+    'settled',
+    'cancelled',
+    'disputed'
+);
+
+-- Every step a deal has taken, the latest one being where it is now
+CREATE TABLE deal_event (
+    id BIGSERIAL PRIMARY KEY,
+    offer_id BIGINT NOT NULL,
+    match_id BIGINT,
+    state deal_state NOT NULL,
+    cause TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+CREATE INDEX deal_event_by_offer ON deal_event (offer_id, id);
+
+CREATE OR REPLACE FUNCTION forbid_rewriting_history() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION '% is append-only', TG_TABLE_NAME;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER deal_event_is_append_only
+    BEFORE UPDATE OR DELETE ON deal_event
+    FOR EACH ROW EXECUTE FUNCTION forbid_rewriting_history();
+
+-- Whatever was in flight before we kept track of it:
+INSERT INTO deal_event (offer_id, state, cause)
+    SELECT id, 'pending_deposit', 'in flight before deal events' FROM preoffer;
+INSERT INTO deal_event (offer_id, state, cause)
+    SELECT id, 'open', 'in flight before deal events' FROM offer
+    WHERE id NOT IN (SELECT onramp_offer_id FROM match UNION SELECT offramp_offer_id FROM match);
+INSERT INTO deal_event (offer_id, match_id, state, cause)
+    SELECT onramp_offer_id, id, 'matched', 'in flight before deal events' FROM match;
+INSERT INTO deal_event (offer_id, match_id, state, cause)
+    SELECT DISTINCT ON (offramp_offer_id) offramp_offer_id, id, 'matched', 'in flight before deal events' FROM match;
+
+-- Done deals, moved out of the way of the live ones;
+-- they are kept whole as they were last seen, whatever columns the live tables grow later
+CREATE TABLE offer_archive (
+    id BIGINT PRIMARY KEY,
+    public_key TEXT NOT NULL,
+    record JSONB NOT NULL,
+    archived_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+CREATE INDEX offer_archive_by_public_key ON offer_archive (public_key);
+
+CREATE TABLE match_archive (
+    id BIGINT PRIMARY KEY,
+    record JSONB NOT NULL,
+    archived_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
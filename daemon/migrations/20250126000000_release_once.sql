@@ -0,0 +1,6 @@
+-- The signed release of a match, kept before it is sent
+-- so that after a crash we can tell by its signature whether it went through
+ALTER TABLE match ADD COLUMN release_transaction BYTEA;
+
+-- Which transaction released a settled deal; unknown for the ones settled before
+ALTER TABLE settlement ADD COLUMN release_signature TEXT;
@@ -0,0 +1,4 @@
+-- Preoffers whose DD never showed up in escrow in time
+ALTER TYPE deal_state ADD VALUE 'expired';
+
+CREATE INDEX preoffer_by_created_at ON preoffer (created_at);
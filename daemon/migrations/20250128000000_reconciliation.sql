@@ -0,0 +1,7 @@
+-- What the reconciler found when comparing the escrows on chain with the book,
+-- kept so that whichever replica is asked can show the latest one
+CREATE TABLE reconciliation_report (
+    id BIGSERIAL PRIMARY KEY,
+    report JSONB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
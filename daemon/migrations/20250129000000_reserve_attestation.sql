@@ -0,0 +1,14 @@
+-- Signed proofs of reserves, taken once in a while so that they can be charted over time;
+-- the amounts are in atoms, and the message is kept byte for byte as it was signed
+CREATE TABLE reserve_attestation (
+    id BIGSERIAL PRIMARY KEY,
+    slot BIGINT NOT NULL,
+    vault_balance NUMERIC NOT NULL,
+    dd_supply NUMERIC NOT NULL,
+    escrowed_dd NUMERIC NOT NULL,
+    message TEXT NOT NULL,
+    signer TEXT NOT NULL,
+    signature TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+CREATE INDEX reserve_attestation_by_slot ON reserve_attestation (slot);
@@ -0,0 +1,41 @@
+-- Transactions of the program as the indexer read them off the chain, finalized only,
+-- so that the history of a wallet does not depend on the offers the daemon made for it
+CREATE TABLE chain_tx (
+    signature TEXT PRIMARY KEY,
+    slot BIGINT NOT NULL,
+    block_time TIMESTAMP,
+    failed BOOLEAN NOT NULL,
+    indexed_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+CREATE INDEX chain_tx_by_slot ON chain_tx (slot);
+
+-- The instructions of the program in each transaction, named after the program's own;
+-- amount is the first argument of those that take one, in atoms
+CREATE TABLE chain_tx_instruction (
+    signature TEXT NOT NULL REFERENCES chain_tx (signature),
+    position INT NOT NULL,
+    name TEXT NOT NULL,
+    amount NUMERIC,
+    accounts TEXT[] NOT NULL,
+    data BYTEA NOT NULL,
+    PRIMARY KEY (signature, position)
+);
+CREATE INDEX chain_tx_instruction_by_account ON chain_tx_instruction USING GIN (accounts);
+
+-- Every token balance a transaction changed, in atoms
+CREATE TABLE chain_tx_balance (
+    signature TEXT NOT NULL REFERENCES chain_tx (signature),
+    account TEXT NOT NULL,
+    owner TEXT,
+    mint TEXT NOT NULL,
+    pre NUMERIC NOT NULL,
+    post NUMERIC NOT NULL,
+    PRIMARY KEY (signature, account)
+);
+CREATE INDEX chain_tx_balance_by_owner ON chain_tx_balance (owner);
+
+-- The newest transaction of each address that the indexer is done with, to carry on from
+CREATE TABLE chain_cursor (
+    address TEXT PRIMARY KEY,
+    signature TEXT NOT NULL
+);
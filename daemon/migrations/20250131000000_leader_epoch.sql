@@ -0,0 +1,8 @@
+-- The term of the current leader, which goes up every time a replica becomes the leader;
+-- the jobs check it in the same transaction as the changes they make,
+-- so that a leader that has been taken over from without noticing changes nothing
+CREATE TABLE leader_epoch (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    epoch BIGINT NOT NULL
+);
+INSERT INTO leader_epoch (epoch) VALUES (0);
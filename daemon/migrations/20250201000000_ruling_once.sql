@@ -0,0 +1,5 @@
+-- The ruling on a disputed match, recorded before anything is paid out,
+-- and the signed transaction that pays it, kept before it is sent
+-- so that after a crash we can tell by its signature whether it went through
+ALTER TABLE match ADD COLUMN buyer_wins BOOLEAN;
+ALTER TABLE match ADD COLUMN ruling_transaction BYTEA;
@@ -0,0 +1,3 @@
+-- The signed transaction that slashes the bond of a buyer who missed their deadline,
+-- kept before it is sent so that the seller is paid exactly once
+ALTER TABLE match ADD COLUMN slash_transaction BYTEA;
@@ -0,0 +1,3 @@
+-- The bank transaction that put a match in dispute, for whoever rules on it;
+-- like the ids of the legs, it is kept out of the causes of the deal events the wallets can read
+ALTER TABLE match ADD COLUMN disputed_transaction_id TEXT;
@@ -0,0 +1,2 @@
+-- The DD in the shielded pool, attested apart from the escrows and bonds
+ALTER TABLE reserve_attestation ADD COLUMN shielded_dd NUMERIC NOT NULL DEFAULT 0;
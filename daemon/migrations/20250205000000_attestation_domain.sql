@@ -0,0 +1,3 @@
+-- Attestations are now signed behind a domain tag, which the ones taken so far were not;
+-- those cannot be checked the way the new ones are, so they make way for the new ones
+DELETE FROM reserve_attestation;
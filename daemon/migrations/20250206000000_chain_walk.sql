@@ -0,0 +1,6 @@
+-- How far the indexer got walking down from the newest transaction it saw to the cursor,
+-- so that a long history is caught up on over many runs; the cursor only moves once the walk is over,
+-- and there is none until the first walk is
+ALTER TABLE chain_cursor ALTER COLUMN signature DROP NOT NULL;
+ALTER TABLE chain_cursor ADD COLUMN walk_top TEXT;
+ALTER TABLE chain_cursor ADD COLUMN walk_before TEXT;
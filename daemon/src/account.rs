@@ -0,0 +1,410 @@
+//! What a wallet has been up to, with us and on chain
+//!
+//! The offers of a wallet come from the book, the preoffers and the archive alike,
+//! so that its past deals show up next to the open ones.
+//! Its history adds to the steps of those deals the instructions of the program
+//! it took part in, as the indexer read them, and the settlements of its deals.
+//! Both are newest first, a page at a time.
+use bigdecimal::BigDecimal;
+use sqlx::PgPool;
+
+use crate::deal::DealState;
+use crate::schema::{Offer, OfferDirection, OfferId, PaymentRail};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct PageQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl PageQuery {
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// The offset to ask for the next page with, if there is one
+    pub next_offset: Option<i64>,
+}
+
+impl<T> Page<T> {
+    /// Cut the page out of `items`, which has one more item than the page if there are more
+    fn new(mut items: Vec<T>, query: &PageQuery) -> Self {
+        let limit = query.limit();
+        let next_offset = (items.len() as i64 > limit).then(|| query.offset() + limit);
+        items.truncate(limit as usize);
+        Self { items, next_offset }
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountOffer {
+    #[serde(flatten)]
+    pub offer: Offer,
+    /// Where its deal is now
+    pub state: Option<DealState>,
+    /// When it was made, in seconds since the Unix epoch
+    pub created_at: Option<i64>,
+}
+
+/// The offers of `public_key`, whether waiting for their deposit, on the book, or done
+pub async fn fetch_offers(
+    pool: &PgPool,
+    public_key: &str,
+    query: &PageQuery,
+) -> Result<Page<AccountOffer>, sqlx::Error> {
+    let records = sqlx::query!(
+        r#"
+        SELECT
+            offers.id as "id!",
+            offers.bank_account as "bank_account!",
+            offers.public_key as "public_key!",
+            offers.amount as "amount!",
+            offers.direction as "direction!: OfferDirection",
+            offers.rail as "rail!: PaymentRail",
+            offers.currency as "currency!",
+            offers.price as "price!",
+            EXTRACT(EPOCH FROM offers.created_at)::bigint as created_at,
+            latest.state as "state?: DealState"
+        FROM (
+            SELECT id, bank_account, public_key, amount, direction, rail, currency, price, created_at
+            FROM offer WHERE public_key = $1
+            UNION ALL
+            SELECT id, bank_account, public_key, amount, 'dd_to_fiat', rail, currency, price, created_at
+            FROM preoffer WHERE public_key = $1
+            UNION ALL
+            SELECT
+                id,
+                record->>'bank_account',
+                public_key,
+                (record->>'amount')::numeric,
+                COALESCE(record->>'direction', 'dd_to_fiat')::offer_direction,
+                (record->>'rail')::payment_rail,
+                record->>'currency',
+                (record->>'price')::numeric,
+                (record->>'created_at')::timestamp
+            FROM offer_archive WHERE public_key = $1
+        ) offers
+        LEFT JOIN LATERAL (
+            SELECT state FROM deal_event WHERE offer_id = offers.id ORDER BY id DESC LIMIT 1
+        ) latest ON TRUE
+        ORDER BY offers.created_at DESC NULLS LAST, offers.id DESC
+        LIMIT $2 OFFSET $3
+        "#,
+        public_key,
+        query.limit() + 1,
+        query.offset()
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let offers = records
+        .into_iter()
+        .map(|record| AccountOffer {
+            offer: Offer {
+                id: OfferId::from(record.id),
+                bank_account: record.bank_account,
+                public_key: record.public_key,
+                amount: record.amount,
+                direction: record.direction,
+                rail: record.rail,
+                currency: record.currency,
+                price: record.price,
+            },
+            state: record.state,
+            created_at: record.created_at,
+        })
+        .collect();
+    Ok(Page::new(offers, query))
+}
+
+/// Something that happened to a wallet, when it did in seconds since the Unix epoch
+#[derive(serde::Serialize, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum HistoryItem {
+    /// An instruction of the program the wallet took part in, such as a deposit or a redeem
+    #[serde(rename_all = "camelCase")]
+    Transaction {
+        time: Option<i64>,
+        signature: String,
+        slot: u64,
+        instruction: String,
+        /// In atoms, for the instructions that move an amount
+        amount: Option<String>,
+        failed: bool,
+    },
+    /// A step one of the deals of the wallet took, such as being matched
+    ///
+    /// Why it took that step stays with us, since it may tell of the bank side of the deal.
+    #[serde(rename_all = "camelCase")]
+    Deal {
+        time: Option<i64>,
+        offer_id: OfferId,
+        match_id: Option<OfferId>,
+        state: DealState,
+    },
+    /// A match of the wallet whose DD was released, on either side of it
+    #[serde(rename_all = "camelCase")]
+    Settlement {
+        time: Option<i64>,
+        match_id: OfferId,
+        direction: OfferDirection,
+        amount: String,
+        currency: String,
+        price: String,
+        release_signature: Option<String>,
+    },
+}
+
+/// What happened to `public_key`, newest first
+pub async fn fetch_history(
+    pool: &PgPool,
+    public_key: &str,
+    query: &PageQuery,
+) -> Result<Page<HistoryItem>, sqlx::Error> {
+    let records = sqlx::query!(
+        r#"
+        SELECT
+            kind as "kind!",
+            EXTRACT(EPOCH FROM time)::bigint as time,
+            signature,
+            slot,
+            name,
+            amount,
+            failed,
+            offer_id,
+            match_id,
+            state as "state: DealState",
+            currency,
+            price,
+            direction as "direction: OfferDirection"
+        FROM (
+            SELECT
+                'transaction' as kind, chain_tx.block_time as time, signature, chain_tx.slot,
+                chain_tx_instruction.name, chain_tx_instruction.amount, chain_tx.failed,
+                NULL::bigint as offer_id, NULL::bigint as match_id, NULL::deal_state as state,
+                NULL as currency, NULL::numeric as price,
+                NULL::offer_direction as direction, chain_tx_instruction.position::bigint as ordinal
+            FROM chain_tx_instruction JOIN chain_tx USING (signature)
+            WHERE $1 = ANY(chain_tx_instruction.accounts)
+            UNION ALL
+            SELECT
+                'deal', created_at, NULL, NULL, NULL, NULL, NULL,
+                offer_id, match_id, state, NULL, NULL, NULL, id
+            FROM deal_event
+            WHERE offer_id IN (
+                SELECT id FROM offer WHERE public_key = $1
+                UNION SELECT id FROM preoffer WHERE public_key = $1
+                UNION SELECT id FROM offer_archive WHERE public_key = $1
+            )
+            UNION ALL
+            SELECT
+                'settlement', created_at, release_signature, NULL, NULL, amount, NULL,
+                NULL, id, NULL, currency, price,
+                CASE WHEN onramp_public_key = $1 THEN 'fiat_to_dd' ELSE 'dd_to_fiat' END::offer_direction,
+                0
+            FROM settlement
+            WHERE $1 IN (onramp_public_key, offramp_public_key)
+        ) history
+        ORDER BY time DESC NULLS LAST, kind, signature, ordinal DESC
+        LIMIT $2 OFFSET $3
+        "#,
+        public_key,
+        query.limit() + 1,
+        query.offset()
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // Every row counts towards the page, so one we cannot make sense of is an error:
+    let items = records
+        .into_iter()
+        .map(|record| {
+            let item = match record.kind.as_str() {
+                "transaction" => HistoryItem::Transaction {
+                    time: record.time,
+                    signature: required(record.signature, "signature")?,
+                    slot: required(record.slot, "slot")? as u64,
+                    instruction: required(record.name, "name")?,
+                    amount: record.amount.as_ref().map(BigDecimal::to_string),
+                    failed: required(record.failed, "failed")?,
+                },
+                "deal" => HistoryItem::Deal {
+                    time: record.time,
+                    offer_id: OfferId::from(required(record.offer_id, "offer_id")?),
+                    match_id: record.match_id.map(OfferId::from),
+                    state: required(record.state, "state")?,
+                },
+                "settlement" => HistoryItem::Settlement {
+                    time: record.time,
+                    match_id: OfferId::from(required(record.match_id, "match_id")?),
+                    direction: required(record.direction, "direction")?,
+                    amount: required(record.amount, "amount")?.to_string(),
+                    currency: required(record.currency, "currency")?,
+                    price: required(record.price, "price")?.to_string(),
+                    release_signature: record.signature,
+                },
+                kind => {
+                    return Err(sqlx::Error::Decode(
+                        format!("no such kind of history as `{kind}`").into(),
+                    ))
+                }
+            };
+            Ok(item)
+        })
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    Ok(Page::new(items, query))
+}
+
+/// The `column` of a row of history, which its kind of row has to have
+fn required<T>(value: Option<T>, column: &str) -> Result<T, sqlx::Error> {
+    value.ok_or_else(|| sqlx::Error::Decode(format!("a row of history lacks its {column}").into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deal::{archive_offer, transition};
+
+    async fn offer(pool: &PgPool, public_key: &str, direction: &str) -> OfferId {
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO offer (amount, bank_account, public_key, direction, rail, currency, price) VALUES (5, '4111111111111111', $1, $2::offer_direction, 'c2c', 'USD', 1) RETURNING id",
+        )
+        .bind(public_key)
+        .bind(direction)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        let id = OfferId::from(id);
+        let mut connection = pool.acquire().await.unwrap();
+        transition(&mut connection, id, None, DealState::Open, "offered")
+            .await
+            .unwrap();
+        id
+    }
+
+    #[sqlx::test]
+    async fn shows_the_open_and_past_offers_a_page_at_a_time(pool: PgPool) {
+        let wallet = "wallet";
+        let past = offer(&pool, wallet, "fiat_to_dd").await;
+        let mut connection = pool.acquire().await.unwrap();
+        transition(
+            &mut connection,
+            past,
+            None,
+            DealState::Cancelled,
+            "changed mind",
+        )
+        .await
+        .unwrap();
+        archive_offer(&mut connection, past).await.unwrap();
+        let open = offer(&pool, wallet, "dd_to_fiat").await;
+        offer(&pool, "someone else", "dd_to_fiat").await;
+
+        let all = fetch_offers(&pool, wallet, &PageQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(all.next_offset, None);
+        let mut states = all
+            .items
+            .iter()
+            .map(|offer| (offer.offer.id, offer.state))
+            .collect::<Vec<_>>();
+        states.sort_by_key(|(id, _)| i64::from(*id));
+        let mut expected = vec![
+            (past, Some(DealState::Cancelled)),
+            (open, Some(DealState::Open)),
+        ];
+        expected.sort_by_key(|(id, _)| i64::from(*id));
+        assert_eq!(states, expected);
+        let archived = all.items.iter().find(|offer| offer.offer.id == past);
+        assert_eq!(
+            archived.map(|offer| offer.offer.direction),
+            Some(OfferDirection::FiatToDD)
+        );
+
+        let first = PageQuery {
+            limit: Some(1),
+            offset: None,
+        };
+        let page = fetch_offers(&pool, wallet, &first).await.unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.next_offset, Some(1));
+        let second = PageQuery {
+            limit: Some(1),
+            offset: page.next_offset,
+        };
+        let page = fetch_offers(&pool, wallet, &second).await.unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.next_offset, None);
+    }
+
+    #[sqlx::test]
+    async fn tells_what_happened_on_chain_and_with_the_deals(pool: PgPool) {
+        let wallet = "wallet";
+        sqlx::query("INSERT INTO chain_tx (signature, slot, block_time, failed) VALUES ('deposited', 7, NOW() - INTERVAL '1 hour', FALSE)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO chain_tx_instruction (signature, position, name, amount, accounts, data) VALUES ('deposited', 0, 'deposit', 5, ARRAY[$1], '')")
+            .bind(wallet)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let sold = offer(&pool, wallet, "dd_to_fiat").await;
+        sqlx::query("INSERT INTO settlement (id, onramp_public_key, offramp_public_key, amount, buyer_transaction_id, seller_transaction_id, fiat_salt, fiat_commitment, currency, price, release_signature) VALUES (1, 'buyer', $1, 5, 'b', 's', '', '', 'USD', 1, 'released')")
+            .bind(wallet)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // As the readout used to word it, with the bank's id of the transfer:
+        sqlx::query("INSERT INTO deal_event (offer_id, state, cause) VALUES ($1, 'matched', 'seller received TX-123')")
+            .bind(i64::from(sold))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let history = fetch_history(&pool, wallet, &PageQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(history.items.len(), 4);
+        assert!(!serde_json::to_string(&history.items)
+            .unwrap()
+            .contains("TX-123"));
+        assert!(matches!(
+            &history.items[3],
+            HistoryItem::Transaction { instruction, amount: Some(amount), .. }
+                if instruction == "deposit" && amount == "5"
+        ));
+        assert!(history.items.iter().any(|item| matches!(
+            item,
+            HistoryItem::Deal { offer_id, state: DealState::Open, .. } if *offer_id == sold
+        )));
+        assert!(history.items.iter().any(|item| matches!(
+            item,
+            HistoryItem::Settlement {
+                direction: OfferDirection::DDToFiat,
+                ..
+            }
+        )));
+        let nobody = fetch_history(&pool, "nobody", &PageQuery::default())
+            .await
+            .unwrap();
+        assert!(nobody.items.is_empty());
+    }
+}
@@ -0,0 +1,43 @@
+//! The bonds the buyers put up behind their offers
+//!
+//! A wallet has a single bond, which it tops up with every offer to buy DD,
+//! so that it covers all of its offers that are still on the book, matched or not.
+//! An offer is only taken once the bond covers it, and the bond goes back to the buyer
+//! once the last of their offers is done with, and the part of it behind a deal
+//! goes to the seller if the buyer does not pay for it.
+use solana_sdk::pubkey::Pubkey;
+use sqlx::PgExecutor;
+
+use crate::chain::{token_amount, ChainClient};
+
+/// How much DD `wallet` currently has put up as a bond, in atoms
+pub async fn fetch_bond(client: &impl ChainClient, program_id: &Pubkey, wallet: &Pubkey) -> u64 {
+    let (bond_account, _) = Pubkey::find_program_address(&[b"bond", wallet.as_ref()], program_id);
+    client
+        .account_data(&bond_account)
+        .await
+        .ok()
+        .flatten()
+        .as_deref()
+        .and_then(token_amount)
+        .unwrap_or(0)
+}
+
+/// How many offers to buy DD `public_key` has on the book, matched or not
+pub async fn count_bids(
+    executor: impl PgExecutor<'_>,
+    public_key: &str,
+) -> Result<u64, sqlx::Error> {
+    let count = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!" FROM offer WHERE public_key = $1 AND direction = 'fiat_to_dd'"#,
+        public_key
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(count as u64)
+}
+
+/// The bond that covers `bids` offers, each of which needs `per_bid`
+pub fn covering_bond(per_bid: u64, bids: u64) -> u64 {
+    per_bid.saturating_mul(bids)
+}
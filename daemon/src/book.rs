@@ -0,0 +1,389 @@
+//! In-memory order book the matcher pairs offers up with
+//!
+//! Offers only meet on the same payment rail and in the same currency, at prices that cross,
+//! and are taken best price first, then oldest first.
+//! A buy offer is always filled in full, since its author pays for it in one transfer,
+//! but a sell offer may be split among several buyers,
+//! keeping what is left of it on the book.
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+use bigdecimal::{BigDecimal, Zero};
+use sqlx::types::time::PrimitiveDateTime;
+
+use crate::schema::{OfferDirection, OfferId, PaymentRail};
+
+/// What the book needs to know about an offer to place it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Order {
+    pub id: OfferId,
+    /// What is still open of the offer
+    pub amount: BigDecimal,
+    pub rail: PaymentRail,
+    pub currency: String,
+    /// Units of `currency` per one DD
+    pub price: BigDecimal,
+    pub created_at: Option<PrimitiveDateTime>,
+}
+
+impl Order {
+    /// Time priority, with the id breaking ties so that the order is always the same
+    fn time_priority(&self, other: &Self) -> Ordering {
+        (self.created_at, i64::from(self.id)).cmp(&(other.created_at, i64::from(other.id)))
+    }
+}
+
+/// A buy offer filled with the given `quantity` out of a sell offer, at the `price` of the seller
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    pub onramp_offer_id: OfferId,
+    pub offramp_offer_id: OfferId,
+    pub quantity: BigDecimal,
+    pub price: BigDecimal,
+}
+
+#[derive(Debug, Default)]
+pub struct OrderBook {
+    onramp: VecDeque<Order>,
+    offramp: VecDeque<Order>,
+}
+
+impl OrderBook {
+    /// Place all the `orders` on their side of the book:
+    /// the buyers who pay the most and the sellers who ask the least go first
+    pub fn extend(&mut self, direction: OfferDirection, orders: Vec<Order>) {
+        match direction {
+            OfferDirection::FiatToDD => {
+                self.onramp.extend(orders);
+                self.onramp
+                    .make_contiguous()
+                    .sort_by(|a, b| b.price.cmp(&a.price).then(a.time_priority(b)));
+            }
+            OfferDirection::DDToFiat => {
+                self.offramp.extend(orders);
+                self.offramp
+                    .make_contiguous()
+                    .sort_by(|a, b| a.price.cmp(&b.price).then(a.time_priority(b)));
+            }
+        }
+    }
+
+    /// Take every buy offer that can be filled off the book,
+    /// leaving only those nobody can be matched with yet
+    pub fn match_all(&mut self) -> Vec<Match> {
+        let mut matches = Vec::new();
+        let mut unmatched = VecDeque::new();
+        while let Some(onramp) = self.onramp.pop_front() {
+            // The first seller in line who still has enough takes the whole buy:
+            let seller = self.offramp.iter_mut().find(|offramp| {
+                offramp.rail == onramp.rail
+                    && offramp.currency == onramp.currency
+                    && offramp.price <= onramp.price
+                    && offramp.amount >= onramp.amount
+            });
+            let Some(offramp) = seller else {
+                unmatched.push_back(onramp);
+                continue;
+            };
+            offramp.amount -= &onramp.amount;
+            matches.push(Match {
+                onramp_offer_id: onramp.id,
+                offramp_offer_id: offramp.id,
+                quantity: onramp.amount,
+                price: offramp.price.clone(),
+            });
+        }
+        self.onramp = unmatched;
+        self.offramp.retain(|offramp| !offramp.amount.is_zero());
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::time::Duration;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn order(id: i64, amount: u64, at: u64) -> Order {
+        use sqlx::types::time::{Date, Time};
+
+        let epoch = Date::from_ordinal_date(2025, 1).unwrap();
+        Order {
+            id: OfferId::from(id),
+            amount: BigDecimal::from(amount),
+            rail: PaymentRail::C2C,
+            currency: "USD".to_string(),
+            price: BigDecimal::from(1),
+            created_at: Some(
+                PrimitiveDateTime::new(epoch, Time::MIDNIGHT) + Duration::from_secs(at),
+            ),
+        }
+    }
+
+    fn quoted(order: Order, currency: &str, price: u64) -> Order {
+        Order {
+            currency: currency.to_string(),
+            price: BigDecimal::from(price),
+            ..order
+        }
+    }
+
+    #[test]
+    fn fills_the_oldest_buy_first() {
+        let mut book = OrderBook::default();
+        book.extend(
+            OfferDirection::FiatToDD,
+            vec![order(1, 100, 20), order(2, 100, 10), order(3, 50, 30)],
+        );
+        book.extend(OfferDirection::DDToFiat, vec![order(4, 100, 0)]);
+
+        let matches = book.match_all();
+
+        assert_eq!(
+            matches,
+            vec![Match {
+                onramp_offer_id: OfferId::from(2),
+                offramp_offer_id: OfferId::from(4),
+                quantity: BigDecimal::from(100),
+                price: BigDecimal::from(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn splits_one_sell_among_several_buys() {
+        let mut book = OrderBook::default();
+        book.extend(
+            OfferDirection::FiatToDD,
+            vec![order(1, 250, 0), order(2, 250, 1)],
+        );
+        book.extend(OfferDirection::DDToFiat, vec![order(3, 500, 0)]);
+
+        let matches = book.match_all();
+
+        assert_eq!(matches.len(), 2);
+        assert!(matches
+            .iter()
+            .all(|m| m.offramp_offer_id == OfferId::from(3)));
+        assert!(book.offramp.is_empty());
+    }
+
+    #[test]
+    fn keeps_the_rest_of_a_sell_on_the_book() {
+        let mut book = OrderBook::default();
+        book.extend(OfferDirection::FiatToDD, vec![order(1, 200, 0)]);
+        book.extend(OfferDirection::DDToFiat, vec![order(2, 500, 0)]);
+
+        book.match_all();
+
+        assert_eq!(book.offramp[0].amount, BigDecimal::from(300));
+    }
+
+    #[test]
+    fn never_matches_one_buy_twice() {
+        // Which the old nested loop did, failing on `unique_onramp_offer`:
+        let mut book = OrderBook::default();
+        book.extend(OfferDirection::FiatToDD, vec![order(1, 100, 0)]);
+        book.extend(
+            OfferDirection::DDToFiat,
+            vec![order(2, 100, 0), order(3, 100, 1)],
+        );
+
+        let matches = book.match_all();
+
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].offramp_offer_id, OfferId::from(2));
+    }
+
+    #[test]
+    fn matches_only_within_one_currency() {
+        let mut book = OrderBook::default();
+        book.extend(
+            OfferDirection::FiatToDD,
+            vec![quoted(order(1, 100, 0), "EUR", 1)],
+        );
+        book.extend(
+            OfferDirection::DDToFiat,
+            vec![quoted(order(2, 100, 0), "USD", 1)],
+        );
+
+        assert!(book.match_all().is_empty());
+    }
+
+    #[test]
+    fn matches_only_on_one_rail() {
+        let mut book = OrderBook::default();
+        book.extend(
+            OfferDirection::FiatToDD,
+            vec![Order {
+                rail: PaymentRail::Sepa,
+                ..order(1, 100, 0)
+            }],
+        );
+        book.extend(OfferDirection::DDToFiat, vec![order(2, 100, 0)]);
+
+        assert!(book.match_all().is_empty());
+    }
+
+    #[test]
+    fn matches_only_at_crossing_prices() {
+        let mut book = OrderBook::default();
+        book.extend(
+            OfferDirection::FiatToDD,
+            vec![quoted(order(1, 100, 0), "USD", 2)],
+        );
+        book.extend(
+            OfferDirection::DDToFiat,
+            vec![quoted(order(2, 100, 0), "USD", 3)],
+        );
+
+        assert!(book.match_all().is_empty());
+    }
+
+    #[test]
+    fn settles_with_the_cheapest_seller_at_their_price() {
+        let mut book = OrderBook::default();
+        book.extend(
+            OfferDirection::FiatToDD,
+            vec![quoted(order(1, 100, 10), "USD", 5)],
+        );
+        book.extend(
+            OfferDirection::DDToFiat,
+            vec![
+                quoted(order(2, 100, 0), "USD", 4),
+                quoted(order(3, 100, 20), "USD", 3),
+            ],
+        );
+
+        let matches = book.match_all();
+
+        assert_eq!(
+            matches,
+            vec![Match {
+                onramp_offer_id: OfferId::from(1),
+                offramp_offer_id: OfferId::from(3),
+                quantity: BigDecimal::from(100),
+                price: BigDecimal::from(3),
+            }]
+        );
+    }
+
+    fn orders(first_id: i64) -> impl Strategy<Value = Vec<Order>> {
+        let rail = prop::sample::select(vec![PaymentRail::C2C, PaymentRail::Sepa]);
+        let currency = prop::sample::select(vec!["USD", "EUR"]);
+        let spec = (1u64..5, 0u64..100, rail, currency, 1u64..4);
+        prop::collection::vec(spec, 0..40).prop_map(move |specs| {
+            specs
+                .into_iter()
+                .enumerate()
+                .map(|(n, (amount, at, rail, currency, price))| Order {
+                    rail,
+                    ..quoted(order(first_id + n as i64, amount, at), currency, price)
+                })
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn every_buy_lands_in_one_match_at_most(
+            onramp in orders(0),
+            offramp in orders(1_000),
+        ) {
+            let mut book = OrderBook::default();
+            book.extend(OfferDirection::FiatToDD, onramp.clone());
+            book.extend(OfferDirection::DDToFiat, offramp.clone());
+
+            let matches = book.match_all();
+
+            let mut seen = HashSet::new();
+            for m in &matches {
+                prop_assert!(seen.insert(i64::from(m.onramp_offer_id)));
+            }
+        }
+
+        #[test]
+        fn matches_on_one_rail_in_one_currency_at_crossing_prices(
+            onramp in orders(0),
+            offramp in orders(1_000),
+        ) {
+            let find = |id: OfferId| onramp.iter().chain(&offramp).find(|o| o.id == id).unwrap();
+            let mut book = OrderBook::default();
+            book.extend(OfferDirection::FiatToDD, onramp.clone());
+            book.extend(OfferDirection::DDToFiat, offramp.clone());
+
+            for m in book.match_all() {
+                let (buy, sell) = (find(m.onramp_offer_id), find(m.offramp_offer_id));
+                prop_assert_eq!(buy.rail, sell.rail);
+                prop_assert_eq!(&buy.currency, &sell.currency);
+                prop_assert!(sell.price <= buy.price);
+                prop_assert_eq!(&m.price, &sell.price);
+            }
+        }
+
+        #[test]
+        fn fills_buys_in_full_and_sells_no_more_than_offered(
+            onramp in orders(0),
+            offramp in orders(1_000),
+        ) {
+            let amount_of = |id: OfferId| {
+                onramp.iter().chain(&offramp).find(|o| o.id == id).map(|o| o.amount.clone()).unwrap()
+            };
+            let mut book = OrderBook::default();
+            book.extend(OfferDirection::FiatToDD, onramp.clone());
+            book.extend(OfferDirection::DDToFiat, offramp.clone());
+
+            let mut sold = HashMap::new();
+            for m in book.match_all() {
+                prop_assert_eq!(&m.quantity, &amount_of(m.onramp_offer_id));
+                *sold.entry(i64::from(m.offramp_offer_id)).or_insert_with(BigDecimal::zero) += m.quantity;
+            }
+            for (id, quantity) in sold {
+                prop_assert!(quantity <= amount_of(OfferId::from(id)));
+            }
+        }
+
+        #[test]
+        fn leaves_nothing_that_could_still_match(
+            onramp in orders(0),
+            offramp in orders(1_000),
+        ) {
+            let mut book = OrderBook::default();
+            book.extend(OfferDirection::FiatToDD, onramp);
+            book.extend(OfferDirection::DDToFiat, offramp);
+
+            book.match_all();
+
+            prop_assert!(book.match_all().is_empty());
+            for buy in &book.onramp {
+                let could_fill = |sell: &Order| {
+                    sell.rail == buy.rail
+                        && sell.currency == buy.currency
+                        && sell.price <= buy.price
+                        && sell.amount >= buy.amount
+                };
+                prop_assert!(!book.offramp.iter().any(could_fill));
+            }
+        }
+
+        #[test]
+        fn gives_the_same_result_in_any_input_order(
+            onramp in orders(0),
+            offramp in orders(1_000),
+        ) {
+            let mut book = OrderBook::default();
+            book.extend(OfferDirection::FiatToDD, onramp.clone());
+            book.extend(OfferDirection::DDToFiat, offramp.clone());
+
+            let mut reversed = OrderBook::default();
+            reversed.extend(OfferDirection::FiatToDD, onramp.into_iter().rev().collect());
+            reversed.extend(OfferDirection::DDToFiat, offramp.into_iter().rev().collect());
+
+            prop_assert_eq!(book.match_all(), reversed.match_all());
+        }
+    }
+}
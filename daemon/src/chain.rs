@@ -0,0 +1,612 @@
+//! What the daemon asks of the network
+//!
+//! Everything the cron reads from or sends to the chain goes through [`ChainClient`],
+//! so that the deals can be followed from deposit to release without a validator.
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::client_error::{ClientError, ClientErrorKind};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_client::GetConfirmedSignaturesForAddress2Config;
+use solana_client::rpc_config::{
+    RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcTransactionConfig,
+};
+use solana_client::rpc_filter::{Memcmp, RpcFilterType};
+use solana_client::rpc_request::RpcRequest;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use solana_sdk::transaction::Transaction;
+use solana_transaction_status::option_serializer::OptionSerializer;
+use solana_transaction_status::{
+    EncodedConfirmedTransactionWithStatusMeta, TransactionStatus, UiTransactionEncoding,
+    UiTransactionTokenBalance,
+};
+
+/// The most signatures the RPC nodes hand out at once
+pub const SIGNATURES_PER_REQUEST: usize = 1000;
+
+/// The mint of a token account, which the SPL token programs keep first
+pub fn token_mint(data: &[u8]) -> Option<Pubkey> {
+    Some(Pubkey::new_from_array(data.get(..32)?.try_into().ok()?))
+}
+
+/// The owner of a token account, kept right after its mint
+pub fn token_owner(data: &[u8]) -> Option<Pubkey> {
+    Some(Pubkey::new_from_array(data.get(32..64)?.try_into().ok()?))
+}
+
+/// The amount of a token account, kept right after its mint and owner
+pub fn token_amount(data: &[u8]) -> Option<u64> {
+    let amount = data.get(64..72)?;
+    Some(u64::from_le_bytes(amount.try_into().ok()?))
+}
+
+/// The supply of a mint, kept right after its optional mint authority
+pub fn mint_supply(data: &[u8]) -> Option<u64> {
+    let supply = data.get(36..44)?;
+    Some(u64::from_le_bytes(supply.try_into().ok()?))
+}
+
+/// A transaction that has landed, as much of it as the indexer keeps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainTransaction {
+    pub signature: Signature,
+    pub slot: u64,
+    /// When it was processed, in seconds since the Unix epoch, if the node knows
+    pub block_time: Option<i64>,
+    pub failed: bool,
+    /// Its top-level instructions, in order
+    pub instructions: Vec<ChainInstruction>,
+    /// The token accounts whose balance it changed
+    pub balance_changes: Vec<BalanceChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainInstruction {
+    pub program_id: Pubkey,
+    pub accounts: Vec<Pubkey>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalanceChange {
+    pub account: Pubkey,
+    pub owner: Option<Pubkey>,
+    pub mint: Pubkey,
+    pub pre: u64,
+    pub post: u64,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait ChainClient {
+    /// The data of `account`, or `None` if it does not exist
+    async fn account_data(&self, account: &Pubkey) -> Result<Option<Vec<u8>>, ClientError>;
+
+    /// The data of each of `accounts` in order, at most a hundred of them at a time
+    async fn multiple_account_data(
+        &self,
+        accounts: &[Pubkey],
+    ) -> Result<Vec<Option<Vec<u8>>>, ClientError>;
+
+    /// The data of each of `accounts` in order, all read at once, and the slot they were read at
+    async fn account_data_at_slot(
+        &self,
+        accounts: &[Pubkey],
+    ) -> Result<(u64, Vec<Option<Vec<u8>>>), ClientError>;
+
+    /// Every account of `token_program` that holds tokens of `mint`, with its data
+    async fn token_accounts(
+        &self,
+        token_program: &Pubkey,
+        mint: &Pubkey,
+    ) -> Result<Vec<(Pubkey, Vec<u8>)>, ClientError>;
+
+    /// The signatures of the finalized transactions that touched `address`, newest first,
+    /// older than `before` and newer than `until`, at most [`SIGNATURES_PER_REQUEST`] of them
+    async fn signatures_for_address(
+        &self,
+        address: &Pubkey,
+        before: Option<Signature>,
+        until: Option<Signature>,
+    ) -> Result<Vec<Signature>, ClientError>;
+
+    /// The finalized transaction with `signature`, or `None` if it is not there yet
+    async fn transaction(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<ChainTransaction>, ClientError>;
+
+    async fn latest_blockhash(&self) -> Result<Hash, ClientError>;
+
+    /// Whether a transaction made with `blockhash` may still land
+    async fn is_blockhash_valid(&self, blockhash: &Hash) -> Result<bool, ClientError>;
+
+    /// Send the transaction without waiting for it to land
+    async fn send_transaction(&self, transaction: &Transaction) -> Result<Signature, ClientError>;
+
+    async fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Signature, ClientError>;
+
+    /// What became of the transaction with `signature`, or `None` if it has not been seen
+    async fn signature_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<TransactionStatus>, ClientError>;
+}
+
+impl ChainClient for RpcClient {
+    async fn account_data(&self, account: &Pubkey) -> Result<Option<Vec<u8>>, ClientError> {
+        let response = self
+            .get_account_with_commitment(account, self.commitment())
+            .await?;
+        Ok(response.value.map(|account| account.data))
+    }
+
+    async fn multiple_account_data(
+        &self,
+        accounts: &[Pubkey],
+    ) -> Result<Vec<Option<Vec<u8>>>, ClientError> {
+        let found = self.get_multiple_accounts(accounts).await?;
+        Ok(found
+            .into_iter()
+            .map(|account| account.map(|account| account.data))
+            .collect())
+    }
+
+    async fn account_data_at_slot(
+        &self,
+        accounts: &[Pubkey],
+    ) -> Result<(u64, Vec<Option<Vec<u8>>>), ClientError> {
+        let response = self
+            .get_multiple_accounts_with_commitment(accounts, self.commitment())
+            .await?;
+        let data = response
+            .value
+            .into_iter()
+            .map(|account| account.map(|account| account.data))
+            .collect();
+        Ok((response.context.slot, data))
+    }
+
+    async fn token_accounts(
+        &self,
+        token_program: &Pubkey,
+        mint: &Pubkey,
+    ) -> Result<Vec<(Pubkey, Vec<u8>)>, ClientError> {
+        let config = RpcProgramAccountsConfig {
+            filters: Some(vec![RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+                0,
+                mint.as_ref(),
+            ))]),
+            account_config: RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64),
+                commitment: Some(self.commitment()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let accounts = self
+            .get_program_accounts_with_config(token_program, config)
+            .await?;
+        Ok(accounts
+            .into_iter()
+            .map(|(address, account)| (address, account.data))
+            .collect())
+    }
+
+    async fn signatures_for_address(
+        &self,
+        address: &Pubkey,
+        before: Option<Signature>,
+        until: Option<Signature>,
+    ) -> Result<Vec<Signature>, ClientError> {
+        let config = GetConfirmedSignaturesForAddress2Config {
+            before,
+            until,
+            limit: Some(SIGNATURES_PER_REQUEST),
+            commitment: Some(CommitmentConfig::finalized()),
+        };
+        let statuses = self
+            .get_signatures_for_address_with_config(address, config)
+            .await?;
+        let signatures = statuses
+            .iter()
+            .map(|status| Signature::from_str(&status.signature))
+            .collect::<Result<_, _>>();
+        signatures.map_err(|e| ClientErrorKind::Custom(e.to_string()).into())
+    }
+
+    async fn transaction(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<ChainTransaction>, ClientError> {
+        let config = RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::Base64),
+            commitment: Some(CommitmentConfig::finalized()),
+            max_supported_transaction_version: Some(0),
+        };
+        // Unlike `get_transaction`, this takes the transaction not being there for an answer:
+        let found: Option<EncodedConfirmedTransactionWithStatusMeta> = self
+            .send(
+                RpcRequest::GetTransaction,
+                serde_json::json!([signature.to_string(), config]),
+            )
+            .await?;
+        let Some(found) = found else {
+            return Ok(None);
+        };
+        match chain_transaction(*signature, found) {
+            Some(transaction) => Ok(Some(transaction)),
+            None => Err(ClientErrorKind::Custom(format!("Cannot decode {signature}")).into()),
+        }
+    }
+
+    async fn latest_blockhash(&self) -> Result<Hash, ClientError> {
+        self.get_latest_blockhash().await
+    }
+
+    async fn is_blockhash_valid(&self, blockhash: &Hash) -> Result<bool, ClientError> {
+        RpcClient::is_blockhash_valid(self, blockhash, CommitmentConfig::processed()).await
+    }
+
+    async fn send_transaction(&self, transaction: &Transaction) -> Result<Signature, ClientError> {
+        RpcClient::send_transaction(self, transaction).await
+    }
+
+    async fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Signature, ClientError> {
+        RpcClient::send_and_confirm_transaction(self, transaction).await
+    }
+
+    async fn signature_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<TransactionStatus>, ClientError> {
+        let statuses = self
+            .get_signature_statuses_with_history(&[*signature])
+            .await?;
+        Ok(statuses.value.into_iter().next().flatten())
+    }
+}
+
+/// Keep what the indexer needs of the transaction `found`
+fn chain_transaction(
+    signature: Signature,
+    found: EncodedConfirmedTransactionWithStatusMeta,
+) -> Option<ChainTransaction> {
+    let transaction = found.transaction.transaction.decode()?;
+    let meta = found.transaction.meta;
+
+    // The accounts the instructions point into, including those of address lookup tables:
+    let mut keys = transaction.message.static_account_keys().to_vec();
+    if let Some(OptionSerializer::Some(loaded)) = meta.as_ref().map(|meta| &meta.loaded_addresses) {
+        for key in loaded.writable.iter().chain(&loaded.readonly) {
+            keys.push(Pubkey::from_str(key).ok()?);
+        }
+    }
+
+    let instructions = transaction
+        .message
+        .instructions()
+        .iter()
+        .map(|instruction| {
+            Some(ChainInstruction {
+                program_id: *keys.get(usize::from(instruction.program_id_index))?,
+                accounts: instruction
+                    .accounts
+                    .iter()
+                    .map(|&index| keys.get(usize::from(index)).copied())
+                    .collect::<Option<_>>()?,
+                data: instruction.data.clone(),
+            })
+        })
+        .collect::<Option<_>>()?;
+
+    let (failed, balance_changes) = match meta {
+        Some(meta) => {
+            let pre = token_balances(meta.pre_token_balances)?;
+            let post = token_balances(meta.post_token_balances)?;
+            let mut indices = pre.keys().chain(post.keys()).copied().collect::<Vec<_>>();
+            indices.sort();
+            indices.dedup();
+            let changes = indices
+                .into_iter()
+                .filter_map(|index| {
+                    let (before, after) = (pre.get(&index), post.get(&index));
+                    let balance = after.or(before)?;
+                    let change = BalanceChange {
+                        account: *keys.get(usize::from(index))?,
+                        owner: balance.owner,
+                        mint: balance.mint,
+                        pre: before.map_or(0, |balance| balance.amount),
+                        post: after.map_or(0, |balance| balance.amount),
+                    };
+                    (change.pre != change.post).then_some(change)
+                })
+                .collect();
+            (meta.err.is_some(), changes)
+        }
+        None => (false, Vec::new()),
+    };
+
+    Some(ChainTransaction {
+        signature,
+        slot: found.slot,
+        block_time: found.block_time,
+        failed,
+        instructions,
+        balance_changes,
+    })
+}
+
+struct TokenBalance {
+    mint: Pubkey,
+    owner: Option<Pubkey>,
+    amount: u64,
+}
+
+/// The balance of each token account in `balances`, by its index in the transaction
+fn token_balances(
+    balances: OptionSerializer<Vec<UiTransactionTokenBalance>>,
+) -> Option<HashMap<u8, TokenBalance>> {
+    let balances: Option<Vec<_>> = balances.into();
+    balances
+        .unwrap_or_default()
+        .into_iter()
+        .map(|balance| {
+            let owner: Option<String> = balance.owner.into();
+            Some((
+                balance.account_index,
+                TokenBalance {
+                    mint: Pubkey::from_str(&balance.mint).ok()?,
+                    owner: owner.and_then(|owner| Pubkey::from_str(&owner).ok()),
+                    amount: balance.ui_token_amount.amount.parse().ok()?,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// A chain that lives in memory, where every transaction sent lands at once
+#[cfg(test)]
+pub mod fake {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use solana_client::client_error::{ClientError, ClientErrorKind};
+    use solana_sdk::hash::Hash;
+    use solana_sdk::pubkey::Pubkey;
+    use solana_sdk::signature::Signature;
+    use solana_sdk::transaction::{Transaction, TransactionError};
+    use solana_transaction_status::{TransactionConfirmationStatus, TransactionStatus};
+
+    use super::{token_mint, ChainClient, ChainTransaction, SIGNATURES_PER_REQUEST};
+
+    #[derive(Default)]
+    struct State {
+        accounts: HashMap<Pubkey, Vec<u8>>,
+        sent: Vec<Transaction>,
+        statuses: HashMap<Signature, TransactionStatus>,
+        failing: bool,
+        delaying: bool,
+        /// The transactions sent while delaying, which have no status yet
+        in_flight: Vec<Signature>,
+        expired: bool,
+        slot: u64,
+        /// The transactions there are to index, oldest first
+        history: Vec<ChainTransaction>,
+    }
+
+    #[derive(Default)]
+    pub struct FakeChain {
+        state: Mutex<State>,
+    }
+
+    impl FakeChain {
+        /// Make `account` a token account holding `amount`
+        pub fn set_token_amount(&self, account: Pubkey, amount: u64) {
+            self.set_token_account(account, Pubkey::default(), Pubkey::default(), amount);
+        }
+
+        /// Make `account` a token account of `owner` holding `amount` of `mint`
+        pub fn set_token_account(&self, account: Pubkey, mint: Pubkey, owner: Pubkey, amount: u64) {
+            let mut data = vec![0; 165];
+            data[..32].copy_from_slice(mint.as_ref());
+            data[32..64].copy_from_slice(owner.as_ref());
+            data[64..72].copy_from_slice(&amount.to_le_bytes());
+            self.state.lock().unwrap().accounts.insert(account, data);
+        }
+
+        /// Make `account` a mint of which `supply` has been minted
+        pub fn set_mint(&self, account: Pubkey, supply: u64) {
+            let mut data = vec![0; 82];
+            data[36..44].copy_from_slice(&supply.to_le_bytes());
+            self.state.lock().unwrap().accounts.insert(account, data);
+        }
+
+        /// Move the chain on to `slot`
+        pub fn set_slot(&self, slot: u64) {
+            self.state.lock().unwrap().slot = slot;
+        }
+
+        /// Have `transaction` land, after all the ones added so far
+        pub fn add_transaction(&self, transaction: ChainTransaction) {
+            self.state.lock().unwrap().history.push(transaction);
+        }
+
+        /// Have the transactions sent from now on fail, or land again
+        pub fn fail_transactions(&self, failing: bool) {
+            self.state.lock().unwrap().failing = failing;
+        }
+
+        /// Have the transactions sent from now on stay in flight, or land right away again
+        pub fn delay_transactions(&self, delaying: bool) {
+            self.state.lock().unwrap().delaying = delaying;
+        }
+
+        /// Have every blockhash expire, the transactions in flight landing just as they do
+        pub fn expire_blockhashes(&self) {
+            self.state.lock().unwrap().expired = true;
+        }
+
+        /// Every transaction sent so far, in order
+        pub fn sent(&self) -> Vec<Transaction> {
+            self.state.lock().unwrap().sent.clone()
+        }
+
+        fn land(&self, transaction: &Transaction) -> Signature {
+            let mut state = self.state.lock().unwrap();
+            let signature = transaction.signatures[0];
+            state.sent.push(transaction.clone());
+            if state.delaying {
+                state.in_flight.push(signature);
+            } else {
+                state.confirm(signature);
+            }
+            signature
+        }
+    }
+
+    impl State {
+        fn confirm(&mut self, signature: Signature) {
+            let err = self
+                .failing
+                .then_some(TransactionError::InsufficientFundsForFee);
+            self.statuses.insert(
+                signature,
+                TransactionStatus {
+                    slot: 1,
+                    confirmations: None,
+                    status: err.clone().map_or(Ok(()), Err),
+                    err,
+                    confirmation_status: Some(TransactionConfirmationStatus::Finalized),
+                },
+            );
+        }
+    }
+
+    impl ChainClient for FakeChain {
+        async fn account_data(&self, account: &Pubkey) -> Result<Option<Vec<u8>>, ClientError> {
+            Ok(self.state.lock().unwrap().accounts.get(account).cloned())
+        }
+
+        async fn multiple_account_data(
+            &self,
+            accounts: &[Pubkey],
+        ) -> Result<Vec<Option<Vec<u8>>>, ClientError> {
+            if accounts.len() > 100 {
+                return Err(ClientErrorKind::Custom("Too many accounts".to_string()).into());
+            }
+            let state = self.state.lock().unwrap();
+            Ok(accounts
+                .iter()
+                .map(|account| state.accounts.get(account).cloned())
+                .collect())
+        }
+
+        async fn account_data_at_slot(
+            &self,
+            accounts: &[Pubkey],
+        ) -> Result<(u64, Vec<Option<Vec<u8>>>), ClientError> {
+            let slot = self.state.lock().unwrap().slot;
+            Ok((slot, self.multiple_account_data(accounts).await?))
+        }
+
+        async fn token_accounts(
+            &self,
+            _token_program: &Pubkey,
+            mint: &Pubkey,
+        ) -> Result<Vec<(Pubkey, Vec<u8>)>, ClientError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .accounts
+                .iter()
+                .filter(|(_, data)| token_mint(data).as_ref() == Some(mint))
+                .map(|(address, data)| (*address, data.clone()))
+                .collect())
+        }
+
+        async fn signatures_for_address(
+            &self,
+            address: &Pubkey,
+            before: Option<Signature>,
+            until: Option<Signature>,
+        ) -> Result<Vec<Signature>, ClientError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .history
+                .iter()
+                .rev()
+                .filter(|transaction| {
+                    transaction.instructions.iter().any(|instruction| {
+                        instruction.program_id == *address || instruction.accounts.contains(address)
+                    })
+                })
+                .map(|transaction| transaction.signature)
+                .skip_while(|signature| before.is_some_and(|before| before != *signature))
+                .skip(usize::from(before.is_some()))
+                .take_while(|signature| Some(*signature) != until)
+                .take(SIGNATURES_PER_REQUEST)
+                .collect())
+        }
+
+        async fn transaction(
+            &self,
+            signature: &Signature,
+        ) -> Result<Option<ChainTransaction>, ClientError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .history
+                .iter()
+                .find(|transaction| transaction.signature == *signature)
+                .cloned())
+        }
+
+        async fn latest_blockhash(&self) -> Result<Hash, ClientError> {
+            Ok(Hash::new_unique())
+        }
+
+        async fn is_blockhash_valid(&self, _blockhash: &Hash) -> Result<bool, ClientError> {
+            let mut state = self.state.lock().unwrap();
+            if !state.expired {
+                return Ok(true);
+            }
+            for signature in std::mem::take(&mut state.in_flight) {
+                state.confirm(signature);
+            }
+            Ok(false)
+        }
+
+        async fn send_transaction(
+            &self,
+            transaction: &Transaction,
+        ) -> Result<Signature, ClientError> {
+            Ok(self.land(transaction))
+        }
+
+        async fn send_and_confirm_transaction(
+            &self,
+            transaction: &Transaction,
+        ) -> Result<Signature, ClientError> {
+            let signature = self.land(transaction);
+            if self.state.lock().unwrap().failing {
+                return Err(ClientErrorKind::Custom("Transaction failed".to_string()).into());
+            }
+            Ok(signature)
+        }
+
+        async fn signature_status(
+            &self,
+            signature: &Signature,
+        ) -> Result<Option<TransactionStatus>, ClientError> {
+            Ok(self.state.lock().unwrap().statuses.get(signature).cloned())
+        }
+    }
+}
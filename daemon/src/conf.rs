@@ -1,22 +1,105 @@
 //! Constants associated to the deployment instance,
 //! most likely to be set `in std::env::var`.
 
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::Duration;
 
-use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Keypair};
+
+use crate::schedule::Job;
+
+/// Bond a buyer with a track record has to put up, in DD atoms, unless set otherwise
+const DEFAULT_BOND_AMOUNT: u64 = 1_000000;
+
+/// How long a buyer has to pay after being matched, unless set otherwise
+const DEFAULT_PAYMENT_WINDOW: Duration = Duration::from_secs(30 * 60);
+
+/// Solana cluster the deployment talks to, named as the `CLUSTER` env
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cluster {
+    /// A `solana-test-validator` running on this machine
+    Localnet,
+    #[default]
+    Devnet,
+    Testnet,
+    Mainnet,
+}
+
+impl FromStr for Cluster {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "localnet" | "localhost" => Ok(Self::Localnet),
+            "devnet" => Ok(Self::Devnet),
+            "testnet" => Ok(Self::Testnet),
+            "mainnet" | "mainnet-beta" => Ok(Self::Mainnet),
+            _ => Err(format!("Unknown cluster `{name}`")),
+        }
+    }
+}
+
+impl Cluster {
+    /// The public JSON RPC endpoint of the cluster
+    pub fn rpc_url(self) -> &'static str {
+        match self {
+            Self::Localnet => "http://127.0.0.1:8899",
+            Self::Devnet => "https://api.devnet.solana.com",
+            Self::Testnet => "https://api.testnet.solana.com",
+            Self::Mainnet => "https://api.mainnet-beta.solana.com",
+        }
+    }
+
+    /// The public websocket endpoint of the cluster
+    pub fn ws_url(self) -> &'static str {
+        match self {
+            Self::Localnet => "ws://127.0.0.1:8900",
+            Self::Devnet => "wss://api.devnet.solana.com",
+            Self::Testnet => "wss://api.testnet.solana.com",
+            Self::Mainnet => "wss://api.mainnet-beta.solana.com",
+        }
+    }
+}
+
+/// How long a preoffer waits for its DD to show up in escrow, unless set otherwise
+const DEFAULT_PREOFFER_TTL: Duration = Duration::from_secs(60 * 60);
 
 /// Configuration of the deployment instance
 pub struct Conf {
+    cluster: Cluster,
+    rpc_url: String,
+    ws_url: String,
+    commitment: CommitmentConfig,
     program_id: Option<Pubkey>,
     token_program: Option<Pubkey>,
     associated_token_program: Option<Pubkey>,
     dd_mint: Option<Pubkey>,
     treasurer_secret_key: Option<Keypair>,
+    bond_amount: u64,
+    payment_window: Duration,
+    preoffer_ttl: Duration,
+    job_intervals: HashMap<Job, Duration>,
+    admin_token: Option<String>,
 }
 
 impl Conf {
     pub fn from_env() -> Self {
+        let cluster = std::env::var("CLUSTER")
+            .ok()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or_default();
         Self {
+            cluster,
+            // Explicit endpoints win over the public ones of the cluster,
+            // which are rate-limited and not meant for production
+            rpc_url: std::env::var("SOLANA_RPC_URL")
+                .unwrap_or_else(|_| cluster.rpc_url().to_string()),
+            ws_url: std::env::var("SOLANA_WS_URL").unwrap_or_else(|_| cluster.ws_url().to_string()),
+            commitment: std::env::var("COMMITMENT")
+                .ok()
+                .and_then(|level| level.parse().ok())
+                .unwrap_or_else(CommitmentConfig::confirmed),
             program_id: std::env::var("PROGRAM_ID")
                 .map(|key| Pubkey::from_str(&key).ok())
                 .ok()
@@ -41,6 +124,34 @@ impl Conf {
                 })
                 .ok()
                 .flatten(),
+            bond_amount: std::env::var("BOND_AMOUNT")
+                .ok()
+                .and_then(|amount| amount.parse().ok())
+                .unwrap_or(DEFAULT_BOND_AMOUNT),
+            payment_window: std::env::var("PAYMENT_WINDOW_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_PAYMENT_WINDOW),
+            preoffer_ttl: std::env::var("PREOFFER_TTL_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_PREOFFER_TTL),
+            job_intervals: Job::ALL
+                .into_iter()
+                .map(|job| {
+                    let interval = std::env::var(job.interval_key())
+                        .ok()
+                        .and_then(|secs| secs.parse().ok())
+                        .map(Duration::from_secs)
+                        .unwrap_or_else(|| job.default_interval());
+                    (job, interval)
+                })
+                .collect(),
+            admin_token: std::env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
         }
     }
 
@@ -64,6 +175,18 @@ impl Conf {
         missing
     }
 
+    pub fn cluster(&self) -> Cluster {
+        self.cluster
+    }
+    pub fn rpc_url(&self) -> &str {
+        &self.rpc_url
+    }
+    pub fn ws_url(&self) -> &str {
+        &self.ws_url
+    }
+    pub fn commitment(&self) -> CommitmentConfig {
+        self.commitment
+    }
     pub fn program_id(&self) -> Option<&Pubkey> {
         self.program_id.as_ref()
     }
@@ -79,4 +202,47 @@ impl Conf {
     pub fn treasurer_secret_key(&self) -> Option<&Keypair> {
         self.treasurer_secret_key.as_ref()
     }
+    pub fn bond_amount(&self) -> u64 {
+        self.bond_amount
+    }
+    pub fn payment_window(&self) -> Duration {
+        self.payment_window
+    }
+    pub fn preoffer_ttl(&self) -> Duration {
+        self.preoffer_ttl
+    }
+    pub fn job_interval(&self, job: Job) -> Duration {
+        self.job_intervals
+            .get(&job)
+            .copied()
+            .unwrap_or_else(|| job.default_interval())
+    }
+    /// The secret the admin endpoints are called with, which are shut if it is not set
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+}
+
+#[cfg(test)]
+impl Conf {
+    /// A deployment of its own, with keys nobody else has
+    pub fn for_tests() -> Self {
+        let cluster = Cluster::Localnet;
+        Self {
+            cluster,
+            rpc_url: cluster.rpc_url().to_string(),
+            ws_url: cluster.ws_url().to_string(),
+            commitment: CommitmentConfig::confirmed(),
+            program_id: Some(Pubkey::new_unique()),
+            token_program: Some(Pubkey::new_unique()),
+            associated_token_program: Some(Pubkey::new_unique()),
+            dd_mint: Some(Pubkey::new_unique()),
+            treasurer_secret_key: Some(Keypair::new()),
+            bond_amount: DEFAULT_BOND_AMOUNT,
+            payment_window: DEFAULT_PAYMENT_WINDOW,
+            preoffer_ttl: DEFAULT_PREOFFER_TTL,
+            job_intervals: HashMap::new(),
+            admin_token: None,
+        }
+    }
 }
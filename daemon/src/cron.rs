@@ -1,169 +1,506 @@
 //! Scheduled periodic tasks
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use bigdecimal::BigDecimal;
+use bigdecimal::{BigDecimal, ToPrimitive};
 use solana_client::nonblocking::rpc_client::RpcClient;
 use solana_sdk::pubkey::Pubkey;
-use sqlx::PgPool;
-use tokio::process::Command;
+use solana_sdk::transaction::Transaction;
+use sqlx::postgres::{PgConnectOptions, PgListener, PgNotification, PgPoolOptions};
+use sqlx::types::time::PrimitiveDateTime;
+use sqlx::{PgConnection, PgPool};
 
+use crate::bond::{count_bids, covering_bond, fetch_bond};
+use crate::book::{Order, OrderBook};
+use crate::chain::{token_amount, ChainClient};
 use crate::conf::Conf;
-use crate::schema::OfferId;
+use crate::deal::{archive_match, archive_offer, archive_preoffer, transition, DealState};
+use crate::dispute::settle_rulings;
+use crate::indexer::index_chain;
+use crate::leader::{Epoch, Leadership};
+use crate::reconcile::{reconcile, save_report};
+use crate::reputation::fetch_user_stats;
+use crate::reserves::{attest, save_attestation};
+use crate::schedule::{wake, Job, Schedule, WAKE_CHANNEL};
+use crate::schema::{OfferDirection, OfferId, PaymentRail};
+use crate::treasury::{return_bond, sign_release_portion, sign_slash_bond};
 
-const SOLANA_RPC_URL: &str = "https://api.devnet.solana.com";
+/// A sell offer waiting for its DD to show up in escrow
+pub struct Preoffer {
+    pub id: OfferId,
+    pub amount: BigDecimal,
+    pub bank_account: String,
+    pub public_key: String,
+    pub rail: PaymentRail,
+    pub currency: String,
+    pub price: BigDecimal,
+}
 
-async fn promote_preoffer_if_ready(
-    conf: &Conf,
+/// All the preoffers, or only the ones of `public_key` if it is given
+pub async fn fetch_preoffers(
     pool: &PgPool,
-    client: &RpcClient,
-    id: OfferId,
-    amount: BigDecimal,
-    bank_account: String,
-    public_key: String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let author = Pubkey::from_str(&public_key)?;
-    let Some(program_id) = conf.program_id() else {
-        return Err("PROGRAM_ID is not set".into());
-    };
+    public_key: Option<&str>,
+) -> Result<Vec<Preoffer>, sqlx::Error> {
+    sqlx::query_as!(
+        Preoffer,
+        r#"SELECT id, amount, bank_account, public_key, rail as "rail: PaymentRail", currency, price FROM preoffer WHERE $1::text IS NULL OR public_key = $1"#,
+        public_key
+    )
+    .fetch_all(pool)
+    .await
+}
 
-    let (escrow_account, _) =
-        Pubkey::find_program_address(&[b"escrow", author.as_ref()], program_id);
-    // The string amount Solana gives us is a whole number of atoms
-    let balance = client
-        .get_token_account_balance(&escrow_account)
-        .await
-        .map(|x| u128::from_str(&x.amount).unwrap_or_default())
-        .unwrap_or(0);
+/// How many accounts to ask for in a single `getMultipleAccounts`, which is as many as it allows
+const ACCOUNTS_PER_REQUEST: usize = 100;
+
+/// Whether the escrow of the preoffer, holding `balance`, has the exact amount of DD it advertises
+fn deposit_arrived(preoffer: &Preoffer, balance: u64) -> Result<bool, String> {
     let balance = BigDecimal::from(balance);
     if balance == BigDecimal::from(0) {
         // It's not time yet, wait for the next round
-        return Ok(());
+        return Ok(false);
     }
     // So we decidedly do not handle decimals here; they are already premultiplied
-    if balance != amount {
-        return Err(format!("Stale preoffer #{id}; expected {amount}, got {balance}").into());
+    if balance != preoffer.amount {
+        return Err(format!(
+            "Stale preoffer #{}; expected {}, got {balance}",
+            preoffer.id, preoffer.amount
+        ));
     }
+    Ok(true)
+}
 
-    let mut transaction = pool.begin().await?;
+/// Turn the preoffer into an offer
+///
+/// Returns whether it has been promoted, which it has not if someone else got there first.
+async fn promote_preoffer(
+    connection: &mut PgConnection,
+    preoffer: Preoffer,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let Preoffer {
+        id,
+        amount,
+        bank_account,
+        public_key,
+        rail,
+        currency,
+        price,
+    } = preoffer;
+
+    // The offer keeps the id of the preoffer, since it is the same deal:
+    let offer_id = id;
     let id: i64 = id.into();
-    sqlx::query!("DELETE FROM preoffer WHERE id = $1", id)
-        .execute(&mut *transaction)
+    let deleted = sqlx::query!("DELETE FROM preoffer WHERE id = $1", id)
+        .execute(&mut *connection)
         .await?;
+    if deleted.rows_affected() == 0 {
+        return Ok(false);
+    }
     sqlx::query!(
-        "INSERT INTO offer (amount, bank_account, public_key, direction) VALUES ($1, $2, $3, 'dd_to_fiat')",
+        "INSERT INTO offer (id, amount, bank_account, public_key, direction, rail, currency, price) VALUES ($1, $2, $3, $4, 'dd_to_fiat', $5, $6, $7)",
+        id,
         amount,
         bank_account,
-        public_key
+        public_key,
+        rail as PaymentRail,
+        currency,
+        price,
+    )
+    .execute(&mut *connection)
+    .await?;
+    transition(
+        connection,
+        offer_id,
+        None,
+        DealState::Open,
+        "DD deposited in escrow",
     )
-    .execute(&mut *transaction)
     .await?;
+
+    Ok(true)
+}
+
+/// Promote, all at once, the preoffers whose escrows hold what they advertise,
+/// given the balance of the escrow of each
+pub async fn promote_deposited(
+    pool: &PgPool,
+    escrowed: impl IntoIterator<Item = (Preoffer, u64)>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let ready = escrowed
+        .into_iter()
+        .filter_map(
+            |(preoffer, balance)| match deposit_arrived(&preoffer, balance) {
+                Ok(true) => Some(preoffer),
+                Ok(false) => None,
+                Err(e) => {
+                    tracing::error!("{e}");
+                    None
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+    if ready.is_empty() {
+        return Ok(0);
+    }
+
+    let mut transaction = pool.begin().await?;
+    let mut promoted = 0;
+    for preoffer in ready {
+        if promote_preoffer(&mut transaction, preoffer).await? {
+            promoted += 1;
+        }
+    }
+    if promoted > 0 {
+        wake(&mut transaction, Job::Matching).await?;
+    }
     transaction.commit().await?;
 
-    Ok(())
+    Ok(promoted)
 }
 
-/// Monitor the network to see if our clients have deposited the correct amount of DD
+/// The amounts of DD held by the token `accounts`, fetched a hundred at a time;
+/// the ones that do not exist yet hold nothing
+async fn fetch_token_amounts(
+    client: &impl ChainClient,
+    accounts: &[Pubkey],
+) -> Result<HashMap<Pubkey, u64>, Box<dyn std::error::Error>> {
+    let mut amounts = HashMap::with_capacity(accounts.len());
+    for chunk in accounts.chunks(ACCOUNTS_PER_REQUEST) {
+        let found = client.multiple_account_data(chunk).await?;
+        for (account, found) in chunk.iter().zip(found) {
+            let amount = found.as_deref().and_then(token_amount).unwrap_or(0);
+            amounts.insert(*account, amount);
+        }
+    }
+    Ok(amounts)
+}
+
+/// Pair the `preoffers` with how much DD their escrows hold
+async fn fetch_escrowed(
+    conf: &Conf,
+    client: &impl ChainClient,
+    preoffers: Vec<Preoffer>,
+) -> Result<Vec<(Preoffer, u64)>, Box<dyn std::error::Error>> {
+    let Some(program_id) = conf.program_id() else {
+        return Err("PROGRAM_ID is not set".into());
+    };
+    let escrowed = preoffers
+        .into_iter()
+        .map(|preoffer| {
+            let escrow = Pubkey::from_str(&preoffer.public_key)
+                .map(|author| {
+                    Pubkey::find_program_address(&[b"escrow", author.as_ref()], program_id).0
+                })
+                .inspect_err(|e| tracing::error!("Preoffer #{} has a bad key: {e}", preoffer.id))
+                .ok();
+            (preoffer, escrow)
+        })
+        .collect::<Vec<_>>();
+
+    let mut escrows = escrowed
+        .iter()
+        .filter_map(|(_, escrow)| *escrow)
+        .collect::<Vec<_>>();
+    escrows.sort_unstable();
+    escrows.dedup();
+    let balances = fetch_token_amounts(client, &escrows).await?;
+
+    Ok(escrowed
+        .into_iter()
+        .map(|(preoffer, escrow)| {
+            let balance = escrow.and_then(|escrow| balances.get(&escrow).copied());
+            (preoffer, balance.unwrap_or(0))
+        })
+        .collect())
+}
+
+/// Look at the escrow of every preoffer to see if our clients have deposited the correct amount of DD
 /// and if so, make their preoffers available for matching as offers
+///
+/// Deposits are normally noticed as they happen by [`crate::watch`]; this is the fallback.
 async fn promote_all_preoffers(
     conf: &Conf,
     pool: &PgPool,
-    client: &RpcClient,
+    client: &impl ChainClient,
 ) -> Result<usize, Box<dyn std::error::Error>> {
-    struct Preoffer {
-        id: OfferId,
-        amount: BigDecimal,
-        bank_account: String,
-        public_key: String,
-    }
+    let preoffers = fetch_preoffers(pool, None).await?;
+    let escrowed = fetch_escrowed(conf, client, preoffers).await?;
+    promote_deposited(pool, escrowed).await
+}
 
-    let preoffers = sqlx::query_as!(
+/// Give up on the preoffers whose DD has not shown up in escrow in time,
+/// after a last look in case a deposit has slipped past us
+async fn expire_preoffers(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+    epoch: Epoch,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let ttl = conf.preoffer_ttl().as_secs_f64();
+    let overdue = sqlx::query_as!(
         Preoffer,
-        "SELECT id, amount, bank_account, public_key FROM preoffer"
+        r#"SELECT id, amount, bank_account, public_key, rail as "rail: PaymentRail", currency, price FROM preoffer WHERE created_at < NOW() - make_interval(secs => $1)"#,
+        ttl
     )
     .fetch_all(pool)
     .await?;
+    if overdue.is_empty() {
+        return Ok(0);
+    }
 
-    let mut promoted = 0;
-    for preoffer in preoffers {
-        let result = promote_preoffer_if_ready(
-            conf,
-            pool,
-            client,
-            preoffer.id,
-            preoffer.amount,
-            preoffer.bank_account,
-            preoffer.public_key,
-        )
+    let (arrived, expired): (Vec<_>, Vec<_>) = fetch_escrowed(conf, client, overdue)
+        .await?
+        .into_iter()
+        .partition(|(preoffer, balance)| deposit_arrived(preoffer, *balance) == Ok(true));
+    promote_deposited(pool, arrived).await?;
+
+    let mut count = 0;
+    for (preoffer, _) in expired {
+        let id = preoffer.id;
+        // Each on its own, so that one promoted in the meantime does not hold the rest up:
+        let result: Result<(), Box<dyn std::error::Error>> = async {
+            let mut transaction = pool.begin().await?;
+            epoch.fence(&mut *transaction).await?;
+            transition(
+                &mut transaction,
+                id,
+                None,
+                DealState::Expired,
+                "no deposit in time",
+            )
+            .await?;
+            archive_preoffer(&mut transaction, id).await?;
+            transaction.commit().await?;
+            Ok(())
+        }
         .await;
-        if let Err(e) = result {
-            let id = preoffer.id.to_string();
-            tracing::error!("While promoting preoffer #{id}: {:?}", e);
-            continue;
+        match result {
+            Ok(()) => count += 1,
+            Err(e) => tracing::error!("While expiring preoffer #{id}: {:?}", e),
         }
-        promoted += 1;
     }
 
-    Ok(promoted)
+    Ok(count)
 }
 
 /// Match any newly become available offers against each others
-async fn make_matches(pool: &PgPool) -> Result<usize, Box<dyn std::error::Error>> {
+///
+/// Only the buyers whose bond covers all of their offers get to be matched,
+/// and only with what is left of the sell offers after their earlier matches.
+async fn make_matches(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+    epoch: Epoch,
+) -> Result<usize, Box<dyn std::error::Error>> {
     struct Offer {
         id: OfferId,
         amount: BigDecimal,
+        public_key: String,
+        rail: PaymentRail,
+        currency: String,
+        price: BigDecimal,
+        created_at: Option<PrimitiveDateTime>,
+    }
+
+    impl From<Offer> for Order {
+        fn from(offer: Offer) -> Self {
+            Order {
+                id: offer.id,
+                amount: offer.amount,
+                rail: offer.rail,
+                currency: offer.currency,
+                price: offer.price,
+                created_at: offer.created_at,
+            }
+        }
     }
 
+    let Some(program_id) = conf.program_id() else {
+        return Err("PROGRAM_ID is not set".into());
+    };
+
     let mut transaction = pool.begin().await?;
-    let onramp_offers = sqlx::query_as!(
+    epoch.fence(&mut *transaction).await?;
+    let mut onramp_offers = sqlx::query_as!(
         Offer,
-        "SELECT id, amount FROM offer WHERE direction = 'fiat_to_dd' AND id NOT IN (SELECT onramp_offer_id FROM match)"
+        r#"SELECT id, amount, public_key, rail as "rail: PaymentRail", currency, price, created_at FROM offer WHERE direction = 'fiat_to_dd' AND id NOT IN (SELECT onramp_offer_id FROM match)"#
     )
     .fetch_all(&mut *transaction)
     .await?;
 
-    let offramp_offers = sqlx::query_as!(
+    let mut offramp_offers = sqlx::query_as!(
         Offer,
-        "SELECT id, amount FROM offer WHERE direction = 'dd_to_fiat' AND id NOT IN (SELECT offramp_offer_id FROM match)"
+        r#"
+        SELECT
+            id,
+            amount - COALESCE((SELECT SUM(quantity) FROM match WHERE offramp_offer_id = offer.id), 0) as "amount!",
+            public_key,
+            rail as "rail: PaymentRail",
+            currency,
+            price,
+            created_at
+        FROM
+            offer
+        WHERE
+            direction = 'dd_to_fiat'
+        "#
     )
     .fetch_all(&mut *transaction)
     .await?;
+    offramp_offers.retain(|offer| offer.amount > BigDecimal::from(0));
 
-    let mut count = 0;
-    for offramp_offer in &offramp_offers {
-        for onramp_offer in &onramp_offers {
-            if offramp_offer.amount == onramp_offer.amount {
-                let onramp_id: i64 = onramp_offer.id.into();
-                let offramp_id: i64 = offramp_offer.id.into();
-                sqlx::query!(
-                    "INSERT INTO match (onramp_offer_id, offramp_offer_id) VALUES ($1, $2)",
-                    onramp_id,
-                    offramp_id
-                )
-                .execute(&mut *transaction)
-                .await?;
-                count += 1;
-            }
+    let mut is_bonded = HashMap::new();
+    for offer in &onramp_offers {
+        if is_bonded.contains_key(&offer.public_key) {
+            continue;
+        }
+        let Ok(wallet) = Pubkey::from_str(&offer.public_key) else {
+            continue;
+        };
+        let stats = fetch_user_stats(client, program_id, &wallet).await;
+        let bond = fetch_bond(client, program_id, &wallet).await;
+        let bids = count_bids(&mut *transaction, &offer.public_key).await?;
+        let required = covering_bond(stats.required_bond(conf.bond_amount()), bids);
+        is_bonded.insert(offer.public_key.clone(), bond >= required);
+    }
+    onramp_offers.retain(|offer| is_bonded.get(&offer.public_key) == Some(&true));
+
+    let mut book = OrderBook::default();
+    book.extend(
+        OfferDirection::FiatToDD,
+        onramp_offers.into_iter().map(Order::from).collect(),
+    );
+    book.extend(
+        OfferDirection::DDToFiat,
+        offramp_offers.into_iter().map(Order::from).collect(),
+    );
+
+    let payment_window = conf.payment_window().as_secs_f64();
+    let matches = book.match_all();
+    for pair in &matches {
+        let onramp_id: i64 = pair.onramp_offer_id.into();
+        let offramp_id: i64 = pair.offramp_offer_id.into();
+        let record = sqlx::query!(
+            r#"
+            INSERT INTO match (onramp_offer_id, offramp_offer_id, quantity, price, payment_deadline)
+            VALUES ($1, $2, $3, $4, NOW() + make_interval(secs => $5))
+            RETURNING id
+            "#,
+            onramp_id,
+            offramp_id,
+            pair.quantity,
+            pair.price,
+            payment_window,
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        let match_id = Some(OfferId::from(record.id));
+        for offer_id in [pair.onramp_offer_id, pair.offramp_offer_id] {
+            transition(
+                &mut transaction,
+                offer_id,
+                match_id,
+                DealState::Matched,
+                "matched",
+            )
+            .await?;
         }
     }
 
     transaction.commit().await?;
 
-    Ok(count)
+    Ok(matches.len())
+}
+
+/// A match with both of its fiat legs seen
+struct DoneMatch {
+    match_id: OfferId,
+    onramp_offer_id: OfferId,
+    offramp_offer_id: OfferId,
+    onramp_public_key: String,
+    offramp_public_key: String,
+    quantity: BigDecimal,
+    currency: String,
+    price: BigDecimal,
+    buyer_transaction_id: Option<String>,
+    seller_transaction_id: Option<String>,
+    fiat_salt: Vec<u8>,
+    release_transaction: Option<Vec<u8>>,
 }
 
-/// Give the buyers their DD if the match is complete
+/// Give the buyers their DD if the match is complete,
+/// taking it out of the escrow of the seller and leaving the rest of their offer on the book
+///
+/// The matches in dispute are left for [`crate::dispute`] to settle.
 async fn release_funds_if_done(
     conf: &Conf,
     pool: &PgPool,
+    client: &impl ChainClient,
+    epoch: Epoch,
 ) -> Result<usize, Box<dyn std::error::Error>> {
-    struct Match {
-        match_id: OfferId,
-        onramp_offer_id: OfferId,
-        offramp_offer_id: OfferId,
-        onramp_public_key: String,
-        offramp_public_key: String,
+    let all_done = sqlx::query_as!(
+        DoneMatch,
+        r#"
+        SELECT
+            match.id as match_id,
+            match.onramp_offer_id,
+            match.offramp_offer_id,
+            bid.public_key as onramp_public_key,
+            ask.public_key as offramp_public_key,
+            match.quantity,
+            ask.currency,
+            match.price,
+            match.buyer_transaction_id,
+            match.seller_transaction_id,
+            match.fiat_salt,
+            match.release_transaction
+        FROM
+            match
+        JOIN
+            offer bid ON match.onramp_offer_id = bid.id
+        JOIN
+            offer ask ON match.offramp_offer_id = ask.id
+        WHERE
+            match.buyer_sent_fiat = TRUE AND match.seller_received_fiat = TRUE
+            AND
+            NOT EXISTS (
+                SELECT 1 FROM deal_event
+                WHERE deal_event.offer_id = match.onramp_offer_id AND deal_event.state = 'disputed'
+            )
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut count = 0;
+    for deal in all_done {
+        let id = deal.match_id;
+        match release_step(conf, pool, client, epoch, deal).await {
+            Err(e) => tracing::error!("While releasing match #{id}: {:?}", e),
+            Ok(true) => count += 1,
+            Ok(false) => {}
+        }
     }
 
+    Ok(count)
+}
+
+/// Take the release of the `deal` one step further, telling whether it is settled now
+///
+/// Every step is safe to crash after: the signed transaction is kept before it is sent,
+/// and the deal is only settled once that transaction is seen confirmed on chain.
+/// A transaction that failed or can no longer land is forgotten and signed anew on a later round,
+/// so each portion of the escrow is released exactly once.
+async fn release_step(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+    epoch: Epoch,
+    deal: DoneMatch,
+) -> Result<bool, Box<dyn std::error::Error>> {
     let (Some(token_program), Some(associated_token_program), Some(dd_mint)) = (
         conf.token_program(),
         conf.associated_token_program(),
@@ -172,16 +509,234 @@ async fn release_funds_if_done(
         return Err("envs are missing".into());
     };
 
+    let author = Pubkey::from_str(&deal.offramp_public_key)?;
+    let buyer = Pubkey::from_str(&deal.onramp_public_key)?;
+    let (target_account, _) = Pubkey::find_program_address(
+        &[buyer.as_ref(), token_program.as_ref(), dd_mint.as_ref()],
+        associated_token_program,
+    );
+    let buyer_transaction_id = deal.buyer_transaction_id.unwrap_or_default();
+    let seller_transaction_id = deal.seller_transaction_id.unwrap_or_default();
+    let commitment = fiat_commitment(
+        &deal.fiat_salt,
+        &buyer_transaction_id,
+        &seller_transaction_id,
+    );
+    let id: i64 = deal.match_id.into();
+
+    let Some(wire) = deal.release_transaction else {
+        let Some(quantity) = deal.quantity.to_u64() else {
+            return Err(format!("Match #{} is for {} DD", deal.match_id, deal.quantity).into());
+        };
+        let release = sign_release_portion(
+            conf,
+            client,
+            &author,
+            &buyer,
+            &target_account,
+            quantity,
+            commitment,
+        )
+        .await?;
+        let mut transaction = pool.begin().await?;
+        epoch.fence(&mut *transaction).await?;
+        transition(
+            &mut transaction,
+            deal.onramp_offer_id,
+            Some(deal.match_id),
+            DealState::Releasing,
+            &format!("signed {}", release.signatures[0]),
+        )
+        .await?;
+        sqlx::query!(
+            "UPDATE match SET release_transaction = $2 WHERE id = $1",
+            id,
+            bincode::serialize(&release)?,
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        // Whether it went out or not, its signature tells on a later round:
+        if let Err(e) = client.send_transaction(&release).await {
+            tracing::warn!(
+                "While sending the release of match #{}: {:?}",
+                deal.match_id,
+                e
+            );
+        }
+        return Ok(false);
+    };
+
+    let release: Transaction = bincode::deserialize(&wire)?;
+    let signature = release.signatures[0];
+    match follow(conf, pool, client, epoch, &release).await? {
+        Landing::Pending => return Ok(false),
+        Landing::Lost => {
+            tracing::warn!("Release {signature} of match #{} is lost", deal.match_id);
+            forget_release(pool, epoch, id).await?;
+            return Ok(false);
+        }
+        Landing::Confirmed => {}
+    }
+
     let mut transaction = pool.begin().await?;
-    let all_done = sqlx::query_as!(
-        Match,
+    epoch.fence(&mut *transaction).await?;
+    // Keep what it takes to open the commitment for an auditor:
+    sqlx::query!(
+        r#"
+        INSERT INTO settlement (
+            id,
+            onramp_public_key,
+            offramp_public_key,
+            amount,
+            buyer_transaction_id,
+            seller_transaction_id,
+            fiat_salt,
+            fiat_commitment,
+            currency,
+            price,
+            release_signature
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        "#,
+        id,
+        deal.onramp_public_key,
+        deal.offramp_public_key,
+        deal.quantity,
+        buyer_transaction_id,
+        seller_transaction_id,
+        deal.fiat_salt,
+        &commitment[..],
+        deal.currency,
+        deal.price,
+        signature.to_string(),
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    transition(
+        &mut transaction,
+        deal.onramp_offer_id,
+        Some(deal.match_id),
+        DealState::Settled,
+        &format!("released in {signature}"),
+    )
+    .await?;
+    archive_match(&mut transaction, deal.match_id).await?;
+    archive_offer(&mut transaction, deal.onramp_offer_id).await?;
+    let id: i64 = deal.offramp_offer_id.into();
+    sqlx::query!(
+        "UPDATE offer SET amount = amount - $2 WHERE id = $1",
+        id,
+        deal.quantity,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    after_match_gone(&mut transaction, deal.offramp_offer_id, deal.match_id).await?;
+    transaction.commit().await?;
+
+    // The deal went through, so the buyer deserves their bond back once it covers nothing else,
+    // but failing to return it should not hold the deal up:
+    if count_bids(pool, &deal.onramp_public_key).await? == 0 {
+        epoch.fence(pool).await?;
+        if let Err(e) = return_bond(conf, client, &buyer, &target_account).await {
+            tracing::error!("While returning the bond of {buyer}: {:?}", e);
+        }
+    }
+
+    Ok(true)
+}
+
+/// What became of a transaction the treasurer signed and we kept
+#[derive(Debug, PartialEq, Eq)]
+pub enum Landing {
+    /// It may still land, or has landed but may yet be rolled back
+    Pending,
+    /// It failed, or can no longer land, so another one has to be signed in its place
+    Lost,
+    /// It has landed for good
+    Confirmed,
+}
+
+/// Follow the kept `transaction` one step further, nudging it along while it may still land
+pub async fn follow(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+    epoch: Epoch,
+    transaction: &Transaction,
+) -> Result<Landing, Box<dyn std::error::Error>> {
+    let signature = transaction.signatures[0];
+    match client.signature_status(&signature).await? {
+        Some(status) if status.err.is_some() => {
+            tracing::warn!("Transaction {signature} failed: {:?}", status.err);
+            Ok(Landing::Lost)
+        }
+        Some(status) if status.satisfies_commitment(conf.commitment()) => Ok(Landing::Confirmed),
+        Some(_) => Ok(Landing::Pending),
+        None => {
+            let blockhash = &transaction.message.recent_blockhash;
+            if client.is_blockhash_valid(blockhash).await? {
+                epoch.fence(pool).await?;
+                let _ = client.send_transaction(transaction).await;
+                Ok(Landing::Pending)
+            } else if client.signature_status(&signature).await?.is_none() {
+                // It has not landed by the time its blockhash expired, so now it never will;
+                // asking before we knew that, it could have landed in between:
+                Ok(Landing::Lost)
+            } else {
+                Ok(Landing::Pending)
+            }
+        }
+    }
+}
+
+/// Drop the release transaction of the match, so that a new one gets signed
+async fn forget_release(
+    pool: &PgPool,
+    epoch: Epoch,
+    match_id: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut transaction = pool.begin().await?;
+    epoch.fence(&mut *transaction).await?;
+    sqlx::query!(
+        "UPDATE match SET release_transaction = NULL WHERE id = $1",
+        match_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// A match whose buyer has not paid by the deadline
+struct UnpaidMatch {
+    match_id: OfferId,
+    onramp_offer_id: OfferId,
+    offramp_offer_id: OfferId,
+    onramp_public_key: String,
+    offramp_public_key: String,
+    slash_transaction: Option<Vec<u8>>,
+}
+
+/// Dissolve the matches where the buyer has not paid by the deadline,
+/// giving the part of their bond that backed the deal to the seller, dropping their offer,
+/// and putting the seller's offer back on the book
+async fn slash_unpaid_matches(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+    epoch: Epoch,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let overdue = sqlx::query_as!(
+        UnpaidMatch,
         r#"
         SELECT
             match.id as match_id,
             match.onramp_offer_id,
             match.offramp_offer_id,
             bid.public_key as onramp_public_key,
-            ask.public_key as offramp_public_key
+            ask.public_key as offramp_public_key,
+            match.slash_transaction
         FROM
             match
         JOIN
@@ -189,113 +744,1081 @@ async fn release_funds_if_done(
         JOIN
             offer ask ON match.offramp_offer_id = ask.id
         WHERE
-            match.buyer_sent_fiat = TRUE AND match.seller_received_fiat = TRUE
+            match.buyer_sent_fiat = FALSE
+            AND
+            match.payment_deadline < NOW()
+            AND
+            NOT EXISTS (
+                SELECT 1 FROM deal_event
+                WHERE deal_event.offer_id = match.onramp_offer_id AND deal_event.state = 'disputed'
+            )
         "#
     )
-    .fetch_all(&mut *transaction)
+    .fetch_all(pool)
     .await?;
 
     let mut count = 0;
-    for deal in all_done {
-        let author = Pubkey::from_str(&deal.offramp_public_key)?;
-        let (target_account, _) = Pubkey::find_program_address(
-            &[
-                Pubkey::from_str(&deal.onramp_public_key)?.as_ref(),
-                token_program.as_ref(),
-                dd_mint.as_ref(),
-            ],
-            associated_token_program,
-        );
-        release_funds(conf, author, target_account).await?;
-
-        let id: i64 = deal.match_id.into();
-        sqlx::query!("DELETE FROM match WHERE id = $1", id)
-            .execute(&mut *transaction)
-            .await?;
-        let id: i64 = deal.onramp_offer_id.into();
-        sqlx::query!("DELETE FROM offer WHERE id = $1", id)
-            .execute(&mut *transaction)
-            .await?;
-        let id: i64 = deal.offramp_offer_id.into();
-        sqlx::query!("DELETE FROM offer WHERE id = $1", id)
-            .execute(&mut *transaction)
-            .await?;
-
-        count += 1;
+    for deal in overdue {
+        let id = deal.match_id;
+        match slash_step(conf, pool, client, epoch, deal).await {
+            Err(e) => tracing::error!("While slashing the bond behind match #{id}: {:?}", e),
+            Ok(true) => count += 1,
+            Ok(false) => {}
+        }
     }
 
-    transaction.commit().await?;
-
     Ok(count)
 }
 
-/// Do all the work behind the scenes
-pub async fn run(conf: Conf, pool: PgPool) {
-    let client = solana_client::nonblocking::rpc_client::RpcClient::new(SOLANA_RPC_URL.to_string());
+/// Take the slashing of the bond behind the unpaid `deal` one step further, telling whether it is dissolved now
+///
+/// Like a release, the signed transaction is kept before it is sent,
+/// and the match is only dissolved once that transaction is seen confirmed on chain,
+/// so the seller gets the part of the bond that backed the deal exactly once.
+async fn slash_step(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+    epoch: Epoch,
+    deal: UnpaidMatch,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let (Some(program_id), Some(token_program), Some(associated_token_program), Some(dd_mint)) = (
+        conf.program_id(),
+        conf.token_program(),
+        conf.associated_token_program(),
+        conf.dd_mint(),
+    ) else {
+        return Err("envs are missing".into());
+    };
+
+    let buyer = Pubkey::from_str(&deal.onramp_public_key)?;
+    let seller = Pubkey::from_str(&deal.offramp_public_key)?;
+    let id: i64 = deal.match_id.into();
+    // What each of the offers of the buyer has to be backed by:
+    let per_bid = fetch_user_stats(client, program_id, &buyer)
+        .await
+        .required_bond(conf.bond_amount());
 
-    loop {
-        let result = promote_all_preoffers(&conf, &pool, &client).await;
-        match result {
-            Err(e) => {
-                tracing::error!("While promoting preoffers: {:?}", e);
-                continue;
-            }
-            Ok(n) if n > 0 => {
-                tracing::info!("Promoted {n} preoffers");
+    let signature = match deal.slash_transaction {
+        None => {
+            let amount = per_bid.min(fetch_bond(client, program_id, &buyer).await);
+            if amount > 0 {
+                tracing::warn!(
+                    "Match #{} missed its payment deadline; {buyer} never paid {seller}",
+                    deal.match_id,
+                );
+                let (target_account, _) = Pubkey::find_program_address(
+                    &[seller.as_ref(), token_program.as_ref(), dd_mint.as_ref()],
+                    associated_token_program,
+                );
+                let slash =
+                    sign_slash_bond(conf, client, &buyer, &seller, &target_account, amount).await?;
+                let mut transaction = pool.begin().await?;
+                epoch.fence(&mut *transaction).await?;
+                sqlx::query!(
+                    "UPDATE match SET slash_transaction = $2 WHERE id = $1",
+                    id,
+                    bincode::serialize(&slash)?,
+                )
+                .execute(&mut *transaction)
+                .await?;
+                transaction.commit().await?;
+                // Whether it went out or not, its signature tells on a later round:
+                if let Err(e) = client.send_transaction(&slash).await {
+                    tracing::warn!(
+                        "While slashing the bond behind match #{}: {:?}",
+                        deal.match_id,
+                        e
+                    );
+                }
+                return Ok(false);
             }
-            Ok(_) => {}
+            // Nothing is left of the bond for the seller to get
+            None
         }
-
-        let result = make_matches(&pool).await;
-        match result {
-            Err(e) => tracing::error!("While making matches: {:?}", e),
-            Ok(n) if n > 0 => tracing::info!("Paired up {n} offers"),
-            _ => {}
+        Some(wire) => {
+            let slash: Transaction = bincode::deserialize(&wire)?;
+            let signature = slash.signatures[0];
+            match follow(conf, pool, client, epoch, &slash).await? {
+                Landing::Pending => return Ok(false),
+                Landing::Lost => {
+                    tracing::warn!("Slash {signature} of match #{} is lost", deal.match_id);
+                    let mut transaction = pool.begin().await?;
+                    epoch.fence(&mut *transaction).await?;
+                    sqlx::query!(
+                        "UPDATE match SET slash_transaction = NULL WHERE id = $1",
+                        id
+                    )
+                    .execute(&mut *transaction)
+                    .await?;
+                    transaction.commit().await?;
+                    return Ok(false);
+                }
+                Landing::Confirmed => Some(signature),
+            }
         }
+    };
 
-        let result = release_funds_if_done(&conf, &pool).await;
-        match result {
-            Err(e) => tracing::error!("While releasing funds: {:?}", e),
-            Ok(n) if n > 0 => tracing::info!("Finalized {n} deals"),
-            _ => {}
-        }
+    // The seller's offer is no longer in this match, so it is back on the book:
+    let cause = match signature {
+        Some(signature) => format!("missed the payment deadline, bond slashed in {signature}"),
+        None => "missed the payment deadline".to_string(),
+    };
+    let mut transaction = pool.begin().await?;
+    epoch.fence(&mut *transaction).await?;
+    transition(
+        &mut transaction,
+        deal.onramp_offer_id,
+        Some(deal.match_id),
+        DealState::Cancelled,
+        &cause,
+    )
+    .await?;
+    archive_match(&mut transaction, deal.match_id).await?;
+    archive_offer(&mut transaction, deal.onramp_offer_id).await?;
+    after_match_gone(&mut transaction, deal.offramp_offer_id, deal.match_id).await?;
+    transaction.commit().await?;
 
-        tokio::time::sleep(tokio::time::Duration::from_secs(4)).await;
-    }
+    after_slash(conf, pool, client, epoch, &buyer, per_bid).await?;
+    Ok(true)
 }
 
-/// Release funds held under deal initiated by `author` to the `target_account`
-async fn release_funds(
+/// Once a part of the bond of `buyer` went to a seller, with `per_bid` backing each of their offers,
+/// give back what is left of it if it covers no offer anymore,
+/// and otherwise dissolve the matches it falls short of covering
+pub async fn after_slash(
     conf: &Conf,
-    author: Pubkey,
-    target_account: Pubkey,
+    pool: &PgPool,
+    client: &impl ChainClient,
+    epoch: Epoch,
+    buyer: &Pubkey,
+    per_bid: u64,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (Some(token_program), Some(dd_mint), Some(treasurer_secret_key)) = (
+    let (Some(program_id), Some(token_program), Some(associated_token_program), Some(dd_mint)) = (
+        conf.program_id(),
         conf.token_program(),
+        conf.associated_token_program(),
         conf.dd_mint(),
-        conf.treasurer_secret_key(),
     ) else {
         return Err("envs are missing".into());
     };
 
-    let result = Command::new("control")
-        .env("USER", author.to_string())
-        .env("BENEFICIARY", target_account.to_string())
-        .env("TOKEN_PROGRAM", token_program.to_string())
-        .env("DD_MINT", dd_mint.to_string())
-        .env(
-            "TREASURER_SECRET_KEY",
-            serde_json::to_string(&treasurer_secret_key.to_bytes().to_vec())?,
+    let bond = fetch_bond(client, program_id, buyer).await;
+    let bids = count_bids(pool, &buyer.to_string()).await?;
+    if bids == 0 {
+        if bond > 0 {
+            let (refund_account, _) = Pubkey::find_program_address(
+                &[buyer.as_ref(), token_program.as_ref(), dd_mint.as_ref()],
+                associated_token_program,
+            );
+            epoch.fence(pool).await?;
+            if let Err(e) = return_bond(conf, client, buyer, &refund_account).await {
+                tracing::error!("While returning the bond of {buyer}: {:?}", e);
+            }
+        }
+        return Ok(());
+    }
+    if bond >= covering_bond(per_bid, bids) {
+        return Ok(());
+    }
+
+    let n = unmatch_uncovered(pool, epoch, buyer).await?;
+    if n > 0 {
+        tracing::warn!("The bond of {buyer} no longer covers their offers; dissolved {n} matches");
+    }
+    Ok(())
+}
+
+/// Dissolve the matches of `buyer` that are still waiting for their fiat within the deadline,
+/// putting both offers of each back on the book, where the bid waits for the bond to be topped up
+async fn unmatch_uncovered(
+    pool: &PgPool,
+    epoch: Epoch,
+    buyer: &Pubkey,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut transaction = pool.begin().await?;
+    epoch.fence(&mut *transaction).await?;
+    // The overdue ones get slashed on their own, and the paid or ruled ones are past the bond:
+    let uncovered = sqlx::query!(
+        r#"
+        SELECT
+            match.id,
+            match.onramp_offer_id,
+            match.offramp_offer_id
+        FROM
+            match
+        JOIN
+            offer bid ON match.onramp_offer_id = bid.id
+        WHERE
+            bid.public_key = $1
+            AND
+            match.buyer_sent_fiat = FALSE
+            AND
+            match.payment_deadline >= NOW()
+            AND
+            match.buyer_wins IS NULL
+            AND
+            NOT EXISTS (
+                SELECT 1 FROM deal_event
+                WHERE deal_event.offer_id = match.onramp_offer_id AND deal_event.state = 'disputed'
+            )
+        FOR UPDATE OF match
+        "#,
+        buyer.to_string()
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    for record in &uncovered {
+        let match_id = OfferId::from(record.id);
+        transition(
+            &mut transaction,
+            OfferId::from(record.onramp_offer_id),
+            Some(match_id),
+            DealState::Open,
+            "bond no longer covers it",
         )
-        .output()
         .await?;
-    tracing::info!("control returned: {:?}", result);
-    if !result.status.success() {
-        return Err(format!(
-            "control failed: {}",
-            String::from_utf8_lossy(&result.stderr)
+        archive_match(&mut transaction, match_id).await?;
+        after_match_gone(
+            &mut transaction,
+            OfferId::from(record.offramp_offer_id),
+            match_id,
         )
-        .into());
+        .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(uncovered.len())
+}
+
+/// Bring the sell offer up to date once one of its matches is gone:
+/// it is done when nothing is left of it, and back on the book when nothing else is pending
+pub async fn after_match_gone(
+    connection: &mut PgConnection,
+    offer_id: OfferId,
+    match_id: OfferId,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let id: i64 = offer_id.into();
+    let offer = sqlx::query!(
+        r#"
+        SELECT
+            amount,
+            (SELECT COUNT(*) FROM match WHERE offramp_offer_id = offer.id) as "pending!"
+        FROM
+            offer
+        WHERE
+            id = $1
+        "#,
+        id
+    )
+    .fetch_one(&mut *connection)
+    .await?;
+
+    if offer.amount <= BigDecimal::from(0) {
+        transition(
+            connection,
+            offer_id,
+            Some(match_id),
+            DealState::Settled,
+            "sold out",
+        )
+        .await?;
+        archive_offer(connection, offer_id).await?;
+    } else if offer.pending == 0 {
+        transition(
+            connection,
+            offer_id,
+            Some(match_id),
+            DealState::Open,
+            "back on the book",
+        )
+        .await?;
     }
     Ok(())
 }
+
+/// How often a replica that is not the leader checks whether it can become one
+const LEADERSHIP_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Run the `job` once as the leader for the `epoch`, logging what it has done
+async fn run_job(
+    job: Job,
+    epoch: Epoch,
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match job {
+        Job::Promotion => {
+            let n = promote_all_preoffers(conf, pool, client).await?;
+            if n > 0 {
+                tracing::info!("Promoted {n} preoffers");
+            }
+        }
+        Job::Matching => {
+            let n = make_matches(conf, pool, client, epoch).await?;
+            if n > 0 {
+                tracing::info!("Paired up {n} offers");
+            }
+        }
+        Job::Release => {
+            // The rulings should not wait on the releases, nor the other way around:
+            let mut failed = false;
+            match release_funds_if_done(conf, pool, client, epoch).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Finalized {n} deals"),
+                Err(e) => {
+                    tracing::error!("While releasing deals: {:?}", e);
+                    failed = true;
+                }
+            }
+            match settle_rulings(conf, pool, client, epoch).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Paid out the rulings on {n} disputes"),
+                Err(e) => {
+                    tracing::error!("While paying out rulings: {:?}", e);
+                    failed = true;
+                }
+            }
+            if failed {
+                return Err("Part of the release failed".into());
+            }
+        }
+        Job::Expiry => {
+            // Neither step should hold the other up, so both run whatever became of the other:
+            let mut failed = false;
+            match expire_preoffers(conf, pool, client, epoch).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Expired {n} preoffers"),
+                Err(e) => {
+                    tracing::error!("While expiring preoffers: {:?}", e);
+                    failed = true;
+                }
+            }
+            match slash_unpaid_matches(conf, pool, client, epoch).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Slashed {n} unpaid deals"),
+                Err(e) => {
+                    tracing::error!("While slashing unpaid deals: {:?}", e);
+                    failed = true;
+                }
+            }
+            if failed {
+                return Err("Part of the expiry failed".into());
+            }
+        }
+        Job::Reconciliation => {
+            let report = reconcile(conf, pool, client).await?;
+            for discrepancy in &report.discrepancies {
+                tracing::warn!("The chain and the book disagree: {:?}", discrepancy);
+            }
+            save_report(pool, &report).await?;
+        }
+        Job::Attestation => {
+            let attestation = attest(conf, client).await?;
+            save_attestation(pool, &attestation).await?;
+        }
+        Job::Indexing => {
+            let n = index_chain(conf, pool, client).await?;
+            if n > 0 {
+                tracing::info!("Indexed {n} transactions");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Listen for the jobs to be woken up early
+///
+/// The listener holds on to its connection for as long as it listens,
+/// so it gets one of its own rather than one of the pool the handlers and the jobs share.
+async fn listen(options: &PgConnectOptions) -> Result<PgListener, sqlx::Error> {
+    let connection = PgPoolOptions::new()
+        .max_connections(1)
+        .max_lifetime(None)
+        .idle_timeout(None)
+        .connect_with(options.clone())
+        .await?;
+    let mut listener = PgListener::connect_with(&connection).await?;
+    listener.listen(WAKE_CHANNEL).await?;
+    Ok(listener)
+}
+
+/// The next wake-up call, or never if we are not listening
+async fn next_wake_up(listener: &mut Option<PgListener>) -> Result<PgNotification, sqlx::Error> {
+    match listener {
+        Some(listener) => listener.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Do all the work behind the scenes, each job whenever the `schedule` has it due
+/// or it is woken up, as long as this replica is the leader
+pub async fn run(
+    conf: Arc<Conf>,
+    pool: PgPool,
+    client: Arc<RpcClient>,
+    schedule: Arc<Mutex<Schedule>>,
+    mut leadership: Leadership,
+    options: PgConnectOptions,
+) {
+    let mut listener = None;
+    let mut is_leader = false;
+    loop {
+        let Some((job, due)) = schedule.lock().unwrap().next() else {
+            return;
+        };
+
+        // Only the leader has anything to be woken up for:
+        if is_leader && listener.is_none() {
+            // Without it the jobs still run on their intervals, only later:
+            listener = listen(&options)
+                .await
+                .inspect_err(|e| tracing::warn!("Could not listen for wake-ups: {:?}", e))
+                .ok();
+        }
+        let woken = tokio::select! {
+            _ = tokio::time::sleep_until(due.into()) => None,
+            notification = next_wake_up(&mut listener) => Some(notification),
+        };
+        match woken {
+            None => {}
+            Some(Ok(notification)) => {
+                if let Ok(job) = notification.payload().parse() {
+                    schedule.lock().unwrap().hurry(job, Instant::now());
+                }
+                continue;
+            }
+            Some(Err(e)) => {
+                tracing::warn!("Stopped listening for wake-ups: {:?}", e);
+                listener = None;
+                continue;
+            }
+        }
+
+        let epoch = leadership.hold().await;
+        is_leader = epoch.is_some();
+        schedule.lock().unwrap().set_leading(is_leader);
+        let Some(epoch) = epoch else {
+            listener = None;
+            // The job stays due, so it runs as soon as we take over
+            tokio::time::sleep(LEADERSHIP_RETRY_INTERVAL).await;
+            continue;
+        };
+
+        let result = run_job(job, epoch, &conf, &pool, client.as_ref()).await;
+        let mut schedule = schedule.lock().unwrap();
+        match result {
+            Ok(()) => schedule.succeeded(job, Instant::now()),
+            Err(e) => {
+                tracing::error!("While running {job:?}: {:?}", e);
+                schedule.failed(job, Instant::now());
+            }
+        }
+    }
+}
+
+/// Salted hash of the bank transactions that settled both fiat legs of a deal
+fn fiat_commitment(
+    salt: &[u8],
+    buyer_transaction_id: &str,
+    seller_transaction_id: &str,
+) -> [u8; 32] {
+    solana_sdk::hash::hashv(&[
+        salt,
+        buyer_transaction_id.as_bytes(),
+        b"\n",
+        seller_transaction_id.as_bytes(),
+    ])
+    .to_bytes()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::chain::fake::FakeChain;
+    use crate::treasury::discriminator;
+
+    fn preoffer(amount: u64) -> Preoffer {
+        Preoffer {
+            id: OfferId::from(1),
+            amount: BigDecimal::from(amount),
+            bank_account: String::new(),
+            public_key: Pubkey::new_unique().to_string(),
+            rail: PaymentRail::C2C,
+            currency: "USD".to_string(),
+            price: BigDecimal::from(1),
+        }
+    }
+
+    #[test]
+    fn reads_the_amount_of_a_token_account() {
+        let mut data = vec![0; 165];
+        data[64..72].copy_from_slice(&5_000000u64.to_le_bytes());
+        assert_eq!(token_amount(&data), Some(5_000000));
+        assert_eq!(token_amount(&data[..70]), None);
+    }
+
+    #[test]
+    fn waits_for_the_exact_deposit() {
+        assert_eq!(deposit_arrived(&preoffer(5), 0), Ok(false));
+        assert_eq!(deposit_arrived(&preoffer(5), 5), Ok(true));
+        assert!(deposit_arrived(&preoffer(5), 4).is_err());
+    }
+
+    async fn offer_dd(pool: &PgPool, seller: &Pubkey, amount: u64) -> OfferId {
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO preoffer (amount, bank_account, public_key, rail, currency, price) VALUES ($1, '4111111111111111', $2, 'c2c', 'USD', 1) RETURNING id",
+        )
+        .bind(BigDecimal::from(amount))
+        .bind(seller.to_string())
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        let id = OfferId::from(id);
+        let mut connection = pool.acquire().await.unwrap();
+        transition(
+            &mut connection,
+            id,
+            None,
+            DealState::PendingDeposit,
+            "offered DD",
+        )
+        .await
+        .unwrap();
+        id
+    }
+
+    async fn offer_fiat(pool: &PgPool, buyer: &Pubkey, amount: u64) -> OfferId {
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO offer (amount, bank_account, public_key, direction, rail, currency, price) VALUES ($1, '4111111111111111', $2, 'fiat_to_dd', 'c2c', 'USD', 1) RETURNING id",
+        )
+        .bind(BigDecimal::from(amount))
+        .bind(buyer.to_string())
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        let id = OfferId::from(id);
+        let mut connection = pool.acquire().await.unwrap();
+        transition(&mut connection, id, None, DealState::Open, "offered fiat")
+            .await
+            .unwrap();
+        id
+    }
+
+    pub async fn state_of(pool: &PgPool, offer_id: OfferId) -> DealState {
+        sqlx::query_scalar(
+            "SELECT state FROM deal_event WHERE offer_id = $1 ORDER BY id DESC LIMIT 1",
+        )
+        .bind(i64::from(offer_id))
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    fn address_of(conf: &Conf, seed: &[u8], wallet: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(&[seed, wallet.as_ref()], conf.program_id().unwrap()).0
+    }
+
+    pub fn instruction_of(transaction: &Transaction) -> [u8; 8] {
+        transaction.message.instructions[0].data[..8]
+            .try_into()
+            .unwrap()
+    }
+
+    /// A seller who has deposited, matched with a bonded buyer; returns their offers
+    pub async fn matched(
+        conf: &Conf,
+        pool: &PgPool,
+        chain: &FakeChain,
+        epoch: Epoch,
+    ) -> (OfferId, OfferId) {
+        let (seller, buyer) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let ask = offer_dd(pool, &seller, 5_000000).await;
+        chain.set_token_amount(address_of(conf, b"escrow", &seller), 5_000000);
+        assert_eq!(promote_all_preoffers(conf, pool, chain).await.unwrap(), 1);
+        let bid = offer_fiat(pool, &buyer, 5_000000).await;
+        chain.set_token_amount(address_of(conf, b"bond", &buyer), 2 * conf.bond_amount());
+        assert_eq!(make_matches(conf, pool, chain, epoch).await.unwrap(), 1);
+        (ask, bid)
+    }
+
+    pub async fn see_both_fiat_legs(pool: &PgPool) {
+        sqlx::query(
+            "UPDATE match SET buyer_sent_fiat = TRUE, seller_received_fiat = TRUE, buyer_transaction_id = 'B1', seller_transaction_id = 'S1'",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn wakes_the_job_once_the_work_is_committed(pool: PgPool) {
+        // The pool of the daemon, which the listener must leave alone:
+        let options = (*pool.connect_options()).clone();
+        let pool = crate::pool_options()
+            .acquire_timeout(Duration::from_secs(2))
+            .connect_with(options.clone())
+            .await
+            .unwrap();
+        let mut listener = Some(listen(&options).await.unwrap());
+        let mut transaction = pool.begin().await.unwrap();
+        wake(&mut transaction, Job::Release).await.unwrap();
+        transaction.commit().await.unwrap();
+
+        let notification = next_wake_up(&mut listener).await.unwrap();
+        assert_eq!(notification.payload().parse(), Ok(Job::Release));
+    }
+
+    #[sqlx::test]
+    async fn follows_a_deal_from_deposit_to_release(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (seller, buyer) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        let ask = offer_dd(&pool, &seller, 5_000000).await;
+        assert_eq!(
+            promote_all_preoffers(&conf, &pool, &chain).await.unwrap(),
+            0
+        );
+        chain.set_token_amount(address_of(&conf, b"escrow", &seller), 5_000000);
+        assert_eq!(
+            promote_all_preoffers(&conf, &pool, &chain).await.unwrap(),
+            1
+        );
+        assert_eq!(state_of(&pool, ask).await, DealState::Open);
+
+        let bid = offer_fiat(&pool, &buyer, 5_000000).await;
+        // A fresh buyer without a bond does not get matched:
+        assert_eq!(make_matches(&conf, &pool, &chain, epoch).await.unwrap(), 0);
+        chain.set_token_amount(address_of(&conf, b"bond", &buyer), 2 * conf.bond_amount());
+        assert_eq!(make_matches(&conf, &pool, &chain, epoch).await.unwrap(), 1);
+        assert_eq!(state_of(&pool, bid).await, DealState::Matched);
+
+        // Nothing is released before the fiat has gone both ways:
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(chain.sent().is_empty());
+
+        see_both_fiat_legs(&pool).await;
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Releasing);
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Settled);
+        assert_eq!(state_of(&pool, ask).await, DealState::Settled);
+
+        let sent = chain.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(instruction_of(&sent[0]), discriminator("release_portion"));
+        assert_eq!(instruction_of(&sent[1]), discriminator("return_bond"));
+        let settled: String = sqlx::query_scalar("SELECT release_signature FROM settlement")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(settled, sent[0].signatures[0].to_string());
+    }
+
+    #[sqlx::test]
+    async fn keeps_the_bond_while_it_covers_other_offers(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (seller, buyer) = (Pubkey::new_unique(), Pubkey::new_unique());
+        offer_dd(&pool, &seller, 10_000000).await;
+        chain.set_token_amount(address_of(&conf, b"escrow", &seller), 10_000000);
+        promote_all_preoffers(&conf, &pool, &chain).await.unwrap();
+        let bids = [
+            offer_fiat(&pool, &buyer, 5_000000).await,
+            offer_fiat(&pool, &buyer, 5_000000).await,
+        ];
+
+        // A fresh buyer owes twice the bond for each of their offers:
+        let bond = address_of(&conf, b"bond", &buyer);
+        chain.set_token_amount(bond, 2 * conf.bond_amount());
+        assert_eq!(make_matches(&conf, &pool, &chain, epoch).await.unwrap(), 0);
+        chain.set_token_amount(bond, 4 * conf.bond_amount());
+        assert_eq!(make_matches(&conf, &pool, &chain, epoch).await.unwrap(), 2);
+
+        see_both_fiat_legs(&pool).await;
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            2
+        );
+        for bid in bids {
+            assert_eq!(state_of(&pool, bid).await, DealState::Settled);
+        }
+        // Returned once, after the last of the deals it covered:
+        let sent = chain.sent().iter().map(instruction_of).collect::<Vec<_>>();
+        assert_eq!(
+            sent,
+            [
+                discriminator("release_portion"),
+                discriminator("release_portion"),
+                discriminator("return_bond"),
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn expires_the_preoffers_nobody_deposited_for(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (idle, late) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let abandoned = offer_dd(&pool, &idle, 5_000000).await;
+        let deposited = offer_dd(&pool, &late, 5_000000).await;
+        chain.set_token_amount(address_of(&conf, b"escrow", &late), 5_000000);
+
+        assert_eq!(
+            expire_preoffers(&conf, &pool, &chain, epoch).await.unwrap(),
+            0
+        );
+        sqlx::query("UPDATE preoffer SET created_at = NOW() - INTERVAL '2 hours'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            expire_preoffers(&conf, &pool, &chain, epoch).await.unwrap(),
+            1
+        );
+
+        assert_eq!(state_of(&pool, abandoned).await, DealState::Expired);
+        assert_eq!(state_of(&pool, deposited).await, DealState::Open);
+        let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM preoffer")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+        let archived: i64 = sqlx::query_scalar("SELECT id FROM offer_archive")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(OfferId::from(archived), abandoned);
+    }
+
+    #[sqlx::test]
+    async fn signs_a_failed_release_anew(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (_, bid) = matched(&conf, &pool, &chain, epoch).await;
+        see_both_fiat_legs(&pool).await;
+
+        chain.fail_transactions(true);
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Releasing);
+
+        chain.fail_transactions(false);
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Settled);
+
+        let sent = chain.sent();
+        assert_eq!(sent.len(), 3);
+        assert_ne!(sent[0].signatures[0], sent[1].signatures[0]);
+        // Settled only once, by the release that went through:
+        let settled: Vec<String> = sqlx::query_scalar("SELECT release_signature FROM settlement")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(settled, [sent[1].signatures[0].to_string()]);
+    }
+
+    #[sqlx::test]
+    async fn pays_once_when_the_release_lands_as_it_expires(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (_, bid) = matched(&conf, &pool, &chain, epoch).await;
+        see_both_fiat_legs(&pool).await;
+
+        chain.delay_transactions(true);
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        // Not seen at first, then landed by the time the blockhash is found expired:
+        chain.expire_blockhashes();
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        chain.delay_transactions(false);
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Settled);
+
+        let releases = chain
+            .sent()
+            .iter()
+            .filter(|sent| instruction_of(sent) == discriminator("release_portion"))
+            .map(|sent| sent.signatures[0])
+            .collect::<Vec<_>>();
+        assert_eq!(releases.len(), 1);
+        let settled: String = sqlx::query_scalar("SELECT release_signature FROM settlement")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(settled, releases[0].to_string());
+    }
+
+    #[sqlx::test]
+    async fn leaves_a_disputed_deal_to_a_human(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (_, bid) = matched(&conf, &pool, &chain, epoch).await;
+        see_both_fiat_legs(&pool).await;
+        let mut connection = pool.acquire().await.unwrap();
+        transition(
+            &mut connection,
+            bid,
+            None,
+            DealState::Disputed,
+            "wrong amount",
+        )
+        .await
+        .unwrap();
+        drop(connection);
+
+        for _ in 0..2 {
+            assert_eq!(
+                release_funds_if_done(&conf, &pool, &chain, epoch)
+                    .await
+                    .unwrap(),
+                0
+            );
+        }
+        assert_eq!(state_of(&pool, bid).await, DealState::Disputed);
+        assert!(chain.sent().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn leaves_the_deals_alone_once_taken_over_from(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let stalled = Epoch::begin(&pool).await.unwrap();
+        let (_, bid) = matched(&conf, &pool, &chain, stalled).await;
+        see_both_fiat_legs(&pool).await;
+
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        assert!(make_matches(&conf, &pool, &chain, stalled).await.is_err());
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, stalled)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Matched);
+        assert!(chain.sent().is_empty());
+
+        assert_eq!(
+            release_funds_if_done(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Releasing);
+    }
+
+    #[sqlx::test]
+    async fn slashes_a_buyer_who_never_paid(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (ask, bid) = matched(&conf, &pool, &chain, epoch).await;
+
+        assert_eq!(
+            slash_unpaid_matches(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        sqlx::query("UPDATE match SET payment_deadline = NOW() - INTERVAL '1 second'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        // Signed and sent, then seen landed with the whole bond, which backed just this deal:
+        assert_eq!(
+            slash_unpaid_matches(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        let buyer: String = sqlx::query_scalar("SELECT public_key FROM offer WHERE id = $1")
+            .bind(i64::from(bid))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let buyer = Pubkey::from_str(&buyer).unwrap();
+        chain.set_token_amount(address_of(&conf, b"bond", &buyer), 0);
+        assert_eq!(
+            slash_unpaid_matches(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            1
+        );
+
+        assert_eq!(state_of(&pool, bid).await, DealState::Cancelled);
+        assert_eq!(state_of(&pool, ask).await, DealState::Open);
+        let sent = chain.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(instruction_of(&sent[0]), discriminator("slash_bond"));
+        assert_eq!(
+            sent[0].message.instructions[0].data[8..],
+            (2 * conf.bond_amount()).to_le_bytes()
+        );
+    }
+
+    #[sqlx::test]
+    async fn slashes_even_when_the_preoffers_cannot_be_expired(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (_, bid) = matched(&conf, &pool, &chain, epoch).await;
+        sqlx::query("UPDATE match SET payment_deadline = NOW() - INTERVAL '1 second'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("ALTER TABLE preoffer RENAME TO preoffer_gone")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(run_job(Job::Expiry, epoch, &conf, &pool, &chain)
+            .await
+            .is_err());
+        let sent = chain.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(instruction_of(&sent[0]), discriminator("slash_bond"));
+        assert!(run_job(Job::Expiry, epoch, &conf, &pool, &chain)
+            .await
+            .is_err());
+        assert_eq!(state_of(&pool, bid).await, DealState::Cancelled);
+    }
+
+    #[sqlx::test]
+    async fn slashes_only_the_part_of_the_bond_behind_each_unpaid_match(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let buyer = Pubkey::new_unique();
+        // A fresh buyer puts up twice as much for each offer:
+        let per_bid = 2 * conf.bond_amount();
+        let mut asks = vec![];
+        for _ in 0..3 {
+            let seller = Pubkey::new_unique();
+            asks.push(offer_dd(&pool, &seller, 5_000000).await);
+            chain.set_token_amount(address_of(&conf, b"escrow", &seller), 5_000000);
+        }
+        assert_eq!(
+            promote_all_preoffers(&conf, &pool, &chain).await.unwrap(),
+            3
+        );
+        let mut bids = vec![];
+        for _ in 0..3 {
+            bids.push(offer_fiat(&pool, &buyer, 5_000000).await);
+        }
+        let bond = address_of(&conf, b"bond", &buyer);
+        chain.set_token_amount(bond, 3 * per_bid);
+        assert_eq!(make_matches(&conf, &pool, &chain, epoch).await.unwrap(), 3);
+
+        // When two of the three matches miss their deadline:
+        sqlx::query(
+            "UPDATE match SET payment_deadline = NOW() - INTERVAL '1 second' WHERE onramp_offer_id <> $1",
+        )
+        .bind(i64::from(bids[2]))
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            slash_unpaid_matches(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            0
+        );
+        // Then each seller gets the part that backed their deal:
+        let sent = chain.sent();
+        assert_eq!(sent.len(), 2);
+        for slash in &sent {
+            assert_eq!(instruction_of(slash), discriminator("slash_bond"));
+            assert_eq!(
+                slash.message.instructions[0].data[8..],
+                per_bid.to_le_bytes()
+            );
+        }
+
+        // And once both have landed, the third deal is left with less than it needs,
+        // so it is dissolved until the buyer tops the bond up:
+        chain.set_token_amount(bond, per_bid / 2);
+        assert_eq!(
+            slash_unpaid_matches(&conf, &pool, &chain, epoch)
+                .await
+                .unwrap(),
+            2
+        );
+        for bid in &bids[..2] {
+            assert_eq!(state_of(&pool, *bid).await, DealState::Cancelled);
+        }
+        assert_eq!(state_of(&pool, bids[2]).await, DealState::Open);
+        for ask in asks {
+            assert_eq!(state_of(&pool, ask).await, DealState::Open);
+        }
+        assert_eq!(make_matches(&conf, &pool, &chain, epoch).await.unwrap(), 0);
+        // Nothing more was sent for them:
+        assert_eq!(chain.sent().len(), 2);
+    }
+}
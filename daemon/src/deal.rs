@@ -0,0 +1,195 @@
+//! Lifecycle of a deal, which is an offer followed from its creation to its settlement
+//!
+//! The state of a deal is whatever its latest entry in `deal_event` says,
+//! and the entries are only ever appended.
+//! A sell offer filled by several buyers stays matched until the last of its matches is done;
+//! the fiat legs of each match are followed on the buy offer.
+use sqlx::PgConnection;
+
+use crate::schema::OfferId;
+
+#[derive(sqlx::Type, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "deal_state", rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum DealState {
+    /// A sell offer waiting for its DD to show up in escrow
+    PendingDeposit,
+    /// On the book
+    Open,
+    Matched,
+    BuyerPaid,
+    SellerConfirmed,
+    /// The treasurer is sending the DD over
+    Releasing,
+    Settled,
+    Cancelled,
+    /// Needs a human to rule on it, which settles or cancels it
+    Disputed,
+    /// A sell offer whose DD never showed up in escrow
+    Expired,
+}
+
+impl DealState {
+    /// Whether a deal in this state may move on to `next`
+    pub fn can_become(self, next: Self) -> bool {
+        use DealState::*;
+
+        matches!(
+            (self, next),
+            (PendingDeposit, Open | Cancelled | Expired)
+                | (Open, Matched | Cancelled)
+                | (
+                    Matched,
+                    Open | BuyerPaid | SellerConfirmed | Releasing | Settled | Cancelled | Disputed
+                )
+                | (BuyerPaid, SellerConfirmed | Releasing | Disputed)
+                | (SellerConfirmed, BuyerPaid | Releasing | Disputed)
+                | (Releasing, Settled)
+                | (Disputed, Settled | Cancelled)
+        )
+    }
+}
+
+/// Move the deal of `offer_id` into the `next` state for the given `cause`,
+/// refusing the moves the lifecycle does not allow
+///
+/// Moving into the state the deal is already in is a no-op, so that retries are harmless.
+pub async fn transition(
+    connection: &mut PgConnection,
+    offer_id: OfferId,
+    match_id: Option<OfferId>,
+    next: DealState,
+    cause: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let id: i64 = offer_id.into();
+    let current = sqlx::query_scalar!(
+        r#"SELECT state as "state: DealState" FROM deal_event WHERE offer_id = $1 ORDER BY id DESC LIMIT 1"#,
+        id
+    )
+    .fetch_optional(&mut *connection)
+    .await?;
+
+    match current {
+        Some(current) if current == next => return Ok(()),
+        Some(current) if !current.can_become(next) => {
+            return Err(format!("Deal #{offer_id} cannot go from {current:?} to {next:?}").into());
+        }
+        _ => {}
+    }
+
+    sqlx::query!(
+        "INSERT INTO deal_event (offer_id, match_id, state, cause) VALUES ($1, $2, $3, $4)",
+        id,
+        match_id.map(i64::from),
+        next as DealState,
+        cause,
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Move the offer out of the book and into the archive
+pub async fn archive_offer(
+    connection: &mut PgConnection,
+    offer_id: OfferId,
+) -> Result<(), sqlx::Error> {
+    let id: i64 = offer_id.into();
+    sqlx::query!("INSERT INTO offer_archive (id, public_key, record) SELECT id, public_key, to_jsonb(offer) FROM offer WHERE id = $1", id)
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query!("DELETE FROM offer WHERE id = $1", id)
+        .execute(&mut *connection)
+        .await?;
+    Ok(())
+}
+
+/// Move the preoffer, which never made it to the book, into the archive of offers
+pub async fn archive_preoffer(
+    connection: &mut PgConnection,
+    preoffer_id: OfferId,
+) -> Result<(), sqlx::Error> {
+    let id: i64 = preoffer_id.into();
+    sqlx::query!("INSERT INTO offer_archive (id, public_key, record) SELECT id, public_key, to_jsonb(preoffer) FROM preoffer WHERE id = $1", id)
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query!("DELETE FROM preoffer WHERE id = $1", id)
+        .execute(&mut *connection)
+        .await?;
+    Ok(())
+}
+
+/// Move the match out of the live ones and into the archive
+pub async fn archive_match(
+    connection: &mut PgConnection,
+    match_id: OfferId,
+) -> Result<(), sqlx::Error> {
+    let id: i64 = match_id.into();
+    sqlx::query!("INSERT INTO match_archive (id, record) SELECT id, to_jsonb(match) FROM match WHERE id = $1", id)
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query!("DELETE FROM match WHERE id = $1", id)
+        .execute(&mut *connection)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DealState::*;
+
+    #[test]
+    fn follows_a_deal_from_deposit_to_settlement() {
+        let path = [
+            PendingDeposit,
+            Open,
+            Matched,
+            BuyerPaid,
+            SellerConfirmed,
+            Releasing,
+            Settled,
+        ];
+        for step in path.windows(2) {
+            assert!(
+                step[0].can_become(step[1]),
+                "{:?} -> {:?}",
+                step[0],
+                step[1]
+            );
+        }
+    }
+
+    #[test]
+    fn never_leaves_a_final_state() {
+        let all = [
+            PendingDeposit,
+            Open,
+            Matched,
+            BuyerPaid,
+            SellerConfirmed,
+            Releasing,
+            Settled,
+            Cancelled,
+            Disputed,
+            Expired,
+        ];
+        for last in [Settled, Cancelled, Expired] {
+            assert!(all.iter().all(|&next| !last.can_become(next)));
+        }
+    }
+
+    #[test]
+    fn only_leaves_a_dispute_by_a_ruling() {
+        assert!(Disputed.can_become(Settled));
+        assert!(Disputed.can_become(Cancelled));
+        assert!(!Disputed.can_become(Releasing));
+        assert!(!Disputed.can_become(BuyerPaid));
+    }
+
+    #[test]
+    fn does_not_release_before_matching() {
+        assert!(!Open.can_become(Releasing));
+        assert!(!PendingDeposit.can_become(Matched));
+    }
+}
@@ -0,0 +1,444 @@
+//! Deals that have to be ruled on by a human
+//!
+//! A deal is put in dispute when a transfer between its parties does not pay for it,
+//! and nothing moves it on from there by itself: the release and the slashing leave it alone.
+//! Whoever runs the deployment rules for one side, which the release job then pays the DD of the deal
+//! out to from the escrow of the seller, the rest of the escrow staying with the other deals.
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, ToPrimitive};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::transaction::Transaction;
+use sqlx::PgPool;
+
+use crate::bond::{count_bids, fetch_bond};
+use crate::chain::ChainClient;
+use crate::conf::Conf;
+use crate::cron::{after_match_gone, after_slash, follow, Landing};
+use crate::deal::{archive_match, archive_offer, transition, DealState};
+use crate::leader::Epoch;
+use crate::reputation::fetch_user_stats;
+use crate::schedule::{wake, Job};
+use crate::schema::OfferId;
+use crate::treasury::{return_bond, sign_resolve_dispute, slash_bond};
+
+/// The state the deal of the buyer ends in once the ruling is paid out
+fn ends_in(buyer_wins: bool) -> DealState {
+    if buyer_wins {
+        DealState::Settled
+    } else {
+        DealState::Cancelled
+    }
+}
+
+/// Rule on the disputed match `match_id`, for the buyer if `buyer_wins` and for the seller otherwise
+///
+/// Only the ruling is recorded here, and the release job pays it out,
+/// so returns the state the deal of the buyer is going to end in,
+/// or nothing if the match is not in dispute or was ruled on the other way.
+pub async fn resolve(
+    pool: &PgPool,
+    match_id: OfferId,
+    buyer_wins: bool,
+) -> Result<Option<DealState>, Box<dyn std::error::Error>> {
+    let id: i64 = match_id.into();
+    let mut transaction = pool.begin().await?;
+    // Locked until the ruling is recorded, so that only one of two rulings stands:
+    let deal = sqlx::query!(
+        r#"
+        SELECT
+            match.buyer_wins,
+            (
+                SELECT state FROM deal_event
+                WHERE deal_event.offer_id = match.onramp_offer_id
+                ORDER BY deal_event.id DESC LIMIT 1
+            ) as "state: DealState"
+        FROM
+            match
+        WHERE
+            match.id = $1
+        FOR UPDATE OF match
+        "#,
+        id
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let Some(deal) = deal.filter(|deal| deal.state == Some(DealState::Disputed)) else {
+        return Ok(None);
+    };
+    match deal.buyer_wins {
+        Some(ruled) if ruled != buyer_wins => return Ok(None),
+        // Asked twice the same way
+        Some(_) => return Ok(Some(ends_in(buyer_wins))),
+        None => {}
+    }
+
+    sqlx::query!(
+        "UPDATE match SET buyer_wins = $2 WHERE id = $1",
+        id,
+        buyer_wins
+    )
+    .execute(&mut *transaction)
+    .await?;
+    wake(&mut transaction, Job::Release).await?;
+    transaction.commit().await?;
+
+    Ok(Some(ends_in(buyer_wins)))
+}
+
+/// A disputed match that was ruled on, but whose ruling is not paid out yet
+struct RuledMatch {
+    match_id: OfferId,
+    onramp_offer_id: OfferId,
+    offramp_offer_id: OfferId,
+    onramp_public_key: String,
+    offramp_public_key: String,
+    quantity: BigDecimal,
+    buyer_wins: bool,
+    ruling_transaction: Option<Vec<u8>>,
+}
+
+/// Pay out the rulings on disputed matches, returning how many of them are settled now
+pub async fn settle_rulings(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+    epoch: Epoch,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let all_ruled = sqlx::query_as!(
+        RuledMatch,
+        r#"
+        SELECT
+            match.id as match_id,
+            match.onramp_offer_id,
+            match.offramp_offer_id,
+            bid.public_key as onramp_public_key,
+            ask.public_key as offramp_public_key,
+            match.quantity,
+            match.buyer_wins as "buyer_wins!",
+            match.ruling_transaction
+        FROM
+            match
+        JOIN
+            offer bid ON match.onramp_offer_id = bid.id
+        JOIN
+            offer ask ON match.offramp_offer_id = ask.id
+        WHERE
+            match.buyer_wins IS NOT NULL
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut count = 0;
+    for deal in all_ruled {
+        let id = deal.match_id;
+        match ruling_step(conf, pool, client, epoch, deal).await {
+            Err(e) => tracing::error!("While paying out the ruling on match #{id}: {:?}", e),
+            Ok(true) => count += 1,
+            Ok(false) => {}
+        }
+    }
+
+    Ok(count)
+}
+
+/// Take the payment of the ruling on the `deal` one step further, telling whether it is settled now
+///
+/// Like a release, the signed transaction is kept before it is sent,
+/// and the deal is only settled once that transaction is seen confirmed on chain,
+/// so the DD of the match leaves the escrow of the seller exactly once.
+async fn ruling_step(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+    epoch: Epoch,
+    deal: RuledMatch,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let (Some(program_id), Some(token_program), Some(associated_token_program), Some(dd_mint)) = (
+        conf.program_id(),
+        conf.token_program(),
+        conf.associated_token_program(),
+        conf.dd_mint(),
+    ) else {
+        return Err("envs are missing".into());
+    };
+
+    let seller = Pubkey::from_str(&deal.offramp_public_key)?;
+    let buyer = Pubkey::from_str(&deal.onramp_public_key)?;
+    let id: i64 = deal.match_id.into();
+
+    let Some(wire) = deal.ruling_transaction else {
+        let Some(amount) = deal.quantity.to_u64() else {
+            return Err(format!("Match #{} is for {} DD", deal.match_id, deal.quantity).into());
+        };
+        let ruling =
+            sign_resolve_dispute(conf, client, &seller, &buyer, amount, deal.buyer_wins).await?;
+        let mut transaction = pool.begin().await?;
+        epoch.fence(&mut *transaction).await?;
+        sqlx::query!(
+            "UPDATE match SET ruling_transaction = $2 WHERE id = $1",
+            id,
+            bincode::serialize(&ruling)?,
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        // Whether it went out or not, its signature tells on a later round:
+        if let Err(e) = client.send_transaction(&ruling).await {
+            tracing::warn!(
+                "While sending the ruling on match #{}: {:?}",
+                deal.match_id,
+                e
+            );
+        }
+        return Ok(false);
+    };
+
+    let ruling: Transaction = bincode::deserialize(&wire)?;
+    let signature = ruling.signatures[0];
+    match follow(conf, pool, client, epoch, &ruling).await? {
+        Landing::Pending => return Ok(false),
+        Landing::Lost => {
+            tracing::warn!("Ruling {signature} on match #{} is lost", deal.match_id);
+            let mut transaction = pool.begin().await?;
+            epoch.fence(&mut *transaction).await?;
+            sqlx::query!(
+                "UPDATE match SET ruling_transaction = NULL WHERE id = $1",
+                id
+            )
+            .execute(&mut *transaction)
+            .await?;
+            transaction.commit().await?;
+            return Ok(false);
+        }
+        Landing::Confirmed => {}
+    }
+
+    let side = if deal.buyer_wins { "buyer" } else { "seller" };
+    let mut transaction = pool.begin().await?;
+    epoch.fence(&mut *transaction).await?;
+    transition(
+        &mut transaction,
+        deal.onramp_offer_id,
+        Some(deal.match_id),
+        ends_in(deal.buyer_wins),
+        &format!("dispute ruled for the {side} in {signature}"),
+    )
+    .await?;
+    archive_match(&mut transaction, deal.match_id).await?;
+    archive_offer(&mut transaction, deal.onramp_offer_id).await?;
+    // Either way the DD of the match has left the escrow:
+    let offramp_offer_id: i64 = deal.offramp_offer_id.into();
+    sqlx::query!(
+        "UPDATE offer SET amount = amount - $2 WHERE id = $1",
+        offramp_offer_id,
+        deal.quantity,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    after_match_gone(&mut transaction, deal.offramp_offer_id, deal.match_id).await?;
+    transaction.commit().await?;
+
+    let [seller_account, buyer_account] = [seller, buyer].map(|wallet| {
+        Pubkey::find_program_address(
+            &[wallet.as_ref(), token_program.as_ref(), dd_mint.as_ref()],
+            associated_token_program,
+        )
+        .0
+    });
+    // The ruling stands whether or not the bond could be moved:
+    epoch.fence(pool).await?;
+    if deal.buyer_wins {
+        // Unless it still covers their other offers
+        if count_bids(pool, &deal.onramp_public_key).await? == 0 {
+            if let Err(e) = return_bond(conf, client, &buyer, &buyer_account).await {
+                tracing::error!("While returning the bond of {buyer}: {:?}", e);
+            }
+        }
+    } else {
+        // The seller gets the part of it that backed this deal:
+        let per_bid = fetch_user_stats(client, program_id, &buyer)
+            .await
+            .required_bond(conf.bond_amount());
+        let amount = per_bid.min(fetch_bond(client, program_id, &buyer).await);
+        if amount > 0 {
+            if let Err(e) = slash_bond(conf, client, &buyer, &seller, &seller_account, amount).await
+            {
+                tracing::error!("While slashing the bond of {buyer}: {:?}", e);
+            }
+        }
+        if let Err(e) = after_slash(conf, pool, client, epoch, &buyer, per_bid).await {
+            tracing::error!("While settling the rest of the bond of {buyer}: {:?}", e);
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::fake::FakeChain;
+    use crate::cron::tests::{instruction_of, matched, see_both_fiat_legs, state_of};
+    use crate::treasury::discriminator;
+
+    async fn dispute(pool: &PgPool, offer_id: OfferId) -> OfferId {
+        let match_id: i64 = sqlx::query_scalar("SELECT id FROM match WHERE onramp_offer_id = $1")
+            .bind(i64::from(offer_id))
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        let match_id = OfferId::from(match_id);
+        let mut connection = pool.acquire().await.unwrap();
+        transition(
+            &mut connection,
+            offer_id,
+            Some(match_id),
+            DealState::Disputed,
+            "transfer of the wrong amount",
+        )
+        .await
+        .unwrap();
+        match_id
+    }
+
+    #[sqlx::test]
+    async fn pays_out_the_disputed_deal_to_whoever_wins(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (ask, bid) = matched(&conf, &pool, &chain, epoch).await;
+        see_both_fiat_legs(&pool).await;
+        let match_id = dispute(&pool, bid).await;
+
+        assert_eq!(
+            resolve(&pool, match_id, true).await.unwrap(),
+            Some(DealState::Settled)
+        );
+        // Only recorded so far:
+        assert_eq!(state_of(&pool, bid).await, DealState::Disputed);
+        assert!(chain.sent().is_empty());
+        // Ruled on once and for all:
+        assert_eq!(resolve(&pool, match_id, false).await.unwrap(), None);
+        assert_eq!(
+            resolve(&pool, match_id, true).await.unwrap(),
+            Some(DealState::Settled)
+        );
+
+        // Signed and sent, then seen landed:
+        assert_eq!(
+            settle_rulings(&conf, &pool, &chain, epoch).await.unwrap(),
+            0
+        );
+        assert_eq!(
+            settle_rulings(&conf, &pool, &chain, epoch).await.unwrap(),
+            1
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Settled);
+        assert_eq!(state_of(&pool, ask).await, DealState::Settled);
+        let sent = chain.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(instruction_of(&sent[0]), discriminator("resolve_dispute"));
+        assert_eq!(instruction_of(&sent[1]), discriminator("return_bond"));
+
+        assert_eq!(
+            settle_rulings(&conf, &pool, &chain, epoch).await.unwrap(),
+            0
+        );
+        assert_eq!(resolve(&pool, match_id, true).await.unwrap(), None);
+        assert_eq!(chain.sent().len(), 2);
+    }
+
+    #[sqlx::test]
+    async fn refunds_the_seller_when_they_win(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (ask, bid) = matched(&conf, &pool, &chain, epoch).await;
+        let match_id = dispute(&pool, bid).await;
+
+        assert_eq!(
+            resolve(&pool, match_id, false).await.unwrap(),
+            Some(DealState::Cancelled)
+        );
+        settle_rulings(&conf, &pool, &chain, epoch).await.unwrap();
+        assert_eq!(
+            settle_rulings(&conf, &pool, &chain, epoch).await.unwrap(),
+            1
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Cancelled);
+        // The whole offer was in the match, so nothing is left of it:
+        assert_eq!(state_of(&pool, ask).await, DealState::Settled);
+        let sent = chain.sent();
+        assert_eq!(instruction_of(&sent[0]), discriminator("resolve_dispute"));
+        assert_eq!(instruction_of(&sent[1]), discriminator("slash_bond"));
+    }
+
+    #[sqlx::test]
+    async fn pays_out_a_ruling_once_however_long_it_takes_to_land(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (_, bid) = matched(&conf, &pool, &chain, epoch).await;
+        let match_id = dispute(&pool, bid).await;
+        resolve(&pool, match_id, true).await.unwrap();
+
+        chain.delay_transactions(true);
+        assert_eq!(
+            settle_rulings(&conf, &pool, &chain, epoch).await.unwrap(),
+            0
+        );
+        assert_eq!(
+            settle_rulings(&conf, &pool, &chain, epoch).await.unwrap(),
+            0
+        );
+        // Not seen at first, then landed by the time the blockhash is found expired:
+        chain.expire_blockhashes();
+        assert_eq!(
+            settle_rulings(&conf, &pool, &chain, epoch).await.unwrap(),
+            0
+        );
+        chain.delay_transactions(false);
+        assert_eq!(
+            settle_rulings(&conf, &pool, &chain, epoch).await.unwrap(),
+            1
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Settled);
+
+        let mut rulings = chain
+            .sent()
+            .iter()
+            .filter(|sent| instruction_of(sent) == discriminator("resolve_dispute"))
+            .map(|sent| sent.signatures[0])
+            .collect::<Vec<_>>();
+        rulings.dedup();
+        assert_eq!(rulings.len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn signs_a_failed_ruling_anew(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let chain = FakeChain::default();
+        let epoch = Epoch::begin(&pool).await.unwrap();
+        let (_, bid) = matched(&conf, &pool, &chain, epoch).await;
+        let match_id = dispute(&pool, bid).await;
+        resolve(&pool, match_id, false).await.unwrap();
+
+        chain.fail_transactions(true);
+        settle_rulings(&conf, &pool, &chain, epoch).await.unwrap();
+        assert_eq!(
+            settle_rulings(&conf, &pool, &chain, epoch).await.unwrap(),
+            0
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Disputed);
+
+        chain.fail_transactions(false);
+        settle_rulings(&conf, &pool, &chain, epoch).await.unwrap();
+        assert_eq!(
+            settle_rulings(&conf, &pool, &chain, epoch).await.unwrap(),
+            1
+        );
+        assert_eq!(state_of(&pool, bid).await, DealState::Cancelled);
+    }
+}
@@ -0,0 +1,347 @@
+//! The transactions of the program, read off the chain into Postgres
+//!
+//! The daemon only knows of the offers that went through it, while deposits,
+//! redeems and releases can be sent by anyone. So we walk the signatures of the program
+//! back to the last one we are done with, then record the transactions oldest first,
+//! each along with the cursor, so that we carry on from the right place whenever we stop.
+use std::str::FromStr;
+
+use bigdecimal::BigDecimal;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use sqlx::{PgConnection, PgPool};
+
+use crate::chain::{ChainClient, ChainTransaction, SIGNATURES_PER_REQUEST};
+use crate::conf::Conf;
+use crate::treasury::discriminator;
+
+/// The instructions of the program, and whether their first argument is an amount
+const INSTRUCTIONS: [(&str, bool); 21] = [
+    ("initialize", false),
+    ("configure", false),
+    ("configure_confidential", false),
+    ("migrate_state", false),
+    ("configure_shielded", false),
+    ("deposit", true),
+    ("redeem", true),
+    ("deposit_confidential", true),
+    ("withdraw_confidential", true),
+    ("shield", true),
+    ("unshield", true),
+    ("private_transfer", false),
+    ("offer_dd", true),
+    ("offer_fiat", true),
+    ("post_bond", true),
+    ("return_bond", false),
+    ("slash_bond", true),
+    ("release_funds", false),
+    ("release_portion", true),
+    ("cancel", false),
+    ("resolve_dispute", true),
+];
+
+/// The name of the instruction `data` calls, and the amount it moves if it takes one
+fn decode_instruction(data: &[u8]) -> (&'static str, Option<u64>) {
+    let Some(prefix) = data.get(..8) else {
+        return ("unknown", None);
+    };
+    INSTRUCTIONS
+        .iter()
+        .find(|(name, _)| discriminator(name) == prefix)
+        .map(|&(name, takes_amount)| {
+            let amount = data
+                .get(8..16)
+                .and_then(|amount| amount.try_into().ok())
+                .map(u64::from_le_bytes);
+            (name, amount.filter(|_| takes_amount))
+        })
+        .unwrap_or(("unknown", None))
+}
+
+/// How many pages of signatures one run walks through at most,
+/// so that catching up on a long history takes many short runs rather than one long one
+const PAGES_PER_RUN: usize = 10;
+
+/// Record the transactions of the program that landed since last time, and say how many
+pub async fn index_chain(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    index_pages(conf, pool, client, PAGES_PER_RUN).await
+}
+
+/// Record the transactions of the program that landed since last time,
+/// walking through at most `pages` pages of them, and say how many
+///
+/// The walk goes from the newest transaction down to the cursor, and is saved as it goes,
+/// so that the next run carries on from where this one stopped.
+/// The cursor only moves up to the newest transaction once the walk has reached it.
+async fn index_pages(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+    pages: usize,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let Some(program_id) = conf.program_id() else {
+        return Err("PROGRAM_ID is not set".into());
+    };
+    let address = program_id.to_string();
+    let cursor = sqlx::query!(
+        "SELECT signature, walk_top, walk_before FROM chain_cursor WHERE address = $1",
+        address
+    )
+    .fetch_optional(pool)
+    .await?;
+    let parse = |signature: Option<String>| {
+        signature
+            .map(|signature| Signature::from_str(&signature))
+            .transpose()
+    };
+    let (until, mut top, mut before) = match cursor {
+        Some(cursor) => (
+            parse(cursor.signature)?,
+            parse(cursor.walk_top)?,
+            parse(cursor.walk_before)?,
+        ),
+        None => (None, None, None),
+    };
+
+    let mut indexed = 0;
+    for _ in 0..pages {
+        let page = client
+            .signatures_for_address(program_id, before, until)
+            .await?;
+        let done = page.len() < SIGNATURES_PER_REQUEST;
+        // Where the cursor goes once the walk is over:
+        if top.is_none() {
+            top = page.first().copied();
+        }
+        let Some(top) = top else {
+            // Nothing new
+            break;
+        };
+
+        for signature in page {
+            let Some(transaction) = client.transaction(&signature).await? else {
+                // The walk carries on from this one next time, or it would be skipped
+                return Ok(indexed);
+            };
+            let mut db_transaction = pool.begin().await?;
+            record(&mut db_transaction, program_id, &transaction).await?;
+            sqlx::query!(
+                "INSERT INTO chain_cursor (address, walk_top, walk_before) VALUES ($1, $2, $3) ON CONFLICT (address) DO UPDATE SET walk_top = $2, walk_before = $3",
+                address,
+                top.to_string(),
+                signature.to_string()
+            )
+            .execute(&mut *db_transaction)
+            .await?;
+            db_transaction.commit().await?;
+            before = Some(signature);
+            indexed += 1;
+        }
+
+        if done {
+            // Everything up to the top of the walk is recorded now
+            if before.is_some() {
+                sqlx::query!(
+                    "UPDATE chain_cursor SET signature = $2, walk_top = NULL, walk_before = NULL WHERE address = $1",
+                    address,
+                    top.to_string()
+                )
+                .execute(pool)
+                .await?;
+            }
+            break;
+        }
+    }
+    Ok(indexed)
+}
+
+/// Record the `transaction`, with the instructions of `program_id` in it, unless it already is
+async fn record(
+    connection: &mut PgConnection,
+    program_id: &Pubkey,
+    transaction: &ChainTransaction,
+) -> Result<(), sqlx::Error> {
+    let signature = transaction.signature.to_string();
+    let inserted = sqlx::query!(
+        "INSERT INTO chain_tx (signature, slot, block_time, failed) VALUES ($1, $2, to_timestamp($3::float8) AT TIME ZONE 'UTC', $4) ON CONFLICT (signature) DO NOTHING",
+        signature,
+        transaction.slot as i64,
+        transaction.block_time.map(|time| time as f64),
+        transaction.failed
+    )
+    .execute(&mut *connection)
+    .await?;
+    if inserted.rows_affected() == 0 {
+        return Ok(());
+    }
+
+    for (position, instruction) in transaction.instructions.iter().enumerate() {
+        if instruction.program_id != *program_id {
+            continue;
+        }
+        let (name, amount) = decode_instruction(&instruction.data);
+        let accounts = instruction
+            .accounts
+            .iter()
+            .map(|account| account.to_string())
+            .collect::<Vec<_>>();
+        sqlx::query!(
+            "INSERT INTO chain_tx_instruction (signature, position, name, amount, accounts, data) VALUES ($1, $2, $3, $4, $5, $6)",
+            signature,
+            position as i32,
+            name,
+            amount.map(BigDecimal::from),
+            &accounts,
+            &instruction.data
+        )
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    for change in &transaction.balance_changes {
+        sqlx::query!(
+            "INSERT INTO chain_tx_balance (signature, account, owner, mint, pre, post) VALUES ($1, $2, $3, $4, $5, $6)",
+            signature,
+            change.account.to_string(),
+            change.owner.map(|owner| owner.to_string()),
+            change.mint.to_string(),
+            BigDecimal::from(change.pre),
+            BigDecimal::from(change.post)
+        )
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::fake::FakeChain;
+    use crate::chain::{BalanceChange, ChainInstruction};
+
+    fn call(name: &str, amount: u64) -> Vec<u8> {
+        let mut data = discriminator(name).to_vec();
+        data.extend(amount.to_le_bytes());
+        data
+    }
+
+    fn deposit(conf: &Conf, user: &Pubkey, amount: u64, slot: u64) -> ChainTransaction {
+        let account = Pubkey::new_unique();
+        ChainTransaction {
+            signature: Signature::new_unique(),
+            slot,
+            block_time: Some(1_700_000_000),
+            failed: false,
+            instructions: vec![
+                ChainInstruction {
+                    program_id: Pubkey::new_unique(),
+                    accounts: vec![],
+                    data: vec![],
+                },
+                ChainInstruction {
+                    program_id: *conf.program_id().unwrap(),
+                    accounts: vec![*user, account],
+                    data: call("deposit", amount),
+                },
+            ],
+            balance_changes: vec![BalanceChange {
+                account,
+                owner: Some(*user),
+                mint: *conf.dd_mint().unwrap(),
+                pre: 0,
+                post: amount,
+            }],
+        }
+    }
+
+    #[test]
+    fn names_the_instructions_of_the_program() {
+        assert_eq!(decode_instruction(&call("redeem", 5)), ("redeem", Some(5)));
+        assert_eq!(decode_instruction(&call("cancel", 5)), ("cancel", None));
+        assert_eq!(decode_instruction(&[1, 2, 3]), ("unknown", None));
+    }
+
+    #[sqlx::test]
+    async fn carries_on_from_the_last_transaction_it_recorded(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let client = FakeChain::default();
+        let user = Pubkey::new_unique();
+        client.add_transaction(deposit(&conf, &user, 5, 1));
+        client.add_transaction(deposit(&conf, &user, 7, 2));
+
+        assert_eq!(index_chain(&conf, &pool, &client).await.unwrap(), 2);
+        assert_eq!(index_chain(&conf, &pool, &client).await.unwrap(), 0);
+        let last = deposit(&conf, &user, 9, 3);
+        client.add_transaction(last.clone());
+        assert_eq!(index_chain(&conf, &pool, &client).await.unwrap(), 1);
+
+        let cursor: Option<String> = sqlx::query_scalar("SELECT signature FROM chain_cursor")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(cursor, Some(last.signature.to_string()));
+        let deposits: Vec<(String, BigDecimal)> = sqlx::query_as(
+            "SELECT name, amount FROM chain_tx_instruction JOIN chain_tx USING (signature) WHERE $1 = ANY(accounts) ORDER BY slot",
+        )
+        .bind(user.to_string())
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            deposits,
+            [5, 7, 9].map(|amount| ("deposit".to_string(), BigDecimal::from(amount)))
+        );
+        let received: BigDecimal =
+            sqlx::query_scalar("SELECT SUM(post - pre) FROM chain_tx_balance WHERE owner = $1")
+                .bind(user.to_string())
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(received, BigDecimal::from(21));
+    }
+
+    #[sqlx::test]
+    async fn catches_up_on_a_long_history_over_several_runs(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let client = FakeChain::default();
+        let user = Pubkey::new_unique();
+        let history = SIGNATURES_PER_REQUEST + 10;
+        for slot in 0..history {
+            client.add_transaction(deposit(&conf, &user, 1, slot as u64));
+        }
+
+        // A page at a time, from the newest down:
+        assert_eq!(
+            index_pages(&conf, &pool, &client, 1).await.unwrap(),
+            SIGNATURES_PER_REQUEST
+        );
+        let cursor: Option<String> = sqlx::query_scalar("SELECT signature FROM chain_cursor")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(cursor, None);
+        // What lands in the meantime waits for the walk to be over:
+        let late = deposit(&conf, &user, 1, history as u64);
+        client.add_transaction(late.clone());
+        assert_eq!(index_pages(&conf, &pool, &client, 1).await.unwrap(), 10);
+        assert_eq!(index_pages(&conf, &pool, &client, 1).await.unwrap(), 1);
+        assert_eq!(index_pages(&conf, &pool, &client, 1).await.unwrap(), 0);
+
+        let cursor: Option<String> = sqlx::query_scalar("SELECT signature FROM chain_cursor")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(cursor, Some(late.signature.to_string()));
+        let recorded: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM chain_tx")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(recorded, history as i64 + 1);
+    }
+}
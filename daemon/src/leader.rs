@@ -0,0 +1,153 @@
+//! Which of the replicas runs the background jobs
+//!
+//! The leader is whoever holds a session-level Postgres advisory lock.
+//! It is held on a connection of its own, outside the pool,
+//! so that it is let go of the moment the leader's session ends,
+//! whether the leader has exited, crashed, or lost the database;
+//! the other replicas keep trying to take it over until one of them does.
+//!
+//! A leader may not notice right away that it is not one anymore, say when it stalls,
+//! so every leader starts a new [`Epoch`], and the jobs make sure theirs is still the current one
+//! in the same transaction as every change they make, and before they send anything to the chain.
+use sqlx::postgres::PgConnectOptions;
+use sqlx::{Connection, PgConnection, PgExecutor};
+
+/// The key of the advisory lock, which is "araza" in ASCII
+const LEADER_LOCK_KEY: i64 = 0x61_72_61_7a_61;
+
+/// The term of a leader
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Epoch(i64);
+
+impl Epoch {
+    /// Start a new term, which ends every one before it
+    ///
+    /// It waits for the transactions that are fenced by the current term to be over.
+    pub async fn begin(executor: impl PgExecutor<'_>) -> Result<Self, sqlx::Error> {
+        sqlx::query_scalar!("UPDATE leader_epoch SET epoch = epoch + 1 RETURNING epoch")
+            .fetch_one(executor)
+            .await
+            .map(Epoch)
+    }
+
+    /// Fail unless this is still the current term, which it then stays until the transaction is over
+    pub async fn fence(
+        self,
+        executor: impl PgExecutor<'_>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let current = sqlx::query_scalar!("SELECT epoch FROM leader_epoch FOR SHARE")
+            .fetch_one(executor)
+            .await?;
+        if current != self.0 {
+            return Err(format!(
+                "Term {} of the leadership is over; {current} has begun",
+                self.0
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+pub struct Leadership {
+    options: PgConnectOptions,
+    connection: Option<PgConnection>,
+    epoch: Option<Epoch>,
+}
+
+impl Leadership {
+    pub fn new(options: PgConnectOptions) -> Self {
+        Self {
+            options,
+            connection: None,
+            epoch: None,
+        }
+    }
+
+    /// The term of this replica if it is the leader, becoming it if nobody else is
+    pub async fn hold(&mut self) -> Option<Epoch> {
+        match self.try_hold().await {
+            Ok(epoch) => epoch,
+            Err(e) => {
+                if self.epoch.is_some() {
+                    tracing::warn!("Lost the leadership: {:?}", e);
+                } else {
+                    tracing::error!("While trying to become the leader: {:?}", e);
+                }
+                // The lock goes with the session, so it is gone either way:
+                self.connection = None;
+                self.epoch = None;
+                None
+            }
+        }
+    }
+
+    async fn try_hold(&mut self) -> Result<Option<Epoch>, sqlx::Error> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => self
+                .connection
+                .insert(PgConnection::connect_with(&self.options).await?),
+        };
+
+        if let Some(epoch) = self.epoch {
+            // The lock is ours for as long as the session lives
+            connection.ping().await?;
+            return Ok(Some(epoch));
+        }
+
+        let won = sqlx::query_scalar!(
+            r#"SELECT pg_try_advisory_lock($1) as "won!""#,
+            LEADER_LOCK_KEY
+        )
+        .fetch_one(&mut *connection)
+        .await?;
+        if !won {
+            return Ok(None);
+        }
+        let epoch = Epoch::begin(&mut *connection).await?;
+        tracing::info!(
+            "Became the leader for term {}; running the background jobs here",
+            epoch.0
+        );
+        self.epoch = Some(epoch);
+        Ok(Some(epoch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn hands_over_when_the_leader_is_gone(pool: sqlx::PgPool) {
+        let options = (*pool.connect_options()).clone();
+        let mut first = Leadership::new(options.clone());
+        let mut second = Leadership::new(options);
+
+        let term = first.hold().await.unwrap();
+        assert_eq!(second.hold().await, None);
+        assert_eq!(first.hold().await, Some(term));
+
+        drop(first);
+        // The lock goes once the server notices the session is over:
+        let mut took_over = None;
+        for _ in 0..50 {
+            took_over = second.hold().await;
+            if took_over.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(took_over > Some(term));
+    }
+
+    #[sqlx::test]
+    async fn fences_off_the_terms_that_are_over(pool: sqlx::PgPool) {
+        let before = Epoch::begin(&pool).await.unwrap();
+        before.fence(&pool).await.unwrap();
+        let after = Epoch::begin(&pool).await.unwrap();
+        assert!(before.fence(&pool).await.is_err());
+        after.fence(&pool).await.unwrap();
+    }
+}
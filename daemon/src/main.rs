@@ -1,28 +1,68 @@
-use actix_web::{middleware, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use bigdecimal::BigDecimal;
 use dotenvy::dotenv;
-use sqlx::postgres::PgPoolOptions;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::pubkey::Pubkey;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
 use std::env;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use subtle::ConstantTimeEq;
 
 use sqlx::migrate::Migrator;
 
 static MIGRATOR: Migrator = sqlx::migrate!();
 
+mod account;
+use account::PageQuery;
+
+mod bond;
+
+mod book;
+
+mod chain;
+
 mod conf;
+use conf::Conf;
 
 mod cron;
 
+mod deal;
+use deal::{transition, DealState};
+
+mod dispute;
+
+mod indexer;
+
+mod leader;
+use leader::Leadership;
+
+mod schedule;
+use schedule::{wake, Job, Schedule};
+
 mod schema;
-use schema::{Offer, OfferDirection, OfferId, OfferRequest};
+use schema::{Offer, OfferDirection, OfferId, OfferRequest, PaymentRail};
 
 mod readout;
 use readout::handle_readout;
 
+mod reconcile;
+
+mod reputation;
+use reputation::fetch_user_stats;
+
+mod reserves;
+
+mod treasury;
+
+mod watch;
+
 /// List all the active on/off-ramps
 async fn get_all_offers(pool: web::Data<sqlx::PgPool>) -> impl Responder {
     let result = sqlx::query_as!(
         Offer,
-        "SELECT id, bank_account, public_key, amount, direction as \"direction: OfferDirection\" FROM offer"
+        "SELECT id, bank_account, public_key, amount, direction as \"direction: OfferDirection\", rail as \"rail: PaymentRail\", currency, price FROM offer"
     )
     .fetch_all(pool.get_ref())
     .await;
@@ -37,21 +77,42 @@ async fn get_all_offers(pool: web::Data<sqlx::PgPool>) -> impl Responder {
 }
 
 /// Get the status of an offer
+///
+/// An offer that is not on the book, such as one still waiting for its deposit
+/// or one that has expired or settled, is reported by the state of its deal alone.
 async fn get_offer(pool: web::Data<sqlx::PgPool>, offer_id: web::Path<OfferId>) -> impl Responder {
-    let offer_id: i64 = offer_id.into_inner().into();
+    let id = offer_id.into_inner();
+    let offer_id: i64 = id.into();
     let result = sqlx::query_as!(
         Offer,
-        "SELECT id, bank_account, public_key, amount, direction as \"direction: OfferDirection\" FROM offer WHERE id = $1",
+        "SELECT id, bank_account, public_key, amount, direction as \"direction: OfferDirection\", rail as \"rail: PaymentRail\", currency, price FROM offer WHERE id = $1",
         offer_id
     )
-    .fetch_one(pool.get_ref())
+    .fetch_optional(pool.get_ref())
     .await;
 
     match result {
-        Ok(offer) => HttpResponse::Ok().json(offer),
+        Ok(Some(offer)) => return HttpResponse::Ok().json(offer),
+        Ok(None) => {}
         Err(e) => {
             eprintln!("error getting offer: {:?}", e);
-            HttpResponse::NotFound().body("Offer not found")
+            return HttpResponse::InternalServerError().body("Failed to get offer");
+        }
+    }
+
+    let state = sqlx::query_scalar!(
+        r#"SELECT state as "state: DealState" FROM deal_event WHERE offer_id = $1 ORDER BY id DESC LIMIT 1"#,
+        offer_id
+    )
+    .fetch_optional(pool.get_ref())
+    .await;
+
+    match state {
+        Ok(Some(state)) => HttpResponse::Ok().json(serde_json::json!({"id": id, "state": state})),
+        Ok(None) => HttpResponse::NotFound().body("Offer not found"),
+        Err(e) => {
+            eprintln!("error getting offer: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to get offer")
         }
     }
 }
@@ -59,24 +120,39 @@ async fn get_offer(pool: web::Data<sqlx::PgPool>, offer_id: web::Path<OfferId>)
 /// Create an offer to sell DD
 async fn offer_dd(pool: web::Data<sqlx::PgPool>, req: web::Json<OfferRequest>) -> impl Responder {
     let amount = BigDecimal::from(req.amount);
-    match req.ensure_authentic() {
+    match req
+        .ensure_well_formed()
+        .and_then(|_| req.ensure_authentic())
+    {
         Ok(_) => (),
         Err(e) => return HttpResponse::BadRequest().body(e),
     }
+    let bank_account = req.rail.normalize_account(&req.bank_account);
     // At first we create a preoffer that will be converted to an offer
     // after we see the DD is deposited with the exact advertised amount,
     // and only that offer will become available for matching
-    let result = sqlx::query!(
-        "INSERT INTO preoffer (amount, bank_account, public_key) VALUES ($1, $2, $3) RETURNING id",
-        amount,
-        req.bank_account,
-        req.public_key,
-    )
-    .fetch_one(pool.get_ref())
+    let result: Result<OfferId, Box<dyn std::error::Error>> = async {
+        let mut transaction = pool.begin().await?;
+        let record = sqlx::query!(
+            "INSERT INTO preoffer (amount, bank_account, public_key, rail, currency, price) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            amount,
+            bank_account,
+            req.public_key,
+            req.rail as PaymentRail,
+            req.currency,
+            req.price.value,
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        let id = OfferId::from(record.id);
+        transition(&mut transaction, id, None, DealState::PendingDeposit, "offered DD").await?;
+        transaction.commit().await?;
+        Ok(id)
+    }
     .await;
 
     match result {
-        Ok(record) => HttpResponse::Ok().json(serde_json::json!({"id": OfferId::from(record.id)})),
+        Ok(id) => HttpResponse::Ok().json(serde_json::json!({"id": id})),
         Err(error) => {
             tracing::error!(?error);
             HttpResponse::InternalServerError().body("Try again later")
@@ -85,24 +161,212 @@ async fn offer_dd(pool: web::Data<sqlx::PgPool>, req: web::Json<OfferRequest>) -
 }
 
 /// Create an offer to buy DD
-async fn offer_fiat(pool: web::Data<sqlx::PgPool>, req: web::Json<OfferRequest>) -> impl Responder {
+///
+/// The offer is only taken once the bond of the buyer covers it along with their other offers;
+/// until then we respond with how much more they have to put up, and they come back after.
+async fn offer_fiat(
+    conf: web::Data<Conf>,
+    client: web::Data<RpcClient>,
+    pool: web::Data<sqlx::PgPool>,
+    req: web::Json<OfferRequest>,
+) -> impl Responder {
     let amount = BigDecimal::from(req.amount);
-    match req.ensure_authentic() {
+    match req
+        .ensure_well_formed()
+        .and_then(|_| req.ensure_authentic())
+    {
         Ok(_) => (),
         Err(e) => return HttpResponse::BadRequest().body(e),
     }
-    let result = sqlx::query!(
-        "INSERT INTO offer (amount, bank_account, public_key, direction) VALUES ($1, $2, $3, $4) RETURNING id",
-        amount,
-        req.bank_account,
-        req.public_key,
-        OfferDirection::FiatToDD as OfferDirection
-    )
-    .fetch_one(pool.get_ref())
+    let (Ok(wallet), Some(program_id)) = (Pubkey::from_str(&req.public_key), conf.program_id())
+    else {
+        return HttpResponse::InternalServerError().body("Try again later");
+    };
+    let bank_account = req.rail.normalize_account(&req.bank_account);
+    let result: Result<Result<OfferId, u64>, Box<dyn std::error::Error>> = async {
+        // Fresh wallets have to put up more:
+        let stats = fetch_user_stats(client.get_ref(), program_id, &wallet).await;
+        let bond = bond::fetch_bond(client.get_ref(), program_id, &wallet).await;
+
+        let mut transaction = pool.begin().await?;
+        let bids = bond::count_bids(&mut *transaction, &req.public_key).await?;
+        let required =
+            bond::covering_bond(stats.required_bond(conf.bond_amount()), bids + 1);
+        if bond < required {
+            return Ok(Err(required - bond));
+        }
+        let record = sqlx::query!(
+            "INSERT INTO offer (amount, bank_account, public_key, direction, rail, currency, price) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+            amount,
+            bank_account,
+            req.public_key,
+            OfferDirection::FiatToDD as OfferDirection,
+            req.rail as PaymentRail,
+            req.currency,
+            req.price.value,
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        let id = OfferId::from(record.id);
+        transition(&mut transaction, id, None, DealState::Open, "offered fiat").await?;
+        wake(&mut transaction, Job::Matching).await?;
+        transaction.commit().await?;
+        Ok(Ok(id))
+    }
     .await;
 
     match result {
-        Ok(record) => HttpResponse::Ok().json(serde_json::json!({"id": OfferId::from(record.id)})),
+        Ok(Ok(id)) => HttpResponse::Ok().json(serde_json::json!({"id": id})),
+        Ok(Err(missing)) => HttpResponse::PaymentRequired().json(serde_json::json!({
+            "bond": missing.to_string(),
+        })),
+        Err(error) => {
+            tracing::error!(?error);
+            HttpResponse::InternalServerError().body("Try again later")
+        }
+    }
+}
+
+/// How each of the background jobs has been doing
+async fn get_jobs(schedule: web::Data<Mutex<Schedule>>) -> impl Responder {
+    let status = schedule.lock().unwrap().status();
+    HttpResponse::Ok().json(status)
+}
+
+/// Why the `req` may not go through, unless it comes with the `ADMIN_TOKEN` as a bearer token
+fn admin_denied(conf: &Conf, req: &HttpRequest) -> Option<HttpResponse> {
+    let Some(token) = conf.admin_token() else {
+        return Some(HttpResponse::Forbidden().body("ADMIN_TOKEN is not set"));
+    };
+    let bearer = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // In constant time, so that how long it takes to say no tells nothing of the token;
+    // hashed first, so that not even its length shows:
+    let digest = |value: &str| solana_sdk::hash::hash(value.as_bytes()).to_bytes();
+    let matches = bearer.is_some_and(|bearer| bool::from(digest(bearer).ct_eq(&digest(token))));
+    if !matches {
+        return Some(HttpResponse::Unauthorized().body("Wrong admin token"));
+    }
+    None
+}
+
+/// The latest comparison of the escrows on chain with the book
+///
+/// Only for whoever runs the deployment.
+async fn get_reconciliation(
+    conf: web::Data<Conf>,
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(denied) = admin_denied(&conf, &req) {
+        return denied;
+    }
+
+    match reconcile::latest_report(pool.get_ref()).await {
+        Ok(Some(report)) => HttpResponse::Ok().json(report),
+        Ok(None) => HttpResponse::NotFound().body("No reconciliation has run yet"),
+        Err(error) => {
+            tracing::error!(?error);
+            HttpResponse::InternalServerError().body("Try again later")
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Ruling {
+    buyer_wins: bool,
+}
+
+/// Rule on a disputed match for the buyer or for the seller,
+/// whose DD the release job then pays out to them
+///
+/// Only for whoever runs the deployment.
+async fn resolve_dispute(
+    conf: web::Data<Conf>,
+    pool: web::Data<sqlx::PgPool>,
+    match_id: web::Path<OfferId>,
+    ruling: web::Json<Ruling>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(denied) = admin_denied(&conf, &req) {
+        return denied;
+    }
+
+    let match_id = match_id.into_inner();
+    match dispute::resolve(&pool, match_id, ruling.buyer_wins).await {
+        Ok(Some(state)) => HttpResponse::Accepted().json(serde_json::json!({"state": state})),
+        Ok(None) => HttpResponse::Conflict().body(format!(
+            "Match #{match_id} is not in dispute, or was ruled on the other way"
+        )),
+        Err(error) => {
+            tracing::error!(?error);
+            HttpResponse::InternalServerError().body("Try again later")
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ReservesQuery {
+    slot: Option<u64>,
+}
+
+/// The latest signed proof of the reserves the leader has taken,
+/// or the latest one at or before `slot` if one is asked for
+///
+/// Its signature is over `araza-reserves-v1`, a zero byte, then its message.
+async fn get_reserves(
+    pool: web::Data<sqlx::PgPool>,
+    query: web::Query<ReservesQuery>,
+) -> impl Responder {
+    let result = match query.slot {
+        Some(slot) => reserves::attestation_at(pool.get_ref(), slot).await,
+        None => reserves::latest_attestation(pool.get_ref()).await,
+    };
+    match result {
+        Ok(Some(attestation)) => HttpResponse::Ok().json(attestation),
+        Ok(None) => HttpResponse::NotFound().body("No attestation that early"),
+        Err(error) => {
+            tracing::error!(?error);
+            HttpResponse::InternalServerError().body("Try again later")
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct HistoryQuery {
+    limit: Option<i64>,
+}
+
+/// The latest signed proofs of the reserves, oldest first, to chart them
+async fn get_reserves_history(
+    pool: web::Data<sqlx::PgPool>,
+    query: web::Query<HistoryQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(reserves::MAX_HISTORY);
+    match reserves::attestation_history(pool.get_ref(), limit).await {
+        Ok(history) => HttpResponse::Ok().json(history),
+        Err(error) => {
+            tracing::error!(?error);
+            HttpResponse::InternalServerError().body("Try again later")
+        }
+    }
+}
+
+/// The offers of a wallet, open and past, newest first
+async fn get_account_offers(
+    pool: web::Data<sqlx::PgPool>,
+    public_key: web::Path<String>,
+    query: web::Query<PageQuery>,
+) -> impl Responder {
+    if let Err(e) = Pubkey::from_str(&public_key) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    match account::fetch_offers(pool.get_ref(), &public_key, &query).await {
+        Ok(page) => HttpResponse::Ok().json(page),
         Err(error) => {
             tracing::error!(?error);
             HttpResponse::InternalServerError().body("Try again later")
@@ -110,13 +374,44 @@ async fn offer_fiat(pool: web::Data<sqlx::PgPool>, req: web::Json<OfferRequest>)
     }
 }
 
-/// Accept a new fiat bank statement, and, if it contains any settled c2c transfers
+/// What a wallet has done on chain and with its deals, newest first
+async fn get_account_history(
+    pool: web::Data<sqlx::PgPool>,
+    public_key: web::Path<String>,
+    query: web::Query<PageQuery>,
+) -> impl Responder {
+    if let Err(e) = Pubkey::from_str(&public_key) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    match account::fetch_history(pool.get_ref(), &public_key, &query).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(error) => {
+            tracing::error!(?error);
+            HttpResponse::InternalServerError().body("Try again later")
+        }
+    }
+}
+
+/// Accept a new fiat bank statement of transfers over `rail`, and, if it contains any
 /// that are related to our offers, update the offers accordingly
 ///
 /// This is intended to be a webhook from the bank, gated by a secret key,
 /// but for now we just accept any POST request
-async fn readout(pool: web::Data<sqlx::PgPool>, body: String) -> impl Responder {
-    let result = handle_readout(pool.get_ref(), &body).await;
+async fn readout(
+    pool: web::Data<sqlx::PgPool>,
+    rail: web::Path<PaymentRail>,
+    body: String,
+) -> impl Responder {
+    readout_on_rail(&pool, rail.into_inner(), &body).await
+}
+
+/// Same as [`readout`], for the c2c transfers the statements were first about
+async fn readout_c2c(pool: web::Data<sqlx::PgPool>, body: String) -> impl Responder {
+    readout_on_rail(&pool, PaymentRail::C2C, &body).await
+}
+
+async fn readout_on_rail(pool: &sqlx::PgPool, rail: PaymentRail, body: &str) -> HttpResponse {
+    let result = handle_readout(pool, rail, body).await;
     match result {
         Ok(_) => HttpResponse::Ok().body("Accepted"),
         Err(e) => {
@@ -126,6 +421,12 @@ async fn readout(pool: web::Data<sqlx::PgPool>, body: String) -> impl Responder
     }
 }
 
+/// The pool the handlers and the jobs share;
+/// the leadership and the wake-ups each hold a connection of their own besides
+fn pool_options() -> PgPoolOptions {
+    PgPoolOptions::new().max_connections(1)
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     use tracing::level_filters::LevelFilter;
@@ -143,22 +444,57 @@ async fn main() -> std::io::Result<()> {
         )
         .init();
 
-    let conf = conf::Conf::from_env();
+    let conf = Arc::new(Conf::from_env());
     for missing in conf.missing_keys() {
         tracing::error!("Missing env `{missing}`; nothing will probably work");
     }
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPoolOptions::new()
-        .max_connections(1)
-        .connect(&database_url)
+    let options =
+        PgConnectOptions::from_str(&database_url).expect("DATABASE_URL must be a Postgres URL");
+    let pool = pool_options()
+        .connect_with(options.clone())
         .await
         .expect("Could not connect to the database");
     MIGRATOR.run(&pool).await.unwrap();
 
+    tracing::info!(
+        "Talking to {:?} at {} and {}",
+        conf.cluster(),
+        conf.rpc_url(),
+        conf.ws_url()
+    );
+    let client = Arc::new(RpcClient::new_with_commitment(
+        conf.rpc_url().to_string(),
+        conf.commitment(),
+    ));
+
+    let also_conf = Clone::clone(&conf);
+    let also_pool = Clone::clone(&pool);
+    let also_client = Clone::clone(&client);
+    let schedule = Arc::new(Mutex::new(Schedule::new(
+        Job::ALL.map(|job| (job, conf.job_interval(job))),
+        Instant::now(),
+    )));
+    let also_schedule = Clone::clone(&schedule);
+    // Every replica serves HTTP, but only the leader runs the jobs:
+    let leadership = Leadership::new(options.clone());
+    actix_rt::spawn(async move {
+        cron::run(
+            also_conf,
+            also_pool,
+            also_client,
+            also_schedule,
+            leadership,
+            options,
+        )
+        .await;
+    });
+
+    let also_conf = Clone::clone(&conf);
     let also_pool = Clone::clone(&pool);
     actix_rt::spawn(async move {
-        cron::run(conf, also_pool).await;
+        watch::run(also_conf, also_pool).await;
     });
 
     let port = env::var("PORT")
@@ -169,12 +505,29 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
+            .app_data(web::Data::from(conf.clone()))
+            .app_data(web::Data::from(client.clone()))
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::from(schedule.clone()))
             .route("/offer", web::get().to(get_all_offers))
             .route("/offer/{offerId}", web::get().to(get_offer))
             .route("/offer-dd", web::post().to(offer_dd))
             .route("/offer-fiat", web::post().to(offer_fiat))
-            .route("/readout", web::post().to(readout))
+            .route(
+                "/account/{publicKey}/offers",
+                web::get().to(get_account_offers),
+            )
+            .route(
+                "/account/{publicKey}/history",
+                web::get().to(get_account_history),
+            )
+            .route("/jobs", web::get().to(get_jobs))
+            .route("/admin/reconciliation", web::get().to(get_reconciliation))
+            .route("/admin/dispute/{matchId}", web::post().to(resolve_dispute))
+            .route("/reserves", web::get().to(get_reserves))
+            .route("/reserves/history", web::get().to(get_reserves_history))
+            .route("/readout", web::post().to(readout_c2c))
+            .route("/readout/{rail}", web::post().to(readout))
             .default_service(actix_files::Files::new("/", "./dist").index_file("index.html"))
     })
     .bind(("0.0.0.0", port))
@@ -1,19 +1,31 @@
 //! Accept a new fiat bank statement and maybe update the offers affected
 //!
-use crate::schema::OfferId;
+use bigdecimal::{BigDecimal, RoundingMode};
+
+use crate::deal::{transition, DealState};
+use crate::schedule::{wake, Job};
+use crate::schema::{OfferId, PaymentRail};
+
+/// How many atoms make one DD
+const DD_ATOMS: u64 = 1_000000;
 
 #[allow(unused)]
 struct BankStatementRecord {
     date: String,
     description: String,
-    amount: f64,
+    amount: BigDecimal,
     account: String,
     transaction_id: String,
 }
 
-/// `# Date,Description,Amount,Account,Transaction ID`
-fn parse_csv(body: &str) -> Vec<BankStatementRecord> {
-    fn parse_line(body: &str) -> Option<BankStatementRecord> {
+/// `# Date,Description,Amount,Account,Transaction ID`,
+/// where the description ends with the account on the other side of the transfer,
+/// both spelled the way `rail` does
+///
+/// Lines that are not records are skipped, but a record we cannot read is an error,
+/// since it may be a transfer one of the deals is waiting for.
+fn parse_csv(rail: PaymentRail, body: &str) -> Result<Vec<BankStatementRecord>, String> {
+    let parse_line = |body: &str| -> Option<Result<BankStatementRecord, String>> {
         // Skip the commentary:
         let body = body.split('#').next().unwrap_or(body);
         // Split the line into cells:
@@ -21,38 +33,71 @@ fn parse_csv(body: &str) -> Vec<BankStatementRecord> {
         if parts.len() != 5 {
             return None;
         }
-        Some(BankStatementRecord {
+        let Ok(amount) = parts[2].parse() else {
+            return Some(Err(format!("expected an amount, got `{}`", parts[2])));
+        };
+        Some(Ok(BankStatementRecord {
             date: parts[0].to_string(),
-            description: parts[1].to_string(),
-            amount: parts[2].parse().unwrap(),
-            account: parts[3].to_string(),
+            description: rail.normalize_account(parts[1]),
+            amount,
+            account: rail.normalize_account(parts[3]),
             transaction_id: parts[4].to_string(),
-        })
-    }
+        }))
+    };
 
     let mut records = vec![];
-    for line in body.lines() {
+    for (number, line) in body.lines().enumerate() {
         let Some(record) = parse_line(line) else {
             continue;
         };
-        records.push(record);
+        records.push(record.map_err(|e| format!("line {}: {e}", number + 1))?);
+    }
+    Ok(records)
+}
+
+/// How many digits the smallest unit of `currency` takes after the point, as ISO 4217 has it
+fn minor_units(currency: &str) -> i64 {
+    match currency {
+        "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX"
+        | "UYI" | "VND" | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+        "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+        "CLF" | "UYW" => 4,
+        _ => 2,
     }
-    records
 }
 
-/// Take in a CSV file, scan it for c2c transfers, and, if there are any that have something to do with our offers,
+/// Whether the transfer of `amount` in fiat pays for `quantity` atoms of DD at `price` in `currency`,
+/// which the bank can only move in whole minor units of it, so the deal is rounded to those;
+/// we do not care about the sign, since outgoing transfers are negative
+fn pays_for(
+    amount: &BigDecimal,
+    quantity: &BigDecimal,
+    price: &BigDecimal,
+    currency: &str,
+) -> bool {
+    let due = (quantity * price / BigDecimal::from(DD_ATOMS))
+        .with_scale_round(minor_units(currency), RoundingMode::HalfUp);
+    amount.abs() == due
+}
+
+struct Match {
+    match_id: OfferId,
+    onramp_offer_id: OfferId,
+    onramp_bank_account: String,
+    offramp_bank_account: String,
+    quantity: BigDecimal,
+    price: BigDecimal,
+    currency: String,
+}
+
+/// Take in a CSV file, scan it for transfers over `rail`, and, if there are any that have something to do with our offers,
 /// update the offers accordingly.
 pub async fn handle_readout(
     pool: &sqlx::PgPool,
+    rail: PaymentRail,
     body: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    struct Match {
-        match_id: OfferId,
-        onramp_bank_account: String,
-        offramp_bank_account: String,
-    }
-
-    let records = parse_csv(body);
+    let records = parse_csv(rail, body)?;
     // please resolve the match involving both of these bank accounts:
     for record in records {
         let relevant_deals = sqlx::query_as!(
@@ -60,8 +105,12 @@ pub async fn handle_readout(
             r#"
             SELECT
                 match.id as match_id,
+                match.onramp_offer_id,
                 bid.bank_account as onramp_bank_account,
-                ask.bank_account as offramp_bank_account
+                ask.bank_account as offramp_bank_account,
+                match.quantity,
+                match.price,
+                ask.currency
             FROM
                 match
             JOIN
@@ -69,43 +118,154 @@ pub async fn handle_readout(
             JOIN
                 offer ask ON match.offramp_offer_id = ask.id
             WHERE
-                bid.bank_account = $1 OR ask.bank_account = $1
+                (bid.bank_account = $1 OR ask.bank_account = $1)
                 AND
-                bid.bank_account = $2 OR ask.bank_account = $2
+                bid.rail = $2
             "#,
             record.account,
-            record.account,
+            rail as PaymentRail,
         )
         .fetch_all(pool)
         .await?;
         for deal in relevant_deals {
-            if deal.onramp_bank_account == record.account
-                && record.description.ends_with(&deal.offramp_bank_account)
-            {
-                let id: i64 = deal.match_id.into();
-                sqlx::query!(
-                    r#"
-                    UPDATE match SET buyer_sent_fiat = TRUE WHERE id = $1
-                    "#,
-                    id,
-                )
-                .execute(pool)
-                .await?;
+            let is_buyer_leg = deal.onramp_bank_account == record.account
+                && record.description.ends_with(&deal.offramp_bank_account);
+            let is_seller_leg = deal.offramp_bank_account == record.account
+                && record.description.ends_with(&deal.onramp_bank_account);
+            if !is_buyer_leg && !is_seller_leg {
+                continue;
             }
-            if deal.offramp_bank_account == record.account
-                && record.description.ends_with(&deal.onramp_bank_account)
-            {
-                let id: i64 = deal.match_id.into();
-                sqlx::query!(
-                    r#"
-                    UPDATE match SET seller_received_fiat = TRUE WHERE id = $1
-                    "#,
-                    id,
-                )
-                .execute(pool)
-                .await?;
+
+            let result = record_leg(pool, &record, &deal, is_buyer_leg).await;
+            if let Err(e) = result {
+                tracing::error!("While recording a leg of match #{}: {:?}", deal.match_id, e);
             }
         }
     }
     Ok(())
 }
+
+/// Flag the leg of the `deal` that the `record` settles,
+/// or put the deal in dispute if the record moves the wrong amount
+async fn record_leg(
+    pool: &sqlx::PgPool,
+    record: &BankStatementRecord,
+    deal: &Match,
+    is_buyer_leg: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut transaction = pool.begin().await?;
+    let id: i64 = deal.match_id.into();
+    if !pays_for(&record.amount, &deal.quantity, &deal.price, &deal.currency) {
+        // The parties have moved money between themselves, but not what the deal says:
+        tracing::warn!(
+            "Transfer {} of {} does not pay for match #{}",
+            record.transaction_id,
+            record.amount,
+            deal.match_id,
+        );
+        transition(
+            &mut transaction,
+            deal.onramp_offer_id,
+            Some(deal.match_id),
+            DealState::Disputed,
+            "transfer of the wrong amount",
+        )
+        .await?;
+        // For whoever rules on it, kept out of the cause like the ids of the legs:
+        sqlx::query!(
+            "UPDATE match SET disputed_transaction_id = $2 WHERE id = $1",
+            id,
+            record.transaction_id,
+        )
+        .execute(&mut *transaction)
+        .await?;
+    } else if is_buyer_leg {
+        transition(
+            &mut transaction,
+            deal.onramp_offer_id,
+            Some(deal.match_id),
+            DealState::BuyerPaid,
+            "buyer sent fiat",
+        )
+        .await?;
+        sqlx::query!(
+            r#"
+            UPDATE match SET buyer_sent_fiat = TRUE, buyer_transaction_id = $2 WHERE id = $1
+            "#,
+            id,
+            record.transaction_id,
+        )
+        .execute(&mut *transaction)
+        .await?;
+    } else {
+        transition(
+            &mut transaction,
+            deal.onramp_offer_id,
+            Some(deal.match_id),
+            DealState::SellerConfirmed,
+            "seller received fiat",
+        )
+        .await?;
+        sqlx::query!(
+            r#"
+            UPDATE match SET seller_received_fiat = TRUE, seller_transaction_id = $2 WHERE id = $1
+            "#,
+            id,
+            record.transaction_id,
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    // This may have been the last leg the release was waiting for:
+    wake(&mut transaction, Job::Release).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn decimal(spelling: &str) -> BigDecimal {
+        BigDecimal::from_str(spelling).unwrap()
+    }
+
+    #[test]
+    fn rounds_the_deal_to_what_a_bank_can_move() {
+        // 3 DD at 0.333 come to 0.999, which no bank can move:
+        let (quantity, price) = (decimal("3000000"), decimal("0.333"));
+        assert!(pays_for(&decimal("-1.00"), &quantity, &price, "USD"));
+        assert!(pays_for(&decimal("1"), &quantity, &price, "USD"));
+        assert!(!pays_for(&decimal("0.99"), &quantity, &price, "USD"));
+
+        // 1.5 DD at 151.3 come to 226.95 yen, of which there are no fractions:
+        let (quantity, price) = (decimal("1500000"), decimal("151.3"));
+        assert!(pays_for(&decimal("227"), &quantity, &price, "JPY"));
+        assert!(!pays_for(&decimal("226.95"), &quantity, &price, "JPY"));
+
+        assert!(pays_for(
+            &decimal("5.000"),
+            &decimal("5000000"),
+            &decimal("1"),
+            "KWD"
+        ));
+    }
+
+    #[test]
+    fn tells_which_record_it_cannot_read() {
+        let body = "# Date,Description,Amount,Account,Transaction ID\n\
+                    2025-01-01,From 4111111111111111,-5.00,4222222222222222,T1\n\
+                    2025-01-02,From 4111111111111111,five,4222222222222222,T2\n";
+        assert_eq!(
+            parse_csv(PaymentRail::C2C, body).err(),
+            Some("line 3: expected an amount, got `five`".to_string())
+        );
+
+        let body = "2025-01-01,From 4111111111111111,-5.00,4222222222222222,T1\nnot a record";
+        let records = parse_csv(PaymentRail::C2C, body).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].amount, decimal("-5"));
+    }
+}
@@ -0,0 +1,384 @@
+//! Checking the book against what is actually sitting in escrow on chain
+//!
+//! The program keeps the DD of every seller in an escrow of their own, and the bonds of the buyers
+//! in bonds of their own; both are token accounts that own themselves.
+//! We list every DD account, keep the ones that own themselves, and compare their balances
+//! with what the live offers say they should hold.
+//! The vault of the shielded pool owns itself too, but belongs to no wallet,
+//! so its balance is reported on its own rather than compared with anything.
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, ToPrimitive};
+use solana_client::client_error::ClientError;
+use solana_sdk::pubkey::Pubkey;
+use sqlx::PgPool;
+
+use crate::chain::{token_amount, token_mint, token_owner, ChainClient};
+use crate::conf::Conf;
+use crate::schema::OfferDirection;
+
+/// Something the book and the chain disagree about
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Discrepancy {
+    /// DD held by the program that no live deal accounts for
+    #[serde(rename_all = "camelCase")]
+    OrphanEscrow { escrow: String, balance: u64 },
+    /// A seller with offers on the book, whose escrow holds nothing
+    #[serde(rename_all = "camelCase")]
+    EmptyEscrow {
+        public_key: String,
+        escrow: String,
+        expected: u64,
+    },
+    /// An escrow holding another amount than the offers of its seller add up to
+    #[serde(rename_all = "camelCase")]
+    BalanceMismatch {
+        public_key: String,
+        escrow: String,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// How many accounts of the program were looked at
+    pub accounts: usize,
+    /// How many wallets the book knows to have something with the program
+    pub wallets: usize,
+    /// The DD in the shielded pool, which no offer accounts for
+    #[serde(default)]
+    pub shielded: u64,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+/// What the book says a wallet should have with the program
+#[derive(Debug, Default, Clone, Copy)]
+struct Expected {
+    /// The DD left of its sell offers
+    offered: u64,
+    /// The DD of its preoffers, which may or may not have been deposited yet
+    pending: u64,
+    /// Whether it has ever offered fiat, and so may have a bond
+    bonded: bool,
+}
+
+/// Compare the `expected` holdings of the wallets with the `held` balances
+/// of the accounts of the program, given where the escrow and bond of a wallet are
+fn compare(
+    expected: &HashMap<Pubkey, Expected>,
+    held: &HashMap<Pubkey, u64>,
+    escrow_of: impl Fn(&Pubkey) -> Pubkey,
+    bond_of: impl Fn(&Pubkey) -> Pubkey,
+) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+    let mut accounted = HashSet::new();
+
+    for (wallet, expected) in expected {
+        let escrow = escrow_of(wallet);
+        accounted.insert(escrow);
+        if expected.bonded {
+            accounted.insert(bond_of(wallet));
+        }
+
+        let actual = held.get(&escrow).copied().unwrap_or(0);
+        // A deposit the watcher has not promoted yet is fine:
+        let fine = [expected.offered, expected.offered + expected.pending];
+        if expected.offered > 0 && actual == 0 {
+            discrepancies.push(Discrepancy::EmptyEscrow {
+                public_key: wallet.to_string(),
+                escrow: escrow.to_string(),
+                expected: expected.offered,
+            });
+        } else if !fine.contains(&actual) {
+            discrepancies.push(Discrepancy::BalanceMismatch {
+                public_key: wallet.to_string(),
+                escrow: escrow.to_string(),
+                expected: expected.offered,
+                actual,
+            });
+        }
+    }
+
+    for (account, &balance) in held {
+        if balance > 0 && !accounted.contains(account) {
+            discrepancies.push(Discrepancy::OrphanEscrow {
+                escrow: account.to_string(),
+                balance,
+            });
+        }
+    }
+
+    discrepancies
+}
+
+/// Where the program keeps the DD of all the notes of the shielded pool
+pub fn shielded_vault(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"vault/shielded"], program_id).0
+}
+
+/// The balance of every escrow and bond of the program, and of the vault of the shielded pool,
+/// which are the only DD accounts that own themselves
+pub async fn program_balances(
+    client: &impl ChainClient,
+    token_program: &Pubkey,
+    dd_mint: &Pubkey,
+) -> Result<HashMap<Pubkey, u64>, ClientError> {
+    Ok(client
+        .token_accounts(token_program, dd_mint)
+        .await?
+        .into_iter()
+        .filter(|(address, data)| {
+            token_mint(data).as_ref() == Some(dd_mint)
+                && token_owner(data).as_ref() == Some(address)
+        })
+        .map(|(address, data)| (address, token_amount(&data).unwrap_or(0)))
+        .collect())
+}
+
+/// Compare every escrow of the program with the live offers
+pub async fn reconcile(
+    conf: &Conf,
+    pool: &PgPool,
+    client: &impl ChainClient,
+) -> Result<Report, Box<dyn std::error::Error>> {
+    let (Some(program_id), Some(token_program), Some(dd_mint)) =
+        (conf.program_id(), conf.token_program(), conf.dd_mint())
+    else {
+        return Err("envs are missing".into());
+    };
+
+    let mut expected: HashMap<Pubkey, Expected> = HashMap::new();
+    let offers = sqlx::query!(
+        r#"SELECT public_key, direction as "direction: OfferDirection", SUM(amount) as "amount!" FROM offer GROUP BY public_key, direction"#
+    )
+    .fetch_all(pool)
+    .await?;
+    for offer in offers {
+        let Ok(wallet) = Pubkey::from_str(&offer.public_key) else {
+            continue;
+        };
+        let entry = expected.entry(wallet).or_default();
+        match offer.direction {
+            OfferDirection::DDToFiat => entry.offered = atoms(&offer.amount),
+            OfferDirection::FiatToDD => entry.bonded = true,
+        }
+    }
+    let preoffers = sqlx::query!(
+        r#"SELECT public_key, SUM(amount) as "amount!" FROM preoffer GROUP BY public_key"#
+    )
+    .fetch_all(pool)
+    .await?;
+    for preoffer in preoffers {
+        let Ok(wallet) = Pubkey::from_str(&preoffer.public_key) else {
+            continue;
+        };
+        expected.entry(wallet).or_default().pending = atoms(&preoffer.amount);
+    }
+    // Bonds outlive the buy offers they were put up for:
+    let buyers = sqlx::query_scalar!(
+        "SELECT DISTINCT public_key FROM offer_archive WHERE record->>'direction' = 'fiat_to_dd'"
+    )
+    .fetch_all(pool)
+    .await?;
+    for buyer in buyers {
+        let Ok(wallet) = Pubkey::from_str(&buyer) else {
+            continue;
+        };
+        expected.entry(wallet).or_default().bonded = true;
+    }
+
+    let mut held = program_balances(client, token_program, dd_mint).await?;
+    let accounts = held.len();
+    let shielded = held.remove(&shielded_vault(program_id)).unwrap_or(0);
+
+    let address = |seed: &[u8], wallet: &Pubkey| {
+        Pubkey::find_program_address(&[seed, wallet.as_ref()], program_id).0
+    };
+    let discrepancies = compare(
+        &expected,
+        &held,
+        |wallet| address(b"escrow", wallet),
+        |wallet| address(b"bond", wallet),
+    );
+
+    Ok(Report {
+        accounts,
+        wallets: expected.len(),
+        shielded,
+        discrepancies,
+    })
+}
+
+/// Keep the `report`, so that any replica can show the latest one
+pub async fn save_report(pool: &PgPool, report: &Report) -> Result<(), Box<dyn std::error::Error>> {
+    sqlx::query!(
+        "INSERT INTO reconciliation_report (report) VALUES ($1)",
+        serde_json::to_value(report)?
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The latest report, and when it was made, if there has been one
+pub async fn latest_report(
+    pool: &PgPool,
+) -> Result<Option<serde_json::Value>, Box<dyn std::error::Error>> {
+    let latest = sqlx::query!(
+        r#"SELECT report, created_at::text as "created_at!" FROM reconciliation_report ORDER BY id DESC LIMIT 1"#
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(latest.map(|latest| {
+        serde_json::json!({
+            "createdAt": latest.created_at,
+            "report": latest.report,
+        })
+    }))
+}
+
+fn atoms(amount: &BigDecimal) -> u64 {
+    amount.to_u64().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::fake::FakeChain;
+
+    fn escrow_of(wallet: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(&[b"escrow", wallet.as_ref()], &Pubkey::default()).0
+    }
+
+    fn bond_of(wallet: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(&[b"bond", wallet.as_ref()], &Pubkey::default()).0
+    }
+
+    fn check(expected: &[(Pubkey, Expected)], held: &[(Pubkey, u64)]) -> Vec<Discrepancy> {
+        compare(
+            &expected.iter().copied().collect(),
+            &held.iter().copied().collect(),
+            escrow_of,
+            bond_of,
+        )
+    }
+
+    fn offered(offered: u64) -> Expected {
+        Expected {
+            offered,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn agrees_with_escrows_that_hold_the_offers() {
+        let (seller, buyer) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let bonded = Expected {
+            bonded: true,
+            ..Default::default()
+        };
+        let discrepancies = check(
+            &[(seller, offered(5)), (buyer, bonded)],
+            &[(escrow_of(&seller), 5), (bond_of(&buyer), 2)],
+        );
+        assert_eq!(discrepancies, []);
+    }
+
+    #[test]
+    fn tolerates_a_deposit_not_promoted_yet() {
+        let seller = Pubkey::new_unique();
+        let pending = Expected {
+            offered: 5,
+            pending: 3,
+            bonded: false,
+        };
+        assert_eq!(check(&[(seller, pending)], &[(escrow_of(&seller), 8)]), []);
+        assert_eq!(check(&[(seller, pending)], &[(escrow_of(&seller), 5)]), []);
+    }
+
+    #[test]
+    fn flags_what_does_not_add_up() {
+        let (empty, short, gone) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let discrepancies = check(
+            &[(empty, offered(5)), (short, offered(5))],
+            &[(escrow_of(&short), 4), (escrow_of(&gone), 7)],
+        );
+        assert_eq!(discrepancies.len(), 3);
+        assert!(discrepancies.contains(&Discrepancy::EmptyEscrow {
+            public_key: empty.to_string(),
+            escrow: escrow_of(&empty).to_string(),
+            expected: 5,
+        }));
+        assert!(discrepancies.contains(&Discrepancy::BalanceMismatch {
+            public_key: short.to_string(),
+            escrow: escrow_of(&short).to_string(),
+            expected: 5,
+            actual: 4,
+        }));
+        assert!(discrepancies.contains(&Discrepancy::OrphanEscrow {
+            escrow: escrow_of(&gone).to_string(),
+            balance: 7,
+        }));
+    }
+
+    async fn offer_dd(pool: &PgPool, seller: &Pubkey, amount: u64) {
+        sqlx::query(
+            "INSERT INTO offer (amount, bank_account, public_key, direction, rail, currency, price) VALUES ($1, '4111111111111111', $2, 'dd_to_fiat', 'c2c', 'USD', 1)",
+        )
+        .bind(BigDecimal::from(amount))
+        .bind(seller.to_string())
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn reports_only_the_escrows_that_disagree(pool: PgPool) {
+        let conf = Conf::for_tests();
+        let client = FakeChain::default();
+        let dd_mint = *conf.dd_mint().unwrap();
+        let address = |seed: &[u8], wallet: &Pubkey| {
+            Pubkey::find_program_address(&[seed, wallet.as_ref()], conf.program_id().unwrap()).0
+        };
+        let escrow = |account: Pubkey, amount: u64| {
+            client.set_token_account(account, dd_mint, account, amount)
+        };
+
+        let (fine, short) = (Pubkey::new_unique(), Pubkey::new_unique());
+        offer_dd(&pool, &fine, 5).await;
+        offer_dd(&pool, &short, 5).await;
+        escrow(address(b"escrow", &fine), 5);
+        escrow(address(b"escrow", &short), 3);
+        let orphan = Pubkey::new_unique();
+        escrow(orphan, 2);
+        // Neither the DD of a wallet itself, nor another token, is any of our business:
+        client.set_token_account(Pubkey::new_unique(), dd_mint, fine, 9);
+        client.set_token_account(Pubkey::new_unique(), Pubkey::new_unique(), orphan, 9);
+
+        // Nor is the shielded pool, which belongs to no wallet:
+        escrow(shielded_vault(conf.program_id().unwrap()), 6);
+
+        let report = reconcile(&conf, &pool, &client).await.unwrap();
+        assert_eq!(report.accounts, 4);
+        assert_eq!(report.wallets, 2);
+        assert_eq!(report.shielded, 6);
+        assert_eq!(report.discrepancies.len(), 2);
+        assert!(report.discrepancies.contains(&Discrepancy::OrphanEscrow {
+            escrow: orphan.to_string(),
+            balance: 2,
+        }));
+
+        assert_eq!(latest_report(&pool).await.unwrap(), None);
+        save_report(&pool, &report).await.unwrap();
+        let latest = latest_report(&pool).await.unwrap().unwrap();
+        assert!(latest["report"]["discrepancies"][0]["kind"].is_string());
+    }
+}
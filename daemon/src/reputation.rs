@@ -0,0 +1,75 @@
+//! Track record of the wallets, as kept on chain by the program
+//!
+//! We cannot link the program crate here (see `Cargo.toml`),
+//! so the account layout is decoded by hand.
+use solana_sdk::pubkey::Pubkey;
+
+use crate::chain::ChainClient;
+
+/// Mirror of `araza::UserStats`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UserStats {
+    pub deals_completed: u64,
+    pub volume: u64,
+    pub cancellations: u64,
+    pub disputes_lost: u64,
+}
+
+impl UserStats {
+    /// Read the account data as Anchor lays it out:
+    /// eight bytes of discriminator followed by the fields in order
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let discriminator = &solana_sdk::hash::hash(b"account:UserStats").to_bytes()[..8];
+        if data.get(..8)? != discriminator {
+            return None;
+        }
+        let field = |n: usize| {
+            let start = 8 + 8 * n;
+            Some(u64::from_le_bytes(
+                data.get(start..start + 8)?.try_into().ok()?,
+            ))
+        };
+        Some(Self {
+            deals_completed: field(0)?,
+            volume: field(1)?,
+            cancellations: field(2)?,
+            disputes_lost: field(3)?,
+        })
+    }
+
+    /// Whether we have not seen this wallet settle anything yet
+    pub fn is_fresh(&self) -> bool {
+        self.deals_completed == 0
+    }
+
+    /// How much DD this wallet has to put up before its fiat offers get matched,
+    /// given what we ask of wallets with a track record
+    pub fn required_bond(&self, base: u64) -> u64 {
+        if self.is_fresh() {
+            base.saturating_mul(2)
+        } else {
+            base
+        }
+    }
+}
+
+/// Where the program keeps the stats of `wallet`
+pub fn stats_address(program_id: &Pubkey, wallet: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"stats", wallet.as_ref()], program_id).0
+}
+
+/// Fetch the stats of `wallet`; a wallet that has never made an offer has none,
+/// and is treated as fresh
+pub async fn fetch_user_stats(
+    client: &impl ChainClient,
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+) -> UserStats {
+    client
+        .account_data(&stats_address(program_id, wallet))
+        .await
+        .ok()
+        .flatten()
+        .and_then(|data| UserStats::decode(&data))
+        .unwrap_or_default()
+}
@@ -0,0 +1,268 @@
+//! Proof that every DD is backed by a USDC in the vault
+//!
+//! An attestation is what the chain said at one slot: how much USDC sits in the vault,
+//! how much DD has been minted, and how much of it the program holds in escrows and bonds,
+//! and in the shielded pool.
+//! It is signed by the treasurer, whose key anyone can look up in the state of the program,
+//! and the exact message that was signed is handed out with it, so that it can be checked
+//! without having to agree on how to serialize it.
+//! What is signed is that message behind [`DOMAIN`], so that no attestation can pass
+//! for a transaction or anything else the treasurer signs, nor the other way around.
+//! The leader takes one every so often, and those are all that is handed out,
+//! so that anonymous requests cost neither a scan of the chain nor a signature.
+use std::time::SystemTime;
+
+use bigdecimal::BigDecimal;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signer::Signer;
+use sqlx::PgPool;
+
+use crate::chain::{mint_supply, token_amount, token_mint, ChainClient};
+use crate::conf::Conf;
+use crate::reconcile::{program_balances, shielded_vault};
+
+/// What the signed bytes of every attestation start with, before its message
+pub const DOMAIN: &[u8] = b"araza-reserves-v1\0";
+
+/// The most attestations handed out at once
+pub const MAX_HISTORY: i64 = 1000;
+
+/// What the chain said about the reserves at one slot, all amounts in atoms
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Attestation {
+    /// The slot the vault and the supply were read at
+    pub slot: u64,
+    /// When it was taken, in seconds since the Unix epoch
+    pub taken_at: u64,
+    pub vault: String,
+    pub usdc_mint: String,
+    pub dd_mint: String,
+    pub vault_balance: u64,
+    pub dd_supply: u64,
+    /// The DD in the escrows and bonds of the program, read right before the slot
+    pub escrowed_dd: u64,
+    /// The DD in the shielded pool, read along with the escrows
+    #[serde(default)]
+    pub shielded_dd: u64,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedAttestation {
+    #[serde(flatten)]
+    pub attestation: Attestation,
+    /// The attestation as JSON, exactly as it was signed
+    pub message: String,
+    /// The public key of the treasurer
+    pub signer: String,
+    /// The ed25519 signature of the message behind [`DOMAIN`], in base58
+    pub signature: String,
+}
+
+/// Read the reserves off the chain, and sign them with the key of the treasurer
+pub async fn attest(
+    conf: &Conf,
+    client: &impl ChainClient,
+) -> Result<SignedAttestation, Box<dyn std::error::Error>> {
+    let (Some(program_id), Some(token_program), Some(dd_mint), Some(treasurer)) = (
+        conf.program_id(),
+        conf.token_program(),
+        conf.dd_mint(),
+        conf.treasurer_secret_key(),
+    ) else {
+        return Err("envs are missing".into());
+    };
+    let (vault, _) = Pubkey::find_program_address(&[b"vault/usdc"], program_id);
+
+    let mut balances = program_balances(client, token_program, dd_mint).await?;
+    let shielded_dd = balances.remove(&shielded_vault(program_id)).unwrap_or(0);
+    let escrowed_dd = balances.values().sum();
+    let (slot, accounts) = client.account_data_at_slot(&[vault, *dd_mint]).await?;
+    let [Some(vault_data), Some(mint_data)] = accounts.as_slice() else {
+        return Err("The vault or the DD mint does not exist".into());
+    };
+    let (Some(usdc_mint), Some(vault_balance), Some(dd_supply)) = (
+        token_mint(vault_data),
+        token_amount(vault_data),
+        mint_supply(mint_data),
+    ) else {
+        return Err("The vault or the DD mint cannot be read".into());
+    };
+
+    let attestation = Attestation {
+        slot,
+        taken_at: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs(),
+        vault: vault.to_string(),
+        usdc_mint: usdc_mint.to_string(),
+        dd_mint: dd_mint.to_string(),
+        vault_balance,
+        dd_supply,
+        escrowed_dd,
+        shielded_dd,
+    };
+    let message = serde_json::to_string(&attestation)?;
+    let signature = treasurer.sign_message(&[DOMAIN, message.as_bytes()].concat());
+    Ok(SignedAttestation {
+        attestation,
+        message,
+        signer: treasurer.pubkey().to_string(),
+        signature: signature.to_string(),
+    })
+}
+
+/// Add the `attestation` to the history
+pub async fn save_attestation(
+    pool: &PgPool,
+    attestation: &SignedAttestation,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let SignedAttestation {
+        attestation: reserves,
+        message,
+        signer,
+        signature,
+    } = attestation;
+    sqlx::query!(
+        "INSERT INTO reserve_attestation (slot, vault_balance, dd_supply, escrowed_dd, shielded_dd, message, signer, signature) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        i64::try_from(reserves.slot)?,
+        BigDecimal::from(reserves.vault_balance),
+        BigDecimal::from(reserves.dd_supply),
+        BigDecimal::from(reserves.escrowed_dd),
+        BigDecimal::from(reserves.shielded_dd),
+        message,
+        signer,
+        signature,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The latest attestation taken at or before `slot`, if any
+pub async fn attestation_at(
+    pool: &PgPool,
+    slot: u64,
+) -> Result<Option<SignedAttestation>, Box<dyn std::error::Error>> {
+    let record = sqlx::query!(
+        "SELECT message, signer, signature FROM reserve_attestation WHERE slot <= $1 ORDER BY slot DESC, id DESC LIMIT 1",
+        i64::try_from(slot)?
+    )
+    .fetch_optional(pool)
+    .await?;
+    record
+        .map(|record| signed(record.message, record.signer, record.signature))
+        .transpose()
+}
+
+/// The latest attestation, if any has been taken yet
+pub async fn latest_attestation(
+    pool: &PgPool,
+) -> Result<Option<SignedAttestation>, Box<dyn std::error::Error>> {
+    let record = sqlx::query!(
+        "SELECT message, signer, signature FROM reserve_attestation ORDER BY slot DESC, id DESC LIMIT 1"
+    )
+    .fetch_optional(pool)
+    .await?;
+    record
+        .map(|record| signed(record.message, record.signer, record.signature))
+        .transpose()
+}
+
+/// The latest `limit` attestations, oldest first
+pub async fn attestation_history(
+    pool: &PgPool,
+    limit: i64,
+) -> Result<Vec<SignedAttestation>, Box<dyn std::error::Error>> {
+    let records = sqlx::query!(
+        "SELECT message, signer, signature FROM (SELECT * FROM reserve_attestation ORDER BY slot DESC, id DESC LIMIT $1) latest ORDER BY slot, id",
+        limit.clamp(0, MAX_HISTORY)
+    )
+    .fetch_all(pool)
+    .await?;
+    records
+        .into_iter()
+        .map(|record| signed(record.message, record.signer, record.signature))
+        .collect()
+}
+
+fn signed(
+    message: String,
+    signer: String,
+    signature: String,
+) -> Result<SignedAttestation, Box<dyn std::error::Error>> {
+    Ok(SignedAttestation {
+        attestation: serde_json::from_str(&message)?,
+        message,
+        signer,
+        signature,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use solana_sdk::signature::Signature;
+
+    use super::*;
+    use crate::chain::fake::FakeChain;
+
+    #[sqlx::test]
+    async fn signs_what_the_chain_says_and_keeps_it(pool: PgPool) {
+        assert!(latest_attestation(&pool).await.unwrap().is_none());
+        let conf = Conf::for_tests();
+        let client = FakeChain::default();
+        let dd_mint = *conf.dd_mint().unwrap();
+        let usdc_mint = Pubkey::new_unique();
+        let (vault, _) = Pubkey::find_program_address(&[b"vault/usdc"], conf.program_id().unwrap());
+        let escrow = Pubkey::new_unique();
+        client.set_token_account(vault, usdc_mint, vault, 10_000000);
+        client.set_mint(dd_mint, 10_000000);
+        client.set_token_account(escrow, dd_mint, escrow, 4_000000);
+        let shielded = shielded_vault(conf.program_id().unwrap());
+        client.set_token_account(shielded, dd_mint, shielded, 3_000000);
+        client.set_slot(42);
+
+        let signed = attest(&conf, &client).await.unwrap();
+        assert_eq!(signed.attestation.slot, 42);
+        assert_eq!(signed.attestation.usdc_mint, usdc_mint.to_string());
+        assert_eq!(signed.attestation.vault_balance, 10_000000);
+        assert_eq!(signed.attestation.dd_supply, 10_000000);
+        assert_eq!(signed.attestation.escrowed_dd, 4_000000);
+        assert_eq!(signed.attestation.shielded_dd, 3_000000);
+        let signer = conf.treasurer_secret_key().unwrap().pubkey();
+        assert_eq!(signed.signer, signer.to_string());
+        assert!(Signature::from_str(&signed.signature).unwrap().verify(
+            signer.as_ref(),
+            &[DOMAIN, signed.message.as_bytes()].concat()
+        ));
+        // Not something that could be taken for a message of another kind:
+        assert!(!Signature::from_str(&signed.signature)
+            .unwrap()
+            .verify(signer.as_ref(), signed.message.as_bytes()));
+
+        save_attestation(&pool, &signed).await.unwrap();
+        client.set_slot(50);
+        client.set_mint(dd_mint, 12_000000);
+        save_attestation(&pool, &attest(&conf, &client).await.unwrap())
+            .await
+            .unwrap();
+
+        assert!(latest_attestation(&pool)
+            .await
+            .unwrap()
+            .is_some_and(|latest| latest.attestation.slot == 50));
+        assert!(attestation_at(&pool, 41).await.unwrap().is_none());
+        let then = attestation_at(&pool, 49).await.unwrap().unwrap();
+        assert_eq!(then.attestation, signed.attestation);
+        assert_eq!(then.message, signed.message);
+        let history = attestation_history(&pool, 10).await.unwrap();
+        let supplies = history
+            .iter()
+            .map(|signed| signed.attestation.dd_supply)
+            .collect::<Vec<_>>();
+        assert_eq!(supplies, [10_000000, 12_000000]);
+    }
+}
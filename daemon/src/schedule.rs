@@ -0,0 +1,325 @@
+//! When each of the background jobs runs next
+//!
+//! Every job runs on its own interval. A job that fails is retried
+//! with exponential backoff rather than right away, so that an unreachable
+//! database or RPC node does not get hammered, and every delay is jittered
+//! so that the jobs do not all wake up at once.
+//!
+//! Jobs can also be woken up early, by a `NOTIFY` on [`WAKE_CHANNEL`]
+//! with the name of the job as the payload, from whichever replica has new work for it.
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::Rng;
+use sqlx::PgConnection;
+
+/// The Postgres channel to wake the jobs up through
+pub const WAKE_CHANNEL: &str = "araza_jobs";
+
+/// The longest a failing job waits before it is tried again
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// How far off its delay a job may run, as a fraction of the delay
+const JITTER: f64 = 0.1;
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum Job {
+    /// Turn the preoffers whose DD has arrived into offers
+    Promotion,
+    Matching,
+    /// Release the DD of the deals whose fiat has gone both ways, or that were ruled on
+    Release,
+    /// Give up on the preoffers and matches that ran out of time
+    Expiry,
+    /// Compare the escrows on chain with the book
+    Reconciliation,
+    /// Sign the reserves, for their history
+    Attestation,
+    /// Read the new transactions of the program into the database
+    Indexing,
+}
+
+impl Job {
+    pub const ALL: [Job; 7] = [
+        Job::Promotion,
+        Job::Matching,
+        Job::Release,
+        Job::Expiry,
+        Job::Reconciliation,
+        Job::Attestation,
+        Job::Indexing,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Job::Promotion => "promotion",
+            Job::Matching => "matching",
+            Job::Release => "release",
+            Job::Expiry => "expiry",
+            Job::Reconciliation => "reconciliation",
+            Job::Attestation => "attestation",
+            Job::Indexing => "indexing",
+        }
+    }
+
+    /// The env its interval is set with, in seconds
+    pub fn interval_key(self) -> &'static str {
+        match self {
+            Job::Promotion => "PROMOTION_INTERVAL_SECS",
+            Job::Matching => "MATCHING_INTERVAL_SECS",
+            Job::Release => "RELEASE_INTERVAL_SECS",
+            Job::Expiry => "EXPIRY_INTERVAL_SECS",
+            Job::Reconciliation => "RECONCILIATION_INTERVAL_SECS",
+            Job::Attestation => "ATTESTATION_INTERVAL_SECS",
+            Job::Indexing => "INDEXING_INTERVAL_SECS",
+        }
+    }
+
+    /// How often it runs unless set otherwise
+    pub fn default_interval(self) -> Duration {
+        match self {
+            // Deposits are mostly noticed by the watcher; this only catches the ones it has missed
+            Job::Promotion => Duration::from_secs(60),
+            Job::Matching | Job::Release | Job::Expiry => Duration::from_secs(4),
+            // It lists every DD account there is, which RPC nodes are slow to answer
+            Job::Reconciliation => Duration::from_secs(10 * 60),
+            Job::Attestation => Duration::from_secs(60 * 60),
+            // Transactions are only indexed once finalized, which takes longer than that anyway
+            Job::Indexing => Duration::from_secs(15),
+        }
+    }
+}
+
+impl FromStr for Job {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Job::ALL
+            .into_iter()
+            .find(|job| job.name() == name)
+            .ok_or_else(|| format!("Unknown job `{name}`"))
+    }
+}
+
+/// Have the leader run the `job` soon, once the current transaction on `connection` commits
+pub async fn wake(connection: &mut PgConnection, job: Job) -> Result<(), sqlx::Error> {
+    sqlx::query!("SELECT pg_notify($1, $2)", WAKE_CHANNEL, job.name())
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+/// How the jobs have been doing, as reported to whoever asks
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleStatus {
+    /// Whether the jobs run on this replica
+    pub leading: bool,
+    pub jobs: Vec<JobStatus>,
+}
+
+/// How a job has been doing
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatus {
+    pub job: Job,
+    pub interval_secs: u64,
+    /// When it last went through, in seconds since the Unix epoch
+    pub last_success: Option<u64>,
+    /// How many times in a row it has failed since
+    pub failures: u32,
+}
+
+struct Entry {
+    job: Job,
+    interval: Duration,
+    due: Instant,
+    failures: u32,
+    last_success: Option<SystemTime>,
+}
+
+pub struct Schedule {
+    entries: Vec<Entry>,
+    leading: bool,
+}
+
+impl Schedule {
+    /// Schedule the `jobs`, each with its interval, all of them due right away
+    pub fn new(jobs: impl IntoIterator<Item = (Job, Duration)>, now: Instant) -> Self {
+        let entries = jobs
+            .into_iter()
+            .map(|(job, interval)| Entry {
+                job,
+                interval,
+                due: now,
+                failures: 0,
+                last_success: None,
+            })
+            .collect();
+        Self {
+            entries,
+            leading: false,
+        }
+    }
+
+    /// The job due the soonest, and when it is due
+    pub fn next(&self) -> Option<(Job, Instant)> {
+        self.entries
+            .iter()
+            .min_by_key(|entry| entry.due)
+            .map(|entry| (entry.job, entry.due))
+    }
+
+    /// Put the `job` off for its interval, as it has just gone through
+    pub fn succeeded(&mut self, job: Job, now: Instant) {
+        let Some(entry) = self.entry_mut(job) else {
+            return;
+        };
+        entry.failures = 0;
+        entry.last_success = Some(SystemTime::now());
+        entry.due = now + jittered(entry.interval);
+    }
+
+    /// Make the `job` due right away, unless it is backing off from failing
+    pub fn hurry(&mut self, job: Job, now: Instant) {
+        let Some(entry) = self.entry_mut(job) else {
+            return;
+        };
+        if entry.failures == 0 {
+            entry.due = entry.due.min(now);
+        }
+    }
+
+    /// Put the `job` off for longer the more times in a row it has failed
+    pub fn failed(&mut self, job: Job, now: Instant) {
+        let Some(entry) = self.entry_mut(job) else {
+            return;
+        };
+        entry.failures = entry.failures.saturating_add(1);
+        entry.due = now + jittered(backoff(entry.interval, entry.failures));
+    }
+
+    /// Note whether this replica is the one running the jobs
+    pub fn set_leading(&mut self, leading: bool) {
+        self.leading = leading;
+    }
+
+    pub fn status(&self) -> ScheduleStatus {
+        let jobs = self
+            .entries
+            .iter()
+            .map(|entry| JobStatus {
+                job: entry.job,
+                interval_secs: entry.interval.as_secs(),
+                last_success: entry.last_success.and_then(|at| {
+                    at.duration_since(SystemTime::UNIX_EPOCH)
+                        .ok()
+                        .map(|since| since.as_secs())
+                }),
+                failures: entry.failures,
+            })
+            .collect();
+        ScheduleStatus {
+            leading: self.leading,
+            jobs,
+        }
+    }
+
+    fn entry_mut(&mut self, job: Job) -> Option<&mut Entry> {
+        self.entries.iter_mut().find(|entry| entry.job == job)
+    }
+}
+
+/// How long to wait after the `failures`-th failure in a row of a job that runs every `interval`
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.min(16));
+    interval.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+fn jittered(delay: Duration) -> Duration {
+    delay.mul_f64(rand::thread_rng().gen_range(1.0 - JITTER..1.0 + JITTER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(now: Instant) -> Schedule {
+        Schedule::new(
+            [
+                (Job::Matching, Duration::from_secs(4)),
+                (Job::Promotion, Duration::from_secs(60)),
+            ],
+            now,
+        )
+    }
+
+    fn due_in(schedule: &Schedule, job: Job, now: Instant) -> Duration {
+        schedule
+            .entries
+            .iter()
+            .find(|entry| entry.job == job)
+            .unwrap()
+            .due
+            - now
+    }
+
+    #[test]
+    fn runs_every_job_on_its_own_interval() {
+        let now = Instant::now();
+        let mut schedule = schedule(now);
+        schedule.succeeded(Job::Matching, now);
+        schedule.succeeded(Job::Promotion, now);
+
+        let matching = due_in(&schedule, Job::Matching, now);
+        assert!(
+            matching >= Duration::from_secs_f64(3.6) && matching <= Duration::from_secs_f64(4.4)
+        );
+        assert_eq!(schedule.next().map(|(job, _)| job), Some(Job::Matching));
+        assert!(schedule
+            .status()
+            .jobs
+            .iter()
+            .all(|status| status.last_success.is_some()));
+    }
+
+    #[test]
+    fn backs_off_a_failing_job() {
+        let now = Instant::now();
+        let mut schedule = schedule(now);
+        for failures in 1..=3 {
+            schedule.failed(Job::Matching, now);
+            let delay = due_in(&schedule, Job::Matching, now).as_secs_f64();
+            let expected = 4.0 * 2f64.powi(failures);
+            assert!(
+                (delay - expected).abs() <= expected * JITTER,
+                "{delay} vs {expected}"
+            );
+        }
+
+        schedule.succeeded(Job::Matching, now);
+        assert_eq!(schedule.status().jobs[0].failures, 0);
+        assert!(due_in(&schedule, Job::Matching, now) <= Duration::from_secs_f64(4.4));
+    }
+
+    #[test]
+    fn hurries_only_the_jobs_that_are_not_failing() {
+        let now = Instant::now();
+        let mut schedule = schedule(now);
+        schedule.succeeded(Job::Matching, now);
+        schedule.failed(Job::Promotion, now);
+
+        schedule.hurry(Job::Matching, now);
+        schedule.hurry(Job::Promotion, now);
+        assert_eq!(due_in(&schedule, Job::Matching, now), Duration::ZERO);
+        assert!(due_in(&schedule, Job::Promotion, now) > Duration::ZERO);
+        assert_eq!("matching".parse(), Ok(Job::Matching));
+    }
+
+    #[test]
+    fn never_waits_longer_than_the_longest_backoff() {
+        assert_eq!(backoff(Duration::from_secs(60), 30), MAX_BACKOFF);
+        assert_eq!(backoff(Duration::from_secs(4), 0), Duration::from_secs(4));
+    }
+}
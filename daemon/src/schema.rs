@@ -16,6 +16,14 @@ pub struct Offer {
     )]
     pub amount: bigdecimal::BigDecimal,
     pub direction: OfferDirection,
+    pub rail: PaymentRail,
+    pub currency: String,
+    /// Units of `currency` per one DD
+    #[serde(
+        deserialize_with = "deserialize_bigdecimal",
+        serialize_with = "serialize_bigdecimal"
+    )]
+    pub price: bigdecimal::BigDecimal,
 }
 
 impl serde::Serialize for OfferId {
@@ -76,6 +84,36 @@ where
     s.serialize_str(&d.to_string())
 }
 
+/// A decimal number along with how the client spelled it,
+/// since that, and not how we would print it back, is what they signed
+#[derive(Debug, Clone)]
+pub struct SpelledDecimal {
+    pub value: bigdecimal::BigDecimal,
+    pub spelling: String,
+}
+
+impl<'de> Deserialize<'de> for SpelledDecimal {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use std::str::FromStr;
+        let spelling = String::deserialize(deserializer)?;
+        let value =
+            bigdecimal::BigDecimal::from_str(&spelling).map_err(serde::de::Error::custom)?;
+        Ok(Self { value, spelling })
+    }
+}
+
+impl serde::Serialize for SpelledDecimal {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.spelling)
+    }
+}
+
 /// Accept both quoted and bare numbers
 fn quoted_or_bare_number<'de, D>(d: D) -> Result<u128, D::Error>
 where
@@ -111,6 +149,116 @@ pub enum OfferDirection {
     FiatToDD,
 }
 
+/// How the fiat side of a deal moves; only offers on the same rail can be matched
+#[derive(
+    sqlx::Type, serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash,
+)]
+#[sqlx(type_name = "payment_rail", rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum PaymentRail {
+    /// Euro transfers between IBANs
+    Sepa,
+    /// UK transfers between sort code and account number pairs
+    FasterPayments,
+    /// Brazilian instant payments addressed by a Pix key
+    Pix,
+    /// Indian instant payments addressed by a virtual payment address
+    Upi,
+    /// Card-to-card transfers within our partner bank
+    #[serde(rename = "c2c")]
+    #[sqlx(rename = "c2c")]
+    C2C,
+}
+
+impl PaymentRail {
+    /// Spell the `account` the way both the offers and the bank statements of this rail are compared in
+    pub fn normalize_account(&self, account: &str) -> String {
+        match self {
+            Self::Sepa => account
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect::<String>()
+                .to_ascii_uppercase(),
+            Self::FasterPayments => account
+                .chars()
+                .filter(|c| !c.is_whitespace() && *c != '-')
+                .collect(),
+            Self::Upi => account.trim().to_ascii_lowercase(),
+            Self::Pix | Self::C2C => account.trim().to_string(),
+        }
+    }
+
+    /// Check that a normalized `account` can be paid over this rail at all
+    pub fn ensure_valid_account(&self, account: &str) -> Result<(), String> {
+        let is_digits =
+            |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+        let valid = match self {
+            Self::Sepa => is_iban(account),
+            // Six digits of sort code followed by eight of account number
+            Self::FasterPayments => is_digits(account, 14),
+            // Tax id of a person or a company, a phone, an email, or a random key
+            Self::Pix => {
+                is_digits(account, 11)
+                    || is_digits(account, 14)
+                    || account
+                        .strip_prefix("+55")
+                        .is_some_and(|phone| is_digits(phone, 10) || is_digits(phone, 11))
+                    || account
+                        .split_once('@')
+                        .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.'))
+                    || is_uuid(account)
+            }
+            Self::Upi => account.split_once('@').is_some_and(|(handle, provider)| {
+                (2..=256).contains(&handle.len())
+                    && handle
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+                    && (2..=64).contains(&provider.len())
+                    && provider.bytes().all(|b| b.is_ascii_alphabetic())
+            }),
+            Self::C2C => !account.is_empty() && account.bytes().all(|b| b.is_ascii_digit()),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("`{account}` is not an account on {self:?}"))
+        }
+    }
+}
+
+/// ISO 13616: a country code, two check digits, and up to thirty letters and digits
+/// that, rotated and read in base 36, leave one modulo 97
+fn is_iban(account: &str) -> bool {
+    if !(15..=34).contains(&account.len())
+        || !account
+            .bytes()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+    {
+        return false;
+    }
+    let (head, tail) = account.split_at(4);
+    if !head[..2].bytes().all(|b| b.is_ascii_uppercase())
+        || !head[2..].bytes().all(|b| b.is_ascii_digit())
+    {
+        return false;
+    }
+    let remainder = tail.chars().chain(head.chars()).fold(0, |acc, c| {
+        let value = c.to_digit(36).unwrap_or_default();
+        let shift = if value < 10 { 10 } else { 100 };
+        (acc * shift + value) % 97
+    });
+    remainder == 1
+}
+
+/// `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` in hex
+fn is_uuid(s: &str) -> bool {
+    s.len() == 36
+        && s.char_indices().all(|(n, c)| match n {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct OfferRequest {
@@ -118,14 +266,46 @@ pub struct OfferRequest {
     pub amount: u128,
     pub bank_account: String,
     pub public_key: String,
+    pub rail: PaymentRail,
+    /// ISO 4217 code of the fiat side of the deal
+    pub currency: String,
+    /// Units of `currency` per one DD: the most a buyer pays, or the least a seller takes
+    pub price: SpelledDecimal,
     pub signature: String,
 }
 
 impl OfferRequest {
+    /// Reject the offers we could never match with anything
+    pub fn ensure_well_formed(&self) -> Result<(), String> {
+        self.rail
+            .ensure_valid_account(&self.rail.normalize_account(&self.bank_account))?;
+        if self.currency.len() != 3 || !self.currency.bytes().all(|b| b.is_ascii_uppercase()) {
+            return Err(format!(
+                "expected an ISO 4217 currency code, got `{}`",
+                self.currency
+            ));
+        }
+        if self.price.value <= bigdecimal::BigDecimal::from(0) {
+            return Err(format!(
+                "expected a positive price, got {}",
+                self.price.spelling
+            ));
+        }
+        Ok(())
+    }
+
     pub fn ensure_authentic(&self) -> Result<(), String> {
+        // Values sorted in lexicographical order of their names in the request,
+        // each as the client sent it
+        let rail = serde_json::to_value(self.rail).map_err(|e| e.to_string())?;
         let cleartext = format!(
-            "{}\n{}\n{}",
-            self.amount, self.bank_account, self.public_key
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            self.amount,
+            self.bank_account,
+            self.currency,
+            self.price.spelling,
+            self.public_key,
+            rail.as_str().unwrap_or_default(),
         );
         let cleartext = cleartext.as_bytes();
 
@@ -153,3 +333,60 @@ impl OfferRequest {
             .map_err(|e| e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    #[test]
+    fn checks_the_signature_of_the_fields_as_sent() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = bs58::encode(key.verifying_key().as_bytes()).into_string();
+        let cleartext = format!("5000000\n987654321\nUSD\n2.0\n{public_key}\nc2c");
+        let signature = bs58::encode(key.sign(cleartext.as_bytes()).to_bytes()).into_string();
+        let request = |price: &str| -> OfferRequest {
+            serde_json::from_value(serde_json::json!({
+                "amount": "5000000",
+                "bankAccount": "987654321",
+                "publicKey": public_key,
+                "rail": "c2c",
+                "currency": "USD",
+                "price": price,
+                "signature": signature,
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(request("2.0").ensure_authentic(), Ok(()));
+        // The same price, but not what was signed:
+        assert!(request("2").ensure_authentic().is_err());
+    }
+
+    #[test]
+    fn accepts_only_accounts_of_the_rail() {
+        let cases = [
+            (PaymentRail::Sepa, "DE89 3704 0044 0532 0130 00", true),
+            (PaymentRail::Sepa, "DE88 3704 0044 0532 0130 00", false),
+            (PaymentRail::FasterPayments, "20-00-00 55779911", true),
+            (PaymentRail::FasterPayments, "20-00-00 5577991", false),
+            (PaymentRail::Pix, "12345678901", true),
+            (PaymentRail::Pix, "+5511912345678", true),
+            (
+                PaymentRail::Pix,
+                "123e4567-e89b-12d3-a456-426614174000",
+                true,
+            ),
+            (PaymentRail::Pix, "not a key", false),
+            (PaymentRail::Upi, "Someone@okaxis", true),
+            (PaymentRail::Upi, "someone@ok-axis", false),
+            (PaymentRail::C2C, "987654321", true),
+            (PaymentRail::C2C, "DE89370400440532013000", false),
+        ];
+        for (rail, account, valid) in cases {
+            let result = rail.ensure_valid_account(&rail.normalize_account(account));
+            assert_eq!(result.is_ok(), valid, "{rail:?} {account}");
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Transactions the treasurer signs
+//!
+//! We cannot link the program crate here (see `Cargo.toml`),
+//! so the instructions are laid out by hand the way Anchor expects them:
+//! eight bytes of discriminator followed by the Borsh-encoded arguments,
+//! with the accounts in the order of the fields of their `#[derive(Accounts)]` struct.
+use solana_sdk::instruction::{AccountMeta, Instruction};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use solana_sdk::system_program;
+use solana_sdk::transaction::Transaction;
+
+use crate::chain::ChainClient;
+use crate::conf::Conf;
+
+/// What every treasurer instruction needs to know about the deployment
+struct Treasury<'a> {
+    program_id: &'a Pubkey,
+    token_program: &'a Pubkey,
+    treasurer: &'a Keypair,
+}
+
+impl<'a> Treasury<'a> {
+    fn from_conf(conf: &'a Conf) -> Result<Self, Box<dyn std::error::Error>> {
+        let (Some(program_id), Some(token_program), Some(treasurer)) = (
+            conf.program_id(),
+            conf.token_program(),
+            conf.treasurer_secret_key(),
+        ) else {
+            return Err("envs are missing".into());
+        };
+        Ok(Self {
+            program_id,
+            token_program,
+            treasurer,
+        })
+    }
+
+    fn address(&self, seeds: &[&[u8]]) -> Pubkey {
+        Pubkey::find_program_address(seeds, self.program_id).0
+    }
+
+    /// The accounts every treasurer instruction starts with: the program state and the treasurer
+    fn preamble(&self) -> [AccountMeta; 2] {
+        [
+            AccountMeta::new_readonly(self.address(&[]), false),
+            AccountMeta::new(self.treasurer.pubkey(), true),
+        ]
+    }
+
+    /// The accounts every treasurer instruction ends with: the DD mint and its token program
+    fn postamble(&self) -> [AccountMeta; 2] {
+        [
+            AccountMeta::new_readonly(self.address(&[b"mint/dd"]), false),
+            AccountMeta::new_readonly(*self.token_program, false),
+        ]
+    }
+
+    fn instruction(&self, name: &str, args: &[&[u8]], accounts: Vec<AccountMeta>) -> Instruction {
+        let mut data = discriminator(name).to_vec();
+        for arg in args {
+            data.extend_from_slice(arg);
+        }
+        Instruction {
+            program_id: *self.program_id,
+            accounts,
+            data,
+        }
+    }
+
+    /// Mirror of `araza::ReleaseFunds`, shared by `release_funds` and `release_portion`
+    fn release_accounts(
+        &self,
+        user: &Pubkey,
+        buyer: &Pubkey,
+        beneficiary: &Pubkey,
+    ) -> Vec<AccountMeta> {
+        let mut accounts = self.preamble().to_vec();
+        accounts.extend([
+            AccountMeta::new_readonly(*user, false),
+            AccountMeta::new(self.address(&[b"escrow", user.as_ref()]), false),
+            AccountMeta::new_readonly(*buyer, false),
+            AccountMeta::new(*beneficiary, false),
+            AccountMeta::new(self.address(&[b"stats", user.as_ref()]), false),
+            AccountMeta::new(self.address(&[b"stats", buyer.as_ref()]), false),
+        ]);
+        accounts.extend(self.postamble());
+        // To open the stats of a buyer who has none yet:
+        accounts.push(AccountMeta::new_readonly(system_program::ID, false));
+        accounts
+    }
+
+    /// Mirror of `araza::ReturnBond`
+    fn return_bond_accounts(&self, user: &Pubkey, refund_account: &Pubkey) -> Vec<AccountMeta> {
+        let mut accounts = self.preamble().to_vec();
+        accounts.extend([
+            AccountMeta::new_readonly(*user, false),
+            AccountMeta::new(self.address(&[b"bond", user.as_ref()]), false),
+            AccountMeta::new(*refund_account, false),
+        ]);
+        accounts.extend(self.postamble());
+        accounts
+    }
+
+    /// Mirror of `araza::SlashBond`
+    fn slash_bond_accounts(
+        &self,
+        user: &Pubkey,
+        seller: &Pubkey,
+        beneficiary: &Pubkey,
+    ) -> Vec<AccountMeta> {
+        let mut accounts = self.preamble().to_vec();
+        accounts.extend([
+            AccountMeta::new_readonly(*user, false),
+            AccountMeta::new_readonly(*seller, false),
+            AccountMeta::new(self.address(&[b"bond", user.as_ref()]), false),
+            AccountMeta::new(*beneficiary, false),
+            AccountMeta::new(self.address(&[b"stats", user.as_ref()]), false),
+        ]);
+        accounts.extend(self.postamble());
+        accounts
+    }
+
+    /// Mirror of `araza::ResolveDispute`
+    fn resolve_dispute_accounts(
+        &self,
+        user: &Pubkey,
+        buyer: &Pubkey,
+        refund_account: &Pubkey,
+        beneficiary: &Pubkey,
+    ) -> Vec<AccountMeta> {
+        let mut accounts = self.preamble().to_vec();
+        accounts.extend([
+            AccountMeta::new_readonly(*user, false),
+            AccountMeta::new_readonly(*buyer, false),
+            AccountMeta::new(self.address(&[b"escrow", user.as_ref()]), false),
+            AccountMeta::new(*refund_account, false),
+            AccountMeta::new(*beneficiary, false),
+            AccountMeta::new(self.address(&[b"stats", user.as_ref()]), false),
+            AccountMeta::new(self.address(&[b"stats", buyer.as_ref()]), false),
+        ]);
+        accounts.extend(self.postamble());
+        // To open the stats of a buyer who has none yet:
+        accounts.push(AccountMeta::new_readonly(system_program::ID, false));
+        accounts
+    }
+
+    async fn sign(
+        &self,
+        client: &impl ChainClient,
+        instruction: Instruction,
+    ) -> Result<Transaction, Box<dyn std::error::Error>> {
+        let blockhash = client.latest_blockhash().await?;
+        Ok(Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&self.treasurer.pubkey()),
+            &[self.treasurer],
+            blockhash,
+        ))
+    }
+}
+
+/// The first eight bytes of the hash Anchor tells its instructions apart by
+pub fn discriminator(name: &str) -> [u8; 8] {
+    let hash = solana_sdk::hash::hash(format!("global:{name}").as_bytes());
+    hash.to_bytes()[..8].try_into().unwrap()
+}
+
+/// Sign, but do not send, the release of `amount` out of the escrow of `user`
+/// to the `beneficiary` account of the `buyer`, committing to the fiat side of the deal on chain
+pub async fn sign_release_portion(
+    conf: &Conf,
+    client: &impl ChainClient,
+    user: &Pubkey,
+    buyer: &Pubkey,
+    beneficiary: &Pubkey,
+    amount: u64,
+    fiat_commitment: [u8; 32],
+) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let treasury = Treasury::from_conf(conf)?;
+    let instruction = treasury.instruction(
+        "release_portion",
+        &[&amount.to_le_bytes(), &fiat_commitment],
+        treasury.release_accounts(user, buyer, beneficiary),
+    );
+    treasury.sign(client, instruction).await
+}
+
+/// Give the bond of `user` back to their `refund_account`
+pub async fn return_bond(
+    conf: &Conf,
+    client: &impl ChainClient,
+    user: &Pubkey,
+    refund_account: &Pubkey,
+) -> Result<Signature, Box<dyn std::error::Error>> {
+    let treasury = Treasury::from_conf(conf)?;
+    let instruction = treasury.instruction(
+        "return_bond",
+        &[],
+        treasury.return_bond_accounts(user, refund_account),
+    );
+    let transaction = treasury.sign(client, instruction).await?;
+    Ok(client.send_and_confirm_transaction(&transaction).await?)
+}
+
+/// Sign, but do not send, the handover of `amount` out of the bond of `user`
+/// to the `beneficiary` account of the `seller`
+pub async fn sign_slash_bond(
+    conf: &Conf,
+    client: &impl ChainClient,
+    user: &Pubkey,
+    seller: &Pubkey,
+    beneficiary: &Pubkey,
+    amount: u64,
+) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let treasury = Treasury::from_conf(conf)?;
+    let instruction = treasury.instruction(
+        "slash_bond",
+        &[&amount.to_le_bytes()],
+        treasury.slash_bond_accounts(user, seller, beneficiary),
+    );
+    treasury.sign(client, instruction).await
+}
+
+/// Hand `amount` out of the bond of `user` over to the `beneficiary` account of the `seller`
+pub async fn slash_bond(
+    conf: &Conf,
+    client: &impl ChainClient,
+    user: &Pubkey,
+    seller: &Pubkey,
+    beneficiary: &Pubkey,
+    amount: u64,
+) -> Result<Signature, Box<dyn std::error::Error>> {
+    let transaction = sign_slash_bond(conf, client, user, seller, beneficiary, amount).await?;
+    Ok(client.send_and_confirm_transaction(&transaction).await?)
+}
+
+/// Sign, but do not send, the payment of the `amount` a disputed deal is for out of the escrow of `user`,
+/// to the DD account of the `buyer` if `buyer_wins`, and back to the one of `user` otherwise
+pub async fn sign_resolve_dispute(
+    conf: &Conf,
+    client: &impl ChainClient,
+    user: &Pubkey,
+    buyer: &Pubkey,
+    amount: u64,
+    buyer_wins: bool,
+) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let treasury = Treasury::from_conf(conf)?;
+    let (Some(associated_token_program), Some(dd_mint)) =
+        (conf.associated_token_program(), conf.dd_mint())
+    else {
+        return Err("envs are missing".into());
+    };
+    let [refund_account, beneficiary] = [user, buyer].map(|wallet| {
+        Pubkey::find_program_address(
+            &[
+                wallet.as_ref(),
+                treasury.token_program.as_ref(),
+                dd_mint.as_ref(),
+            ],
+            associated_token_program,
+        )
+        .0
+    });
+    let instruction = treasury.instruction(
+        "resolve_dispute",
+        &[&amount.to_le_bytes(), &[u8::from(buyer_wins)]],
+        treasury.resolve_dispute_accounts(user, buyer, &refund_account, &beneficiary),
+    );
+    treasury.sign(client, instruction).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::discriminator;
+
+    #[test]
+    fn tells_instructions_apart_like_anchor() {
+        // As generated by `#[program]` into `araza::instruction`
+        assert_eq!(
+            discriminator("release_portion"),
+            [121, 114, 108, 128, 161, 131, 236, 169]
+        );
+        assert_eq!(
+            discriminator("return_bond"),
+            [148, 66, 62, 8, 206, 184, 10, 73]
+        );
+        assert_eq!(
+            discriminator("slash_bond"),
+            [143, 246, 51, 243, 88, 198, 217, 48]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! Websocket subscriptions to the escrows of the preoffers
+//!
+//! Rather than asking for the balance of every escrow on every tick,
+//! we subscribe to the escrows that still wait for a deposit and promote
+//! their preoffers as soon as the deposit shows up.
+//! The subscriptions are brought in line with the preoffers every few seconds,
+//! and the cron still looks at all the escrows once in a while in case we miss a notification.
+//! Unlike the cron, this runs on every replica: a preoffer is only ever promoted by one of them.
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::stream::{BoxStream, SelectAll, StreamExt};
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
+use solana_client::nonblocking::pubsub_client::PubsubClient;
+use solana_client::rpc_config::RpcAccountInfoConfig;
+use solana_sdk::pubkey::Pubkey;
+use sqlx::PgPool;
+
+use crate::chain::token_amount;
+use crate::conf::Conf;
+use crate::cron::{fetch_preoffers, promote_deposited};
+
+/// How often to pick up new preoffers and drop the subscriptions of the gone ones
+const SUBSCRIPTION_REFRESH_INTERVAL: Duration = Duration::from_secs(4);
+
+/// How long to wait before connecting again after the websocket has failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+type Unsubscribe = Box<dyn FnOnce() -> futures_util::future::BoxFuture<'static, ()> + Send>;
+
+/// Keep watching the escrows of the preoffers, reconnecting whenever the websocket fails
+pub async fn run(conf: Arc<Conf>, pool: PgPool) {
+    loop {
+        match PubsubClient::new(conf.ws_url()).await {
+            Ok(pubsub) => {
+                if let Err(e) = watch(&conf, &pool, &pubsub).await {
+                    tracing::error!("While watching escrows: {:?}", e);
+                }
+                pubsub.shutdown().await.ok();
+            }
+            Err(e) => tracing::error!("Could not connect to {}: {:?}", conf.ws_url(), e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Follow the escrows over the single connection of `pubsub` until it fails
+async fn watch(
+    conf: &Conf,
+    pool: &PgPool,
+    pubsub: &PubsubClient,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(program_id) = conf.program_id() else {
+        return Err("PROGRAM_ID is not set".into());
+    };
+    let config = RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        commitment: Some(conf.commitment()),
+        ..Default::default()
+    };
+
+    // Notifications of all the escrows, each tagged with the author it belongs to:
+    let mut notifications: SelectAll<BoxStream<'_, (Pubkey, UiAccount)>> = SelectAll::new();
+    let mut subscriptions: HashMap<Pubkey, Unsubscribe> = HashMap::new();
+    let mut refresh = tokio::time::interval(SUBSCRIPTION_REFRESH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = refresh.tick() => {
+                let authors = fetch_preoffers(pool, None)
+                    .await?
+                    .iter()
+                    .filter_map(|preoffer| Pubkey::from_str(&preoffer.public_key).ok())
+                    .collect::<std::collections::HashSet<_>>();
+
+                let gone = subscriptions
+                    .keys()
+                    .filter(|author| !authors.contains(author))
+                    .copied()
+                    .collect::<Vec<_>>();
+                for author in gone {
+                    if let Some(unsubscribe) = subscriptions.remove(&author) {
+                        unsubscribe().await;
+                    }
+                }
+
+                for author in authors {
+                    if subscriptions.contains_key(&author) {
+                        continue;
+                    }
+                    let (escrow, _) =
+                        Pubkey::find_program_address(&[b"escrow", author.as_ref()], program_id);
+                    let (stream, unsubscribe) =
+                        pubsub.account_subscribe(&escrow, Some(config.clone())).await?;
+                    notifications.push(stream.map(move |update| (author, update.value)).boxed());
+                    subscriptions.insert(author, unsubscribe);
+                }
+            }
+            Some((author, account)) = notifications.next(), if !notifications.is_empty() => {
+                let Some(balance) = account.data.decode().as_deref().and_then(token_amount) else {
+                    continue;
+                };
+                if let Err(e) = promote_arrived(pool, &author, balance).await {
+                    tracing::error!("While promoting the preoffers of {author}: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Promote the preoffers of `author`, whose escrow now holds `balance`
+async fn promote_arrived(
+    pool: &PgPool,
+    author: &Pubkey,
+    balance: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let preoffers = fetch_preoffers(pool, Some(&author.to_string())).await?;
+    let promoted = promote_deposited(pool, preoffers.into_iter().map(|p| (p, balance))).await?;
+    if promoted > 0 {
+        tracing::info!("Promoted {promoted} preoffers of {author} as their deposit arrived");
+    }
+    Ok(())
+}
@@ -1,14 +1,29 @@
 use core::mem::size_of;
 
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program::invoke;
+use anchor_lang::system_program::{create_account, transfer, CreateAccount, Transfer};
+use anchor_lang::Discriminator;
 use anchor_spl::{
     associated_token::AssociatedToken,
+    token_2022::{
+        initialize_mint2,
+        spl_token_2022::{
+            extension::{confidential_transfer, ExtensionType},
+            proof::ProofLocation,
+            solana_zk_token_sdk::zk_token_elgamal::pod::{AeCiphertext, ElGamalPubkey},
+        },
+        InitializeMint2, Token2022,
+    },
     token_interface::{
         burn, close_account, mint_to, transfer_checked, Burn, CloseAccount, Mint, MintTo,
         TokenAccount, TokenInterface, TransferChecked,
     },
 };
 
+pub mod shielded;
+pub mod verifying_key;
+
 declare_id!("AnymAL5sjUsgFVFabV2bs1cbMKVT45dcGHCaCUJB4RDg");
 
 const VERSION: u8 = 1;
@@ -61,6 +76,214 @@ pub mod araza {
         Ok(())
     }
 
+    /// Make DD a Token-2022 mint with confidential transfers,
+    /// so that users can keep their balances private
+    ///
+    /// Every confidential transfer is also encrypted to the auditor key,
+    /// so the treasurer can still account for all the DD in motion,
+    /// while the escrows keep their balances in the open.
+    ///
+    /// Has to be called before `configure`, which would otherwise mint plain DD.
+    /// Token-2022 only sets the extension up on a fresh mint,
+    /// so a deployment whose DD is already minted has to be redeployed to make it confidential.
+    #[access_control(is_privileged(&ctx.accounts.signer))]
+    pub fn configure_confidential(
+        ctx: Context<ConfigureConfidential>,
+        auditor_elgamal_pubkey: [u8; 32],
+    ) -> Result<()> {
+        // Leave the DD that is already out there alone:
+        if !ctx.accounts.dd_mint.data_is_empty() {
+            return Err(Error::AlreadyMinted.into());
+        }
+
+        // Record the key the treasurer audits with:
+        ctx.accounts.state.auditor_elgamal_pubkey = auditor_elgamal_pubkey;
+
+        // The mint has to have room for the extension from the start:
+        let space = ExtensionType::try_calculate_account_len::<
+            anchor_spl::token_2022::spl_token_2022::state::Mint,
+        >(&[ExtensionType::ConfidentialTransferMint])?;
+        create_account(
+            CpiContext::new_with_signer(
+                ctx.accounts.system_program.to_account_info(),
+                CreateAccount {
+                    from: ctx.accounts.signer.to_account_info(),
+                    to: ctx.accounts.dd_mint.to_account_info(),
+                },
+                &[&[b"mint/dd", &[ctx.bumps.dd_mint]]],
+            ),
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            ctx.accounts.token_program.key,
+        )?;
+
+        // And the extension has to be set up before the mint itself:
+        invoke(
+            &confidential_transfer::instruction::initialize_mint(
+                ctx.accounts.token_program.key,
+                ctx.accounts.dd_mint.key,
+                Some(ctx.accounts.dd_mint.key()),
+                true,
+                Some(ElGamalPubkey(auditor_elgamal_pubkey)),
+            )?,
+            &[ctx.accounts.dd_mint.to_account_info()],
+        )?;
+
+        initialize_mint2(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                InitializeMint2 {
+                    mint: ctx.accounts.dd_mint.to_account_info(),
+                },
+            ),
+            6,
+            ctx.accounts.dd_mint.key,
+            None,
+        )?;
+
+        Ok(())
+    }
+
+    /// Bring the state of a deployment made before confidential DD to the current layout
+    ///
+    /// Its DD stays plain, so the auditor key is left empty.
+    /// Does nothing if the state is already in the current layout.
+    #[access_control(is_privileged(&ctx.accounts.signer))]
+    pub fn migrate_state(ctx: Context<MigrateState>) -> Result<()> {
+        let state = ctx.accounts.state.to_account_info();
+        let space = 8 + size_of::<ProgramState>();
+        if state.data_len() >= space {
+            return Ok(());
+        }
+        let migrated = ProgramStateV0::migrate(&state.try_borrow_data()?)?;
+
+        // Keep the state rent exempt once it is grown:
+        let rent = Rent::get()?
+            .minimum_balance(space)
+            .saturating_sub(state.lamports());
+        if rent > 0 {
+            transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.signer.to_account_info(),
+                        to: state.clone(),
+                    },
+                ),
+                rent,
+            )?;
+        }
+        state.realloc(space, true)?;
+        migrated.try_serialize(&mut &mut state.try_borrow_mut_data()?[..])?;
+
+        Ok(())
+    }
+
+    /// Open the shielded pool, where DD moves between notes nobody but their owners can link
+    ///
+    /// The pool starts out as an empty tree of commitments,
+    /// next to the vault that holds all the DD in it.
+    #[access_control(is_privileged(&ctx.accounts.signer))]
+    pub fn configure_shielded(ctx: Context<ConfigureShielded>) -> Result<()> {
+        ctx.accounts.pool.open()
+    }
+
+    /// Move `amount` of DD from the user into a new note of the shielded pool
+    ///
+    /// The note is committed to as `H(amount, hiding)`, where the user keeps
+    /// who owns it and its blinding in `hiding`, so only the amount shows.
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn shield(ctx: Context<Shield>, amount: u64, hiding: [u8; 32]) -> Result<()> {
+        let commitment = shielded::hash_pair(&shielded::field_of(amount), &hiding)?;
+        ctx.accounts.pool.insert(commitment)?;
+
+        transfer_checked(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    authority: ctx.accounts.user.to_account_info(),
+                    mint: ctx.accounts.dd_mint.to_account_info(),
+                    from: ctx.accounts.from_account.to_account_info(),
+                    to: ctx.accounts.vault.to_account_info(),
+                },
+            ),
+            amount,
+            ctx.accounts.dd_mint.decimals,
+        )?;
+
+        Ok(())
+    }
+
+    /// Spend a note of the shielded pool into two new ones,
+    /// sending `amount` of DD out of the pool to the `to_account`
+    ///
+    /// The proof shows that the spender owns a note under a recent `root`,
+    /// which `nullifier` marks as spent, and which is worth the new `commitments` and `amount`.
+    /// It also binds the `to_account`, so nobody can redirect the DD on the way.
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn unshield(
+        ctx: Context<Unshield>,
+        amount: u64,
+        proof: [u8; 256],
+        root: [u8; 32],
+        nullifier: [u8; 32],
+        commitments: [[u8; 32]; 2],
+    ) -> Result<()> {
+        let recipient = shielded::recipient_of(&ctx.accounts.to_account.key());
+        ctx.accounts.pool.spend(
+            &proof,
+            [
+                root,
+                nullifier,
+                commitments[0],
+                commitments[1],
+                shielded::field_of(amount),
+                recipient,
+            ],
+        )?;
+
+        transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    authority: ctx.accounts.vault.to_account_info(),
+                    mint: ctx.accounts.dd_mint.to_account_info(),
+                    from: ctx.accounts.vault.to_account_info(),
+                    to: ctx.accounts.to_account.to_account_info(),
+                },
+                &[&[b"vault/shielded", &[ctx.bumps.vault]]],
+            ),
+            amount,
+            ctx.accounts.dd_mint.decimals,
+        )?;
+
+        Ok(())
+    }
+
+    /// Spend a note of the shielded pool into two new ones, without any DD leaving it
+    ///
+    /// Paying someone is making one of the new notes theirs, and the other one the change.
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn private_transfer(
+        ctx: Context<PrivateTransfer>,
+        proof: [u8; 256],
+        root: [u8; 32],
+        nullifier: [u8; 32],
+        commitments: [[u8; 32]; 2],
+    ) -> Result<()> {
+        ctx.accounts.pool.spend(
+            &proof,
+            [
+                root,
+                nullifier,
+                commitments[0],
+                commitments[1],
+                [0; 32],
+                [0; 32],
+            ],
+        )
+    }
+
     /// Deposit USDC and mint Digital Dollars (DD)
     #[access_control(has_version(&ctx.accounts.state))]
     pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
@@ -130,6 +353,66 @@ pub mod araza {
         Ok(())
     }
 
+    /// Move DD from the public balance of the user to their confidential one
+    ///
+    /// The deposit lands in the pending balance,
+    /// which the user applies on their own, since only they can decrypt it.
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn deposit_confidential(ctx: Context<DepositConfidential>, amount: u64) -> Result<()> {
+        invoke(
+            &confidential_transfer::instruction::deposit(
+                ctx.accounts.token_program.key,
+                &ctx.accounts.token_account.key(),
+                &ctx.accounts.dd_mint.key(),
+                amount,
+                ctx.accounts.dd_mint.decimals,
+                ctx.accounts.user.key,
+                &[],
+            )?,
+            &[
+                ctx.accounts.token_account.to_account_info(),
+                ctx.accounts.dd_mint.to_account_info(),
+                ctx.accounts.user.to_account_info(),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Move DD from the confidential balance of the user back to their public one
+    ///
+    /// The user proves they have enough with a withdraw proof,
+    /// verified beforehand into the `proof_context` account,
+    /// and gives us their new available balance encrypted to themselves.
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn withdraw_confidential(
+        ctx: Context<WithdrawConfidential>,
+        amount: u64,
+        new_decryptable_available_balance: [u8; 36],
+    ) -> Result<()> {
+        invoke(
+            &confidential_transfer::instruction::inner_withdraw(
+                ctx.accounts.token_program.key,
+                &ctx.accounts.token_account.key(),
+                &ctx.accounts.dd_mint.key(),
+                amount,
+                ctx.accounts.dd_mint.decimals,
+                AeCiphertext(new_decryptable_available_balance),
+                ctx.accounts.user.key,
+                &[],
+                ProofLocation::ContextStateAccount(ctx.accounts.proof_context.key),
+            )?,
+            &[
+                ctx.accounts.token_account.to_account_info(),
+                ctx.accounts.dd_mint.to_account_info(),
+                ctx.accounts.proof_context.to_account_info(),
+                ctx.accounts.user.to_account_info(),
+            ],
+        )?;
+
+        Ok(())
+    }
+
     /// Offer DD for fiat exchange
     ///
     /// This will create an escrow account for the user's DD tokens,
@@ -167,49 +450,242 @@ pub mod araza {
         Ok(())
     }
 
-    /// Execute the escrow, assuming the associated deal is fully settled
+    /// Put up some DD as a bond behind a fiat offer
+    ///
+    /// A user has a single bond, which every offer tops up.
+    /// It is returned once the deals it covers are settled,
+    /// and goes to the seller if the buyer does not pay in time.
     #[access_control(has_version(&ctx.accounts.state))]
-    pub fn release_funds(ctx: Context<ReleaseFunds>) -> Result<()> {
-        // Since it's a non-native account, we need to transfer
-        // the whole balance in a separate step:
+    pub fn post_bond(ctx: Context<PostBond>, amount: u64) -> Result<()> {
         transfer_checked(
-            CpiContext::new_with_signer(
+            CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
                 TransferChecked {
-                    authority: ctx.accounts.escrow.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
                     mint: ctx.accounts.dd_mint.to_account_info(),
-                    from: ctx.accounts.escrow.to_account_info(),
-                    to: ctx.accounts.beneficiary.to_account_info(),
+                    from: ctx.accounts.from_account.to_account_info(),
+                    to: ctx.accounts.bond.to_account_info(),
                 },
-                &[&[
-                    b"escrow",
-                    ctx.accounts.user.key().as_ref(),
-                    &[ctx.bumps.escrow],
-                ]],
             ),
-            ctx.accounts.escrow.amount,
+            amount,
             ctx.accounts.dd_mint.decimals,
         )?;
 
-        // And now we can close the escrow account:
-        close_account(CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            CloseAccount {
-                authority: ctx.accounts.escrow.to_account_info(),
-                account: ctx.accounts.escrow.to_account_info(),
-                destination: ctx.accounts.beneficiary.to_account_info(),
-            },
-            &[&[
+        Ok(())
+    }
+
+    /// Give the bond back to the buyer once no deal of theirs is left for it to cover
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn return_bond(ctx: Context<ReturnBond>) -> Result<()> {
+        drain(
+            &ctx.accounts.token_program,
+            &ctx.accounts.dd_mint,
+            &ctx.accounts.bond,
+            &ctx.accounts.refund_account,
+            &[b"bond", ctx.accounts.user.key().as_ref(), &[ctx.bumps.bond]],
+        )
+    }
+
+    /// Hand the `amount` of the bond that backed one deal over to the seller, since the buyer never paid
+    ///
+    /// The rest of the bond stays behind the other offers of the buyer,
+    /// and it is only closed if nothing is left of it.
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn slash_bond(ctx: Context<SlashBond>, amount: u64) -> Result<()> {
+        // Walking away from a matched deal is as good as calling it off:
+        let stats = &mut ctx.accounts.user_stats;
+        stats.cancellations = stats.cancellations.saturating_add(1);
+
+        pay_out(
+            &ctx.accounts.token_program,
+            &ctx.accounts.dd_mint,
+            &ctx.accounts.bond,
+            &ctx.accounts.beneficiary,
+            amount.min(ctx.accounts.bond.amount),
+            &[b"bond", ctx.accounts.user.key().as_ref(), &[ctx.bumps.bond]],
+        )
+    }
+
+    /// Execute the escrow, assuming the associated deal is fully settled
+    ///
+    /// The `fiat_commitment` is a salted hash of the bank transactions
+    /// of both fiat legs, so that the release can be tied to them later.
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn release_funds(ctx: Context<ReleaseFunds>, fiat_commitment: [u8; 32]) -> Result<()> {
+        // Credit the deal to both counterparties.
+        // When one sells to themselves, both are the same account,
+        // and it gets counted only once:
+        let amount = ctx.accounts.escrow.amount;
+        ctx.accounts.user_stats.record_deal(amount);
+        ctx.accounts.buyer_stats.record_deal(amount);
+
+        emit!(Settled {
+            seller: ctx.accounts.user.key(),
+            buyer: ctx.accounts.buyer.key(),
+            amount,
+            fiat_commitment,
+        });
+
+        drain(
+            &ctx.accounts.token_program,
+            &ctx.accounts.dd_mint,
+            &ctx.accounts.escrow,
+            &ctx.accounts.beneficiary,
+            &[
                 b"escrow",
                 ctx.accounts.user.key().as_ref(),
                 &[ctx.bumps.escrow],
-            ]],
-        ))?;
+            ],
+        )
+    }
 
-        Ok(())
+    /// Execute a portion of the escrow, for a deal that filled only a part of the offer
+    ///
+    /// The escrow is closed once nothing is left in it.
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn release_portion(
+        ctx: Context<ReleaseFunds>,
+        amount: u64,
+        fiat_commitment: [u8; 32],
+    ) -> Result<()> {
+        ctx.accounts.user_stats.record_deal(amount);
+        ctx.accounts.buyer_stats.record_deal(amount);
+
+        emit!(Settled {
+            seller: ctx.accounts.user.key(),
+            buyer: ctx.accounts.buyer.key(),
+            amount,
+            fiat_commitment,
+        });
+
+        pay_out(
+            &ctx.accounts.token_program,
+            &ctx.accounts.dd_mint,
+            &ctx.accounts.escrow,
+            &ctx.accounts.beneficiary,
+            amount,
+            &[
+                b"escrow",
+                ctx.accounts.user.key().as_ref(),
+                &[ctx.bumps.escrow],
+            ],
+        )
+    }
+
+    /// Give the escrowed DD back to the user who offered them,
+    /// calling off the deal before it is settled
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn cancel(ctx: Context<Cancel>) -> Result<()> {
+        let stats = &mut ctx.accounts.user_stats;
+        stats.cancellations = stats.cancellations.saturating_add(1);
+
+        drain(
+            &ctx.accounts.token_program,
+            &ctx.accounts.dd_mint,
+            &ctx.accounts.escrow,
+            &ctx.accounts.refund_account,
+            &[
+                b"escrow",
+                ctx.accounts.user.key().as_ref(),
+                &[ctx.bumps.escrow],
+            ],
+        )
+    }
+
+    /// Settle a disputed deal in favor of one of the counterparties
+    ///
+    /// The `amount` of the escrow the deal is for goes to the buyer if `buyer_wins`,
+    /// and back to the seller otherwise, leaving the rest of the escrow to the other deals;
+    /// the other side has the dispute counted against them.
+    #[access_control(has_version(&ctx.accounts.state))]
+    pub fn resolve_dispute(
+        ctx: Context<ResolveDispute>,
+        amount: u64,
+        buyer_wins: bool,
+    ) -> Result<()> {
+        let (loser, to) = if buyer_wins {
+            (&mut ctx.accounts.user_stats, &ctx.accounts.beneficiary)
+        } else {
+            (&mut ctx.accounts.buyer_stats, &ctx.accounts.refund_account)
+        };
+        loser.disputes_lost = loser.disputes_lost.saturating_add(1);
+
+        pay_out(
+            &ctx.accounts.token_program,
+            &ctx.accounts.dd_mint,
+            &ctx.accounts.escrow,
+            to,
+            amount,
+            &[
+                b"escrow",
+                ctx.accounts.user.key().as_ref(),
+                &[ctx.bumps.escrow],
+            ],
+        )
     }
 }
 
+/// Move the whole balance of a self-owned token account `from` to `to`, then close `from`.
+///
+/// The `seeds` are those of `from`, so that it can sign for itself.
+fn drain<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    dd_mint: &InterfaceAccount<'info, Mint>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    seeds: &[&[u8]],
+) -> Result<()> {
+    pay_out(token_program, dd_mint, from, to, from.amount, seeds)
+}
+
+/// Move `amount` out of a self-owned token account `from` to `to`,
+/// and close `from` if that was all it had.
+///
+/// The `seeds` are those of `from`, so that it can sign for itself.
+fn pay_out<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    dd_mint: &InterfaceAccount<'info, Mint>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    amount: u64,
+    seeds: &[&[u8]],
+) -> Result<()> {
+    if amount > from.amount {
+        return Err(Error::InsufficientFunds.into());
+    }
+
+    // Since it's a non-native account, we need to transfer
+    // the balance in a separate step:
+    transfer_checked(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            TransferChecked {
+                authority: from.to_account_info(),
+                mint: dd_mint.to_account_info(),
+                from: from.to_account_info(),
+                to: to.to_account_info(),
+            },
+            &[seeds],
+        ),
+        amount,
+        dd_mint.decimals,
+    )?;
+    if amount < from.amount {
+        return Ok(());
+    }
+
+    // And now that it's empty, we can close the account:
+    close_account(CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        CloseAccount {
+            authority: from.to_account_info(),
+            account: from.to_account_info(),
+            destination: to.to_account_info(),
+        },
+        &[seeds],
+    ))
+}
+
 #[derive(Accounts)]
 pub struct Initialize<'info> {
     /// Who made the call
@@ -278,6 +754,42 @@ pub struct Configure<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct ConfigureConfidential<'info> {
+    /// Who made the call
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    /// Internal state of the program,
+    /// which has to be migrated first if it was made before we had an auditor key
+    #[account(mut, seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    /// CHECK: The mint of the DD tokens, yet to be created here,
+    /// since Anchor cannot set up this extension on its own.
+    /// We refuse to go on if it already exists.
+    #[account(mut, seeds = [b"mint/dd"], bump)]
+    pub dd_mint: UncheckedAccount<'info>,
+
+    /// Only Token-2022 knows about confidential transfers
+    pub token_program: Program<'info, Token2022>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateState<'info> {
+    /// Who made the call, and pays for the state to grow
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    /// CHECK: Internal state of the program, in whichever layout it was made with,
+    /// which `Account` would fail to read if it is an old one.
+    #[account(mut, seeds = [], bump, owner = crate::ID)]
+    pub state: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct Deposit<'info> {
     #[account(seeds = [], bump)]
@@ -361,6 +873,51 @@ pub struct Redeem<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct DepositConfidential<'info> {
+    #[account(seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut, token::mint = dd_mint, token::authority = user)]
+    pub token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mint::token_program = token_program,
+        seeds = [b"mint/dd"],
+        bump,
+    )]
+    pub dd_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Program<'info, Token2022>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawConfidential<'info> {
+    #[account(seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut, token::mint = dd_mint, token::authority = user)]
+    pub token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mint::token_program = token_program,
+        seeds = [b"mint/dd"],
+        bump,
+    )]
+    pub dd_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: The token program makes sure this is a verified withdraw proof
+    /// owned by the ZK Token proof program.
+    #[account()]
+    pub proof_context: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token2022>,
+}
+
 #[derive(Accounts)]
 pub struct OfferDD<'info> {
     #[account(seeds = [], bump)]
@@ -388,6 +945,16 @@ pub struct OfferDD<'info> {
     )]
     pub escrow: InterfaceAccount<'info, TokenAccount>,
 
+    /// Track record of the user, opened on their first offer
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + size_of::<UserStats>(),
+        seeds = [b"stats", user.key().as_ref()],
+        bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
     pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
 }
@@ -398,10 +965,54 @@ pub struct OfferFiat<'info> {
     pub state: Account<'info, ProgramState>,
     #[account(mut)]
     pub user: Signer<'info>,
+
+    /// Track record of the user, opened on their first offer
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + size_of::<UserStats>(),
+        seeds = [b"stats", user.key().as_ref()],
+        bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct ReleaseFunds<'info> {
+pub struct PostBond<'info> {
+    #[account(seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut, token::mint = dd_mint)]
+    pub from_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mint::token_program = token_program,
+        seeds = [b"mint/dd"],
+        bump,
+    )]
+    pub dd_mint: InterfaceAccount<'info, Mint>,
+
+    /// Opened on the first bond of the user, and topped up by the next ones
+    #[account(
+        init_if_needed,
+        payer = user,
+        token::mint = dd_mint,
+        token::authority = bond,
+        seeds = [b"bond", user.key().as_ref()],
+        bump,
+    )]
+    pub bond: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ReturnBond<'info> {
     #[account(seeds = [], bump)]
     pub state: Account<'info, ProgramState>,
 
@@ -411,21 +1022,22 @@ pub struct ReleaseFunds<'info> {
     )]
     pub treasurer: Signer<'info>,
 
-    /// CHECK: We only need the public key from it to look up the escrow account.
+    /// CHECK: We only need the public key from it to look up the bond account.
     #[account()]
     pub user: UncheckedAccount<'info>,
 
     #[account(
         mut,
         token::mint = dd_mint,
-        token::authority = escrow,
-        seeds = [b"escrow", user.key().as_ref()],
+        token::authority = bond,
+        seeds = [b"bond", user.key().as_ref()],
         bump,
     )]
-    pub escrow: InterfaceAccount<'info, TokenAccount>,
+    pub bond: InterfaceAccount<'info, TokenAccount>,
 
-    #[account(mut, token::mint = dd_mint)]
-    pub beneficiary: InterfaceAccount<'info, TokenAccount>,
+    /// Where the user gets their DD back
+    #[account(mut, token::mint = dd_mint, token::authority = user)]
+    pub refund_account: InterfaceAccount<'info, TokenAccount>,
 
     #[account(
         mint::token_program = token_program,
@@ -436,7 +1048,343 @@ pub struct ReleaseFunds<'info> {
     pub token_program: Interface<'info, TokenInterface>,
 }
 
-#[account]
+#[derive(Accounts)]
+pub struct SlashBond<'info> {
+    #[account(seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    #[account(
+        mut,
+        constraint = state.treasurer == *treasurer.key @ Error::Unauthorized
+    )]
+    pub treasurer: Signer<'info>,
+
+    /// CHECK: We only need the public key from it to look up the bond account.
+    #[account()]
+    pub user: UncheckedAccount<'info>,
+
+    /// CHECK: We only need the public key from it to check where the bond goes.
+    #[account()]
+    pub seller: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        token::mint = dd_mint,
+        token::authority = bond,
+        seeds = [b"bond", user.key().as_ref()],
+        bump,
+    )]
+    pub bond: InterfaceAccount<'info, TokenAccount>,
+
+    /// Where the seller gets the bond
+    #[account(mut, token::mint = dd_mint, token::authority = seller)]
+    pub beneficiary: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"stats", user.key().as_ref()], bump)]
+    pub user_stats: Account<'info, UserStats>,
+
+    #[account(
+        mint::token_program = token_program,
+        seeds = [b"mint/dd"],
+        bump,
+    )]
+    pub dd_mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ReleaseFunds<'info> {
+    #[account(seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    #[account(
+        mut,
+        constraint = state.treasurer == *treasurer.key @ Error::Unauthorized
+    )]
+    pub treasurer: Signer<'info>,
+
+    /// CHECK: We only need the public key from it to look up the escrow account.
+    #[account()]
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        token::mint = dd_mint,
+        token::authority = escrow,
+        seeds = [b"escrow", user.key().as_ref()],
+        bump,
+    )]
+    pub escrow: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: We only need the public key from it to look up the buyer's stats.
+    #[account()]
+    pub buyer: UncheckedAccount<'info>,
+
+    #[account(mut, token::mint = dd_mint, token::authority = buyer)]
+    pub beneficiary: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"stats", user.key().as_ref()], bump)]
+    pub user_stats: Account<'info, UserStats>,
+    /// Track record of the buyer, opened on their first deal if nothing opened it before
+    #[account(
+        init_if_needed,
+        payer = treasurer,
+        space = 8 + size_of::<UserStats>(),
+        seeds = [b"stats", buyer.key().as_ref()],
+        bump,
+    )]
+    pub buyer_stats: Account<'info, UserStats>,
+
+    #[account(
+        mint::token_program = token_program,
+        seeds = [b"mint/dd"],
+        bump,
+    )]
+    pub dd_mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Cancel<'info> {
+    #[account(seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    #[account(
+        mut,
+        constraint = state.treasurer == *treasurer.key @ Error::Unauthorized
+    )]
+    pub treasurer: Signer<'info>,
+
+    /// CHECK: We only need the public key from it to look up the escrow account.
+    #[account()]
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        token::mint = dd_mint,
+        token::authority = escrow,
+        seeds = [b"escrow", user.key().as_ref()],
+        bump,
+    )]
+    pub escrow: InterfaceAccount<'info, TokenAccount>,
+
+    /// Where the user gets their DD back
+    #[account(mut, token::mint = dd_mint, token::authority = user)]
+    pub refund_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"stats", user.key().as_ref()], bump)]
+    pub user_stats: Account<'info, UserStats>,
+
+    #[account(
+        mint::token_program = token_program,
+        seeds = [b"mint/dd"],
+        bump,
+    )]
+    pub dd_mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ResolveDispute<'info> {
+    #[account(seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    #[account(
+        mut,
+        constraint = state.treasurer == *treasurer.key @ Error::Unauthorized
+    )]
+    pub treasurer: Signer<'info>,
+
+    /// CHECK: We only need the public key from it to look up the escrow account.
+    #[account()]
+    pub user: UncheckedAccount<'info>,
+
+    /// CHECK: We only need the public key from it to look up the buyer's stats.
+    #[account()]
+    pub buyer: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        token::mint = dd_mint,
+        token::authority = escrow,
+        seeds = [b"escrow", user.key().as_ref()],
+        bump,
+    )]
+    pub escrow: InterfaceAccount<'info, TokenAccount>,
+
+    /// Where the seller gets their DD back if they win
+    #[account(mut, token::mint = dd_mint, token::authority = user)]
+    pub refund_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Where the buyer gets the DD if they win
+    #[account(mut, token::mint = dd_mint, token::authority = buyer)]
+    pub beneficiary: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"stats", user.key().as_ref()], bump)]
+    pub user_stats: Account<'info, UserStats>,
+    /// Track record of the buyer, opened on their first deal if nothing opened it before
+    #[account(
+        init_if_needed,
+        payer = treasurer,
+        space = 8 + size_of::<UserStats>(),
+        seeds = [b"stats", buyer.key().as_ref()],
+        bump,
+    )]
+    pub buyer_stats: Account<'info, UserStats>,
+
+    #[account(
+        mint::token_program = token_program,
+        seeds = [b"mint/dd"],
+        bump,
+    )]
+    pub dd_mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ConfigureShielded<'info> {
+    /// Who made the call
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    /// The tree of the commitments to the notes
+    #[account(
+        init,
+        payer = signer,
+        space = 8 + size_of::<ShieldedPool>(),
+        seeds = [b"pool/shielded"],
+        bump,
+    )]
+    pub pool: Box<Account<'info, ShieldedPool>>,
+
+    /// Where we keep the DD of all the notes
+    #[account(
+        init,
+        payer = signer,
+        token::mint = dd_mint,
+        token::authority = vault,
+        seeds = [b"vault/shielded"],
+        bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mint::token_program = token_program,
+        seeds = [b"mint/dd"],
+        bump,
+    )]
+    pub dd_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Shield<'info> {
+    #[account(seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut, token::mint = dd_mint)]
+    pub from_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"pool/shielded"], bump)]
+    pub pool: Box<Account<'info, ShieldedPool>>,
+
+    #[account(
+        mut,
+        token::mint = dd_mint,
+        seeds = [b"vault/shielded"],
+        bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mint::token_program = token_program,
+        seeds = [b"mint/dd"],
+        bump,
+    )]
+    pub dd_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64, proof: [u8; 256], root: [u8; 32], nullifier: [u8; 32])]
+pub struct Unshield<'info> {
+    #[account(seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    /// Who pays for the nullifier, who need not be the owner of the note
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut, seeds = [b"pool/shielded"], bump)]
+    pub pool: Box<Account<'info, ShieldedPool>>,
+
+    /// Which only opens once for every note, so that it cannot be spent twice
+    #[account(
+        init,
+        payer = user,
+        space = 8 + size_of::<Nullifier>(),
+        seeds = [b"nullifier", nullifier.as_ref()],
+        bump,
+    )]
+    pub spent: Account<'info, Nullifier>,
+
+    #[account(
+        mut,
+        token::mint = dd_mint,
+        seeds = [b"vault/shielded"],
+        bump,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// Where the DD leaving the pool goes, as the proof says
+    #[account(mut, token::mint = dd_mint)]
+    pub to_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mint::token_program = token_program,
+        seeds = [b"mint/dd"],
+        bump,
+    )]
+    pub dd_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(proof: [u8; 256], root: [u8; 32], nullifier: [u8; 32])]
+pub struct PrivateTransfer<'info> {
+    #[account(seeds = [], bump)]
+    pub state: Account<'info, ProgramState>,
+
+    /// Who pays for the nullifier, who need not be the owner of the note
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut, seeds = [b"pool/shielded"], bump)]
+    pub pool: Box<Account<'info, ShieldedPool>>,
+
+    /// Which only opens once for every note, so that it cannot be spent twice
+    #[account(
+        init,
+        payer = user,
+        space = 8 + size_of::<Nullifier>(),
+        seeds = [b"nullifier", nullifier.as_ref()],
+        bump,
+    )]
+    pub spent: Account<'info, Nullifier>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
 pub struct ProgramState {
     /// Version of the program
     ///
@@ -449,6 +1397,168 @@ pub struct ProgramState {
 
     /// The mint of the USDC tokens
     pub usdc_mint: Pubkey,
+
+    /// The ElGamal key every confidential DD transfer is also encrypted to,
+    /// all zeros while DD is not confidential
+    pub auditor_elgamal_pubkey: [u8; 32],
+}
+
+/// `ProgramState` as it was laid out before confidential DD, only read to migrate from
+#[derive(AnchorDeserialize)]
+struct ProgramStateV0 {
+    version: u8,
+    treasurer: Pubkey,
+    usdc_mint: Pubkey,
+}
+
+impl ProgramStateV0 {
+    /// Read the state of an old deployment from its account `data`, in the current layout
+    fn migrate(data: &[u8]) -> Result<ProgramState> {
+        let Some(mut fields) = data.strip_prefix(&ProgramState::DISCRIMINATOR[..]) else {
+            return Err(ErrorCode::AccountDiscriminatorMismatch.into());
+        };
+        let old =
+            Self::deserialize(&mut fields).map_err(|_| ErrorCode::AccountDidNotDeserialize)?;
+        Ok(ProgramState {
+            version: old.version,
+            treasurer: old.treasurer,
+            usdc_mint: old.usdc_mint,
+            auditor_elgamal_pubkey: [0; 32],
+        })
+    }
+}
+
+/// The shielded pool: an append-only Merkle tree of the commitments to its notes
+///
+/// Only the rightmost path of the tree is kept, which is all it takes to append,
+/// along with the latest roots that proofs can be made against.
+#[account]
+pub struct ShieldedPool {
+    /// Where the next commitment goes
+    pub next_index: u64,
+
+    /// Where the latest root is in `roots`
+    pub current_root_index: u64,
+
+    /// The last left node of every level that got filled
+    pub filled_subtrees: [[u8; 32]; shielded::DEPTH],
+
+    /// The root of an empty tree of every height, the empty leaf first
+    pub zeros: [[u8; 32]; shielded::DEPTH + 1],
+
+    /// The latest roots, as a ring
+    pub roots: [[u8; 32]; shielded::ROOTS],
+}
+
+impl ShieldedPool {
+    /// Start out as an empty tree, with empty leaves worth zero
+    fn open(&mut self) -> Result<()> {
+        for level in 0..shielded::DEPTH {
+            self.zeros[level + 1] = shielded::hash_pair(&self.zeros[level], &self.zeros[level])?;
+        }
+        self.filled_subtrees
+            .copy_from_slice(&self.zeros[..shielded::DEPTH]);
+        self.roots[0] = self.zeros[shielded::DEPTH];
+
+        Ok(())
+    }
+
+    /// Append `commitment` to the tree, and remember the new root
+    fn insert(&mut self, commitment: [u8; 32]) -> Result<()> {
+        if self.next_index >> shielded::DEPTH != 0 {
+            return Err(Error::PoolFull.into());
+        }
+
+        let mut node = commitment;
+        for level in 0..shielded::DEPTH {
+            node = if self.next_index >> level & 1 == 0 {
+                self.filled_subtrees[level] = node;
+                shielded::hash_pair(&node, &self.zeros[level])?
+            } else {
+                shielded::hash_pair(&self.filled_subtrees[level], &node)?
+            };
+        }
+        self.current_root_index = (self.current_root_index + 1) % shielded::ROOTS as u64;
+        self.roots[self.current_root_index as usize] = node;
+
+        emit!(NoteCommitted {
+            commitment,
+            index: self.next_index,
+        });
+        self.next_index += 1;
+
+        Ok(())
+    }
+
+    /// Whether `root` is one of the latest roots of the tree
+    fn is_known_root(&self, root: &[u8; 32]) -> bool {
+        // Past an empty slot, no root was remembered yet:
+        *root != [0; 32] && self.roots.contains(root)
+    }
+
+    /// Check the proof of a spend with its public `inputs`, and append its new notes
+    ///
+    /// The inputs are the root, the nullifier, the two new commitments,
+    /// then the amount and the recipient of the DD that leaves the pool.
+    fn spend(&mut self, proof: &[u8; 256], inputs: [[u8; 32]; 6]) -> Result<()> {
+        let [root, _, first, second, _, _] = inputs;
+        if !self.is_known_root(&root) {
+            return Err(Error::UnknownRoot.into());
+        }
+        if !shielded::verify(proof, &inputs)? {
+            return Err(Error::InvalidProof.into());
+        }
+
+        self.insert(first)?;
+        self.insert(second)
+    }
+}
+
+/// The mark of a spent note of the shielded pool, at the address of its nullifier
+#[account]
+pub struct Nullifier {}
+
+/// Track record of a wallet, so that the daemon can weigh its counterparties
+#[account]
+pub struct UserStats {
+    /// Deals settled through `release_funds`, on either side of the deal
+    pub deals_completed: u64,
+
+    /// Sum of all the DD those deals moved, in atoms
+    pub volume: u64,
+
+    /// Offers called off before they were settled
+    pub cancellations: u64,
+
+    /// Disputes resolved against this wallet
+    pub disputes_lost: u64,
+}
+
+impl UserStats {
+    fn record_deal(&mut self, amount: u64) {
+        self.deals_completed = self.deals_completed.saturating_add(1);
+        self.volume = self.volume.saturating_add(amount);
+    }
+}
+
+/// Emitted on every release of an escrow
+#[event]
+pub struct Settled {
+    pub seller: Pubkey,
+    pub buyer: Pubkey,
+    pub amount: u64,
+
+    /// What the daemon committed to as the fiat side of the deal,
+    /// which it can open to an auditor without us publishing any bank data
+    pub fiat_commitment: [u8; 32],
+}
+
+/// Emitted for every note that gets into the shielded pool,
+/// so that wallets can rebuild the tree and find their notes in it
+#[event]
+pub struct NoteCommitted {
+    pub commitment: [u8; 32],
+    pub index: u64,
 }
 
 #[error_code]
@@ -457,4 +1567,121 @@ pub enum Error {
     InvalidVersion,
     #[msg("Unauthorized action.")]
     Unauthorized,
+    #[msg("Not enough funds to pay out.")]
+    InsufficientFunds,
+    #[msg("DD is already minted.")]
+    AlreadyMinted,
+    #[msg("Not a recent root of the shielded pool.")]
+    UnknownRoot,
+    #[msg("Invalid proof.")]
+    InvalidProof,
+    #[msg("The shielded pool is full.")]
+    PoolFull,
+    #[msg("Not a field element.")]
+    NotAField,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_the_state_from_before_confidential_dd() {
+        let treasurer = Pubkey::new_unique();
+        let usdc_mint = Pubkey::new_unique();
+        // As `initialize` and `configure` used to leave it:
+        let mut old = ProgramState::DISCRIMINATOR.to_vec();
+        old.push(VERSION);
+        old.extend_from_slice(treasurer.as_ref());
+        old.extend_from_slice(usdc_mint.as_ref());
+        assert_eq!(old.len(), 8 + 1 + 32 + 32);
+        assert!(ProgramState::try_deserialize(&mut &old[..]).is_err());
+
+        let mut data = vec![0; 8 + size_of::<ProgramState>()];
+        ProgramStateV0::migrate(&old)
+            .unwrap()
+            .try_serialize(&mut &mut data[..])
+            .unwrap();
+
+        let state = ProgramState::try_deserialize(&mut &data[..]).unwrap();
+        assert_eq!(state.version, VERSION);
+        assert_eq!(state.treasurer, treasurer);
+        assert_eq!(state.usdc_mint, usdc_mint);
+        assert_eq!(state.auditor_elgamal_pubkey, [0; 32]);
+    }
+
+    #[test]
+    fn migrates_nothing_but_the_state() {
+        let mut stats = UserStats::DISCRIMINATOR.to_vec();
+        stats.extend_from_slice(&[0; 65]);
+
+        assert!(ProgramStateV0::migrate(&stats).is_err());
+    }
+
+    fn empty_pool() -> ShieldedPool {
+        let mut pool = ShieldedPool {
+            next_index: 0,
+            current_root_index: 0,
+            filled_subtrees: [[0; 32]; shielded::DEPTH],
+            zeros: [[0; 32]; shielded::DEPTH + 1],
+            roots: [[0; 32]; shielded::ROOTS],
+        };
+        pool.open().unwrap();
+        pool
+    }
+
+    /// The root of the tree of `leaves`, hashed level by level
+    fn root_of(leaves: &[[u8; 32]], zeros: &[[u8; 32]]) -> [u8; 32] {
+        let mut level = leaves.to_vec();
+        for zero in &zeros[..shielded::DEPTH] {
+            level = level
+                .chunks(2)
+                .map(|pair| shielded::hash_pair(&pair[0], pair.get(1).unwrap_or(zero)).unwrap())
+                .collect();
+        }
+        level.first().copied().unwrap_or(zeros[shielded::DEPTH])
+    }
+
+    #[test]
+    fn keeps_the_root_of_every_note_in_the_tree() {
+        let mut pool = empty_pool();
+        assert!(pool.is_known_root(&root_of(&[], &pool.zeros)));
+
+        let mut leaves = Vec::new();
+        for value in 1..=5 {
+            leaves.push(shielded::field_of(value));
+            pool.insert(*leaves.last().unwrap()).unwrap();
+
+            let root = root_of(&leaves, &pool.zeros);
+            assert_eq!(pool.roots[pool.current_root_index as usize], root);
+            assert!(pool.is_known_root(&root));
+        }
+        assert_eq!(pool.next_index, 5);
+        assert!(!pool.is_known_root(&[0; 32]));
+    }
+
+    #[test]
+    fn forgets_the_oldest_roots() {
+        let mut pool = empty_pool();
+        let empty = pool.roots[0];
+
+        for value in 1..shielded::ROOTS as u64 {
+            pool.insert(shielded::field_of(value)).unwrap();
+        }
+        assert!(pool.is_known_root(&empty));
+        pool.insert(shielded::field_of(0)).unwrap();
+        assert!(!pool.is_known_root(&empty));
+    }
+
+    #[test]
+    fn spends_only_against_a_known_root() {
+        let mut pool = empty_pool();
+        let inputs = [shielded::field_of(1); 6];
+
+        assert_eq!(
+            pool.spend(&[0; 256], inputs),
+            Err(Error::UnknownRoot.into())
+        );
+        assert_eq!(pool.next_index, 0);
+    }
 }
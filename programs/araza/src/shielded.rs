@@ -0,0 +1,115 @@
+//! The cryptography of the shielded pool, on top of the syscalls
+//!
+//! Notes are committed to with Poseidon, and spent with a Groth16 proof
+//! for the verifying key `prover setup` wrote next to this file.
+//! Field elements and points are all big-endian, the way the syscalls take them.
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::alt_bn128::prelude::{
+    alt_bn128_addition, alt_bn128_multiplication, alt_bn128_pairing,
+};
+use anchor_lang::solana_program::hash::hash;
+use anchor_lang::solana_program::poseidon::{hashv, Endianness, Parameters};
+
+use crate::verifying_key::{ALPHA_G1, BETA_G2, DELTA_G2, GAMMA_ABC_G1, GAMMA_G2};
+use crate::Error;
+
+/// How deep the tree of commitments is, so it has room for `2^DEPTH` notes
+pub const DEPTH: usize = 20;
+
+/// How many of the latest roots a proof may be made against,
+/// so that a spend does not fail for a note that got in while it was being proven
+pub const ROOTS: usize = 30;
+
+/// The order of the scalar field, which every input of a proof has to be below
+const SCALAR_MODULUS: [u8; 32] = [
+    0x30, 0x64, 0x4e, 0x72, 0xe1, 0x31, 0xa0, 0x29, 0xb8, 0x50, 0x45, 0xb6, 0x81, 0x81, 0x58, 0x5d,
+    0x28, 0x33, 0xe8, 0x48, 0x79, 0xb9, 0x70, 0x91, 0x43, 0xe1, 0xf5, 0x93, 0xf0, 0x00, 0x00, 0x01,
+];
+
+/// The order of the base field, which the coordinates of the points are below
+const BASE_MODULUS: [u8; 32] = [
+    0x30, 0x64, 0x4e, 0x72, 0xe1, 0x31, 0xa0, 0x29, 0xb8, 0x50, 0x45, 0xb6, 0x81, 0x81, 0x58, 0x5d,
+    0x97, 0x81, 0x6a, 0x91, 0x68, 0x71, 0xca, 0x8d, 0x3c, 0x20, 0x8c, 0x16, 0xd8, 0x7c, 0xfd, 0x47,
+];
+
+/// Whether `bytes` are a field element in the one way it can be written
+///
+/// The same element written twice would otherwise make two nullifiers for one note.
+pub fn is_field(bytes: &[u8; 32]) -> bool {
+    *bytes < SCALAR_MODULUS
+}
+
+/// The hash of two field elements, as the circuit computes it
+pub fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> Result<[u8; 32]> {
+    hashv(Parameters::Bn254X5, Endianness::BigEndian, &[left, right])
+        .map(|hash| hash.to_bytes())
+        .map_err(|_| Error::NotAField.into())
+}
+
+/// The field element `u64` is
+pub fn field_of(amount: u64) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    bytes[24..].copy_from_slice(&amount.to_be_bytes());
+    bytes
+}
+
+/// Who DD leaving the pool goes to, as the proof commits to them:
+/// the SHA-256 of their token account, less the first byte, to make it a field element
+pub fn recipient_of(address: &Pubkey) -> [u8; 32] {
+    let mut bytes = hash(address.as_ref()).to_bytes();
+    bytes[0] = 0;
+    bytes
+}
+
+/// `-point`, which the pairing check takes in place of `point`
+fn negate(point: &[u8]) -> [u8; 64] {
+    let mut negated = [0; 64];
+    negated[..32].copy_from_slice(&point[..32]);
+    if point[32..] == [0; 32] {
+        return negated;
+    }
+    // The modulus less `y`, byte by byte from the least significant one:
+    let mut borrow = 0;
+    for i in (0..32).rev() {
+        let difference = i16::from(BASE_MODULUS[i]) - i16::from(point[32 + i]) - borrow;
+        negated[32 + i] = difference.rem_euclid(256) as u8;
+        borrow = i16::from(difference < 0);
+    }
+    negated
+}
+
+/// Whether `proof` holds for `inputs`, in the order the circuit takes them
+///
+/// The proof is `a`, `b` then `c`, and `e(a, b)` has to equal
+/// `e(alpha, beta) * e(inputs, gamma) * e(c, delta)`.
+pub fn verify(proof: &[u8; 256], inputs: &[[u8; 32]; 6]) -> Result<bool> {
+    if !inputs.iter().all(is_field) {
+        return Err(Error::NotAField.into());
+    }
+
+    // The inputs folded into the one point the verifying key has for them:
+    let mut prepared = GAMMA_ABC_G1[0];
+    for (input, point) in inputs.iter().zip(&GAMMA_ABC_G1[1..]) {
+        let product = alt_bn128_multiplication(&[&point[..], &input[..]].concat())
+            .map_err(|_| Error::InvalidProof)?;
+        let sum = alt_bn128_addition(&[&prepared[..], &product[..]].concat())
+            .map_err(|_| Error::InvalidProof)?;
+        prepared.copy_from_slice(&sum);
+    }
+
+    let pairing = alt_bn128_pairing(
+        &[
+            &negate(&proof[..64])[..],
+            &proof[64..192],
+            &ALPHA_G1,
+            &BETA_G2,
+            &prepared,
+            &GAMMA_G2,
+            &proof[192..],
+            &DELTA_G2,
+        ]
+        .concat(),
+    )
+    .map_err(|_| Error::InvalidProof)?;
+    Ok(pairing == field_of(1))
+}
@@ -0,0 +1,79 @@
+//! The verifying key of the spend circuit, written by `prover setup`: do not edit
+
+pub const ALPHA_G1: [u8; 64] = [
+    26, 54, 2, 125, 107, 174, 126, 33, 104, 61, 36, 117, 249, 92, 115, 199, 89, 19, 113, 91, 9, 87,
+    203, 117, 109, 50, 39, 69, 209, 146, 115, 60, 2, 169, 197, 197, 13, 240, 32, 253, 137, 227, 87,
+    144, 91, 36, 155, 47, 74, 113, 182, 253, 100, 182, 248, 45, 43, 241, 87, 103, 184, 143, 249,
+    82,
+];
+pub const BETA_G2: [u8; 128] = [
+    37, 35, 131, 59, 196, 209, 102, 31, 215, 209, 22, 112, 17, 66, 1, 8, 240, 94, 61, 244, 22, 95,
+    63, 44, 142, 66, 101, 5, 228, 214, 32, 249, 26, 127, 19, 1, 82, 87, 23, 217, 124, 238, 47, 74,
+    10, 65, 72, 68, 199, 234, 1, 106, 201, 159, 79, 91, 7, 119, 141, 162, 22, 31, 84, 182, 2, 241,
+    49, 155, 140, 101, 148, 200, 65, 132, 254, 124, 233, 208, 223, 152, 177, 18, 189, 37, 254, 198,
+    199, 17, 178, 225, 63, 193, 193, 227, 84, 78, 25, 195, 118, 10, 107, 47, 154, 211, 184, 190,
+    97, 126, 119, 226, 157, 243, 36, 81, 55, 157, 255, 121, 142, 19, 187, 50, 158, 165, 177, 209,
+    156, 125,
+];
+pub const GAMMA_G2: [u8; 128] = [
+    17, 76, 169, 122, 28, 62, 251, 139, 127, 17, 175, 181, 173, 145, 6, 247, 22, 54, 165, 74, 115,
+    226, 109, 141, 44, 226, 181, 52, 86, 251, 119, 211, 20, 175, 70, 32, 157, 208, 132, 139, 178,
+    194, 78, 118, 1, 117, 138, 208, 47, 13, 124, 130, 49, 252, 17, 73, 182, 30, 221, 151, 237, 118,
+    59, 125, 47, 66, 189, 115, 238, 193, 220, 52, 253, 93, 63, 126, 150, 205, 119, 174, 96, 95,
+    127, 245, 97, 77, 240, 1, 82, 161, 176, 90, 151, 217, 4, 175, 33, 34, 13, 13, 238, 187, 29,
+    159, 55, 112, 215, 124, 203, 30, 187, 86, 53, 245, 252, 221, 27, 249, 53, 93, 82, 239, 127,
+    242, 2, 33, 73, 86,
+];
+pub const DELTA_G2: [u8; 128] = [
+    27, 155, 189, 120, 238, 146, 142, 26, 18, 117, 75, 219, 152, 117, 85, 26, 206, 58, 197, 126,
+    185, 32, 8, 99, 234, 152, 32, 156, 232, 179, 8, 0, 1, 181, 180, 208, 211, 120, 210, 86, 142,
+    99, 123, 33, 204, 203, 110, 185, 244, 126, 8, 133, 114, 95, 40, 155, 146, 228, 158, 0, 248, 8,
+    234, 167, 1, 227, 68, 143, 70, 193, 160, 20, 123, 137, 14, 232, 170, 219, 198, 17, 80, 124,
+    228, 158, 183, 194, 157, 64, 22, 131, 186, 6, 220, 160, 120, 146, 0, 44, 12, 62, 41, 144, 195,
+    44, 223, 63, 87, 55, 28, 30, 69, 252, 42, 78, 66, 105, 202, 101, 64, 255, 59, 106, 86, 211, 50,
+    255, 32, 124,
+];
+pub const GAMMA_ABC_G1: [[u8; 64]; 7] = [
+    [
+        32, 62, 193, 110, 155, 253, 159, 228, 153, 78, 115, 118, 62, 27, 147, 233, 4, 67, 72, 169,
+        87, 191, 243, 51, 29, 62, 219, 102, 206, 26, 233, 245, 5, 209, 102, 54, 151, 37, 235, 186,
+        94, 132, 204, 139, 98, 220, 169, 247, 128, 159, 81, 113, 214, 161, 183, 93, 84, 14, 59,
+        103, 80, 160, 21, 33,
+    ],
+    [
+        10, 108, 250, 206, 123, 24, 41, 247, 155, 168, 166, 126, 1, 30, 42, 49, 10, 111, 53, 237,
+        62, 161, 10, 74, 247, 102, 26, 206, 11, 43, 218, 137, 36, 129, 87, 129, 211, 69, 225, 92,
+        101, 180, 27, 242, 219, 208, 184, 241, 211, 179, 83, 246, 49, 69, 27, 142, 111, 191, 11,
+        182, 107, 11, 93, 186,
+    ],
+    [
+        42, 212, 22, 134, 196, 106, 123, 26, 126, 106, 220, 192, 29, 75, 62, 149, 158, 56, 45, 83,
+        218, 29, 190, 136, 248, 228, 155, 43, 108, 124, 138, 29, 24, 7, 171, 129, 103, 218, 149,
+        184, 89, 99, 16, 89, 150, 200, 188, 167, 64, 80, 145, 174, 173, 100, 43, 79, 113, 175, 133,
+        206, 247, 181, 197, 233,
+    ],
+    [
+        14, 18, 9, 135, 180, 47, 181, 48, 232, 73, 180, 101, 88, 47, 198, 190, 49, 132, 50, 3, 17,
+        250, 157, 53, 111, 1, 76, 4, 247, 168, 97, 30, 20, 206, 188, 154, 248, 9, 104, 93, 237, 60,
+        247, 212, 227, 54, 50, 192, 180, 9, 217, 214, 20, 194, 8, 220, 52, 31, 115, 232, 87, 106,
+        75, 105,
+    ],
+    [
+        30, 42, 92, 121, 234, 63, 127, 2, 4, 248, 141, 79, 70, 20, 7, 89, 37, 123, 23, 55, 137, 26,
+        174, 65, 6, 173, 93, 121, 177, 136, 138, 8, 47, 118, 90, 17, 231, 148, 102, 175, 240, 125,
+        204, 15, 135, 0, 168, 192, 76, 161, 105, 252, 127, 133, 233, 94, 191, 203, 52, 176, 47, 61,
+        66, 18,
+    ],
+    [
+        31, 85, 199, 225, 97, 77, 78, 78, 161, 223, 114, 147, 59, 170, 111, 221, 85, 82, 115, 135,
+        57, 203, 229, 18, 61, 63, 208, 20, 229, 141, 39, 176, 1, 54, 187, 203, 35, 153, 87, 193,
+        164, 71, 212, 60, 112, 126, 192, 90, 163, 105, 83, 161, 49, 100, 59, 177, 97, 110, 119,
+        241, 211, 135, 135, 247,
+    ],
+    [
+        16, 190, 172, 1, 11, 174, 139, 66, 25, 215, 25, 254, 52, 233, 74, 144, 251, 56, 232, 183,
+        86, 145, 40, 235, 174, 52, 81, 236, 255, 185, 32, 190, 19, 124, 210, 213, 211, 129, 205,
+        97, 86, 21, 218, 236, 26, 99, 47, 124, 84, 6, 4, 28, 243, 4, 192, 185, 150, 189, 73, 251,
+        37, 23, 10, 229,
+    ],
+];
@@ -0,0 +1,298 @@
+//! What a spender proves when they spend a note of the shielded pool
+//!
+//! A note is worth `value` DD and belongs to whoever knows the `secret` behind its `owner`.
+//! The pool only ever sees its commitment, `H(value, H(owner, blinding))`,
+//! and, once it is spent, its nullifier, `H(commitment, secret)`,
+//! which nobody but the owner can link to the commitment.
+//!
+//! Spending a note makes two new ones, and sends whatever is left out of the pool,
+//! so a private transfer sends nothing out, and an unshield may leave some change.
+use ark_bn254::Fr;
+use ark_ff::PrimeField;
+
+use crate::poseidon::{enforce_hash, hash};
+use crate::r1cs::{ConstraintSystem, LinearCombination};
+use crate::tree::DEPTH;
+
+/// How many bits an amount of DD takes, in atoms
+const AMOUNT_BITS: usize = 64;
+
+/// A note of the shielded pool
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Note {
+    pub value: u64,
+    pub owner: Fr,
+    pub blinding: Fr,
+}
+
+impl Note {
+    /// What the commitment hides besides the value, which is all `shield` is given
+    pub fn hiding(&self) -> Fr {
+        hash(&[self.owner, self.blinding])
+    }
+
+    /// The leaf the note takes in the tree
+    pub fn commitment(&self) -> Fr {
+        hash(&[Fr::from(self.value), self.hiding()])
+    }
+}
+
+/// The owner key of the notes `secret` can spend, which is safe to hand out to payers
+pub fn owner_of(secret: Fr) -> Fr {
+    hash(&[secret])
+}
+
+/// Who DD leaving the pool goes to, as the program hashes their token account `address`:
+/// its SHA-256, less the first byte, to make it a field element
+pub fn recipient_of(address: &[u8; 32]) -> Fr {
+    let mut hash = solana_sdk::hash::hash(address).to_bytes();
+    hash[0] = 0;
+    Fr::from_be_bytes_mod_order(&hash)
+}
+
+/// What marks the note with `commitment` as spent, once and for all
+pub fn nullifier_of(commitment: Fr, secret: Fr) -> Fr {
+    hash(&[commitment, secret])
+}
+
+/// Everything the spender knows about spending a note
+#[derive(Clone, Debug, Default)]
+pub struct Spend {
+    /// The secret behind the owner of `note`
+    pub secret: Fr,
+    pub note: Note,
+    /// Where `note` is in the tree
+    pub index: u64,
+    /// The siblings of `note` in the tree, from the bottom up
+    pub path: [Fr; DEPTH],
+    /// The root of the tree `path` leads to
+    pub root: Fr,
+    /// The notes made out of `note`
+    pub outputs: [Note; 2],
+    /// How much DD leaves the pool
+    pub amount: u64,
+    /// Who the DD that leaves the pool goes to, as the program hashes them
+    pub recipient: Fr,
+}
+
+/// What the program sees of a spend, in the order it passes them to the verifier
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PublicInputs {
+    pub root: Fr,
+    pub nullifier: Fr,
+    pub commitments: [Fr; 2],
+    pub amount: Fr,
+    pub recipient: Fr,
+}
+
+impl PublicInputs {
+    pub fn to_vec(&self) -> Vec<Fr> {
+        vec![
+            self.root,
+            self.nullifier,
+            self.commitments[0],
+            self.commitments[1],
+            self.amount,
+            self.recipient,
+        ]
+    }
+}
+
+impl Spend {
+    pub fn public_inputs(&self) -> PublicInputs {
+        PublicInputs {
+            root: self.root,
+            nullifier: nullifier_of(self.note.commitment(), self.secret),
+            commitments: self.outputs.map(|output| output.commitment()),
+            amount: Fr::from(self.amount),
+            recipient: self.recipient,
+        }
+    }
+
+    /// The constraints of spending a note, with the values of this spend
+    pub fn synthesize(&self) -> ConstraintSystem {
+        let public = self.public_inputs();
+        let mut cs = ConstraintSystem::default();
+        let root = cs.input(public.root);
+        let nullifier = cs.input(public.nullifier);
+        let commitments = public.commitments.map(|commitment| cs.input(commitment));
+        let amount = cs.input(public.amount);
+        let recipient = cs.input(public.recipient);
+
+        // The spender owns the note:
+        let secret: LinearCombination = cs.witness(self.secret).into();
+        let owner = enforce_hash(&mut cs, std::slice::from_ref(&secret));
+        let value: LinearCombination = cs.witness(Fr::from(self.note.value)).into();
+        let blinding = cs.witness(self.note.blinding).into();
+        let hiding = enforce_hash(&mut cs, &[owner, blinding]);
+        let commitment = enforce_hash(&mut cs, &[value.clone(), hiding]);
+
+        // Which has not been spent yet:
+        let expected = enforce_hash(&mut cs, &[commitment.clone(), secret]);
+        cs.enforce_equal(expected, nullifier.into());
+
+        // And which is in the tree:
+        let mut node = commitment;
+        for (level, &sibling) in self.path.iter().enumerate() {
+            let is_right = cs.boolean(self.index >> level & 1 == 1);
+            let sibling: LinearCombination = cs.witness(sibling).into();
+            // The node and its sibling swap places when the node is on the right:
+            let swap = cs.product(is_right.into(), sibling.clone() - node.clone());
+            let left = node.clone() + swap.into();
+            let right = sibling - swap.into();
+            node = enforce_hash(&mut cs, &[left, right]);
+        }
+        cs.enforce_equal(node, root.into());
+
+        // The new notes are worth what they say:
+        let mut total = LinearCombination::from(amount);
+        for (output, commitment) in self.outputs.iter().zip(commitments) {
+            let value: LinearCombination = cs.witness(Fr::from(output.value)).into();
+            cs.enforce_bits(value.clone(), AMOUNT_BITS);
+            let owner = cs.witness(output.owner).into();
+            let blinding = cs.witness(output.blinding).into();
+            let hiding = enforce_hash(&mut cs, &[owner, blinding]);
+            let expected = enforce_hash(&mut cs, &[value.clone(), hiding]);
+            cs.enforce_equal(expected, commitment.into());
+            total = total + value;
+        }
+
+        // Which, with what leaves the pool, is what the spent note was worth:
+        cs.enforce_bits(amount.into(), AMOUNT_BITS);
+        cs.enforce_equal(total, value);
+
+        // The recipient is part of the proof, so nobody can redirect what leaves the pool:
+        cs.product(recipient.into(), recipient.into());
+
+        cs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ark_ff::UniformRand;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use solana_sdk::pubkey::Pubkey;
+
+    use super::*;
+    use crate::groth16::{self, field_bytes};
+    use crate::spend_key;
+    use crate::tree::Tree;
+
+    /// A note of 100 DD spent into 30 for someone else and 20 of change, with 50 leaving the pool
+    fn spend() -> Spend {
+        let mut rng = StdRng::seed_from_u64(29);
+        let secret = Fr::rand(&mut rng);
+        let note = Note {
+            value: 100,
+            owner: owner_of(secret),
+            blinding: Fr::rand(&mut rng),
+        };
+        let tree = Tree::new(&[Fr::from(1u64), note.commitment(), Fr::from(3u64)]);
+        Spend {
+            secret,
+            note,
+            index: 1,
+            path: tree.path(1).unwrap(),
+            root: tree.root(),
+            outputs: [
+                Note {
+                    value: 30,
+                    owner: owner_of(Fr::rand(&mut rng)),
+                    blinding: Fr::rand(&mut rng),
+                },
+                Note {
+                    value: 20,
+                    owner: note.owner,
+                    blinding: Fr::rand(&mut rng),
+                },
+            ],
+            amount: 50,
+            recipient: recipient_of(&[7; 32]),
+        }
+    }
+
+    #[test]
+    fn satisfies_an_honest_spend() {
+        assert!(spend().synthesize().is_satisfied());
+    }
+
+    #[test]
+    fn refuses_to_make_dd_out_of_nothing() {
+        let mut spend = spend();
+        spend.amount = 51;
+
+        assert!(!spend.synthesize().is_satisfied());
+    }
+
+    #[test]
+    fn refuses_a_note_of_someone_else() {
+        let mut spend = spend();
+        spend.secret = Fr::from(1u64);
+
+        assert!(!spend.synthesize().is_satisfied());
+    }
+
+    #[test]
+    fn refuses_a_note_outside_the_tree() {
+        let mut spend = spend();
+        spend.root = Tree::new(&[Fr::from(1u64)]).root();
+
+        assert!(!spend.synthesize().is_satisfied());
+    }
+
+    #[test]
+    fn has_the_shape_the_keys_were_made_for() {
+        assert_eq!(
+            spend().synthesize().constraints,
+            Spend::default().synthesize().constraints
+        );
+    }
+
+    #[test]
+    fn commits_to_a_note_the_way_shield_does() {
+        let note = spend().note;
+
+        let commitment = araza::shielded::hash_pair(
+            &araza::shielded::field_of(note.value),
+            &field_bytes(&note.hiding()),
+        );
+
+        assert_eq!(commitment.unwrap(), field_bytes(&note.commitment()));
+    }
+
+    #[test]
+    fn sends_dd_where_the_program_does() {
+        let address = Pubkey::new_unique();
+
+        assert_eq!(
+            araza::shielded::recipient_of(&address),
+            field_bytes(&recipient_of(&address.to_bytes()))
+        );
+    }
+
+    #[test]
+    fn proves_a_spend_the_program_accepts() {
+        let spend = spend();
+        let proof = groth16::prove(
+            &spend_key(),
+            &spend.synthesize(),
+            &mut StdRng::seed_from_u64(1),
+        );
+        let mut inputs: [[u8; 32]; 6] = spend
+            .public_inputs()
+            .to_vec()
+            .iter()
+            .map(field_bytes)
+            .collect::<Vec<_>>()
+            .try_into()
+            .unwrap();
+
+        assert!(araza::shielded::verify(&proof.to_bytes(), &inputs).unwrap());
+
+        // Nor can anyone take more out of the pool than was proven:
+        inputs[4] = araza::shielded::field_of(51);
+        assert!(!araza::shielded::verify(&proof.to_bytes(), &inputs).unwrap());
+    }
+}
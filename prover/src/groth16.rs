@@ -0,0 +1,295 @@
+//! Groth16 over BN254, which the program verifies with the `alt_bn128` syscalls
+//!
+//! The constraints become a quadratic arithmetic program the way libsnark has it:
+//! every input also gets a row of its own, so that a proof binds all of them.
+use std::ops::Neg;
+
+use ark_bn254::{Bn254, Fr, G1Affine, G1Projective, G2Affine, G2Projective};
+use ark_ec::pairing::Pairing;
+use ark_ec::scalar_mul::fixed_base::FixedBase;
+use ark_ec::scalar_mul::ScalarMul;
+use ark_ec::{AffineRepr, CurveGroup, Group, VariableBaseMSM};
+use ark_ff::{BigInteger, FftField, Field, One, PrimeField, UniformRand, Zero};
+use ark_poly::{EvaluationDomain, Radix2EvaluationDomain};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use rand::Rng;
+
+use crate::r1cs::ConstraintSystem;
+
+/// What the program needs to verify a proof
+#[derive(Clone, Debug, PartialEq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct VerifyingKey {
+    pub alpha_g1: G1Affine,
+    pub beta_g2: G2Affine,
+    pub gamma_g2: G2Affine,
+    pub delta_g2: G2Affine,
+    /// One per input, the constant one first
+    pub gamma_abc_g1: Vec<G1Affine>,
+}
+
+/// What a prover needs to make a proof, which the setup leaves behind with the verifying key
+#[derive(Clone, Debug, PartialEq, CanonicalSerialize, CanonicalDeserialize)]
+pub struct ProvingKey {
+    pub vk: VerifyingKey,
+    pub beta_g1: G1Affine,
+    pub delta_g1: G1Affine,
+    /// One per variable
+    pub a_query: Vec<G1Affine>,
+    pub b_g1_query: Vec<G1Affine>,
+    pub b_g2_query: Vec<G2Affine>,
+    /// One per power of tau below the size of the domain, less one
+    pub h_query: Vec<G1Affine>,
+    /// One per witness
+    pub l_query: Vec<G1Affine>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Proof {
+    pub a: G1Affine,
+    pub b: G2Affine,
+    pub c: G1Affine,
+}
+
+/// The domain the polynomials of `cs` are interpolated over
+fn domain_of(cs: &ConstraintSystem) -> Radix2EvaluationDomain<Fr> {
+    Radix2EvaluationDomain::new(cs.constraints.len() + 1 + cs.inputs.len())
+        .expect("The circuit fits in the two-adicity of the field")
+}
+
+/// `g` times each of `scalars`
+fn batch_mul<G>(g: G, scalars: &[Fr]) -> Vec<G::MulBase>
+where
+    G: ScalarMul + Group<ScalarField = Fr>,
+{
+    let bits = Fr::MODULUS_BIT_SIZE as usize;
+    let window = FixedBase::get_mul_window_size(scalars.len());
+    let table = FixedBase::get_window_table(bits, window, g);
+    G::batch_convert_to_mul_base(&FixedBase::msm::<G>(bits, window, &table, scalars))
+}
+
+/// Make the keys for the circuit `cs`, from toxic waste drawn from `rng`
+///
+/// Whoever learns the toxic waste can prove anything, so it is dropped here and now.
+pub fn setup(cs: &ConstraintSystem, rng: &mut impl Rng) -> ProvingKey {
+    let [tau, alpha, beta, gamma, delta] = [(); 5].map(|_| Fr::rand(rng));
+    let domain = domain_of(cs);
+    let lagrange = domain.evaluate_all_lagrange_coefficients(tau);
+    let num_constraints = cs.constraints.len();
+    let num_instance = 1 + cs.inputs.len();
+
+    // Each polynomial of the program, at tau:
+    let mut a = vec![Fr::zero(); cs.num_variables()];
+    let mut b = a.clone();
+    let mut c = a.clone();
+    for (i, a) in a.iter_mut().take(num_instance).enumerate() {
+        *a = lagrange[num_constraints + i];
+    }
+    for (row, constraint) in cs.constraints.iter().enumerate() {
+        for (polynomials, combination) in [&mut a, &mut b, &mut c].into_iter().zip(constraint) {
+            for &(variable, coefficient) in &combination.0 {
+                polynomials[cs.index(variable)] += lagrange[row] * coefficient;
+            }
+        }
+    }
+
+    let gamma_inverse = gamma.inverse().unwrap();
+    let delta_inverse = delta.inverse().unwrap();
+    let combined: Vec<Fr> = (0..cs.num_variables())
+        .map(|i| beta * a[i] + alpha * b[i] + c[i])
+        .collect();
+    let gamma_abc: Vec<Fr> = combined[..num_instance]
+        .iter()
+        .map(|x| *x * gamma_inverse)
+        .collect();
+    let l: Vec<Fr> = combined[num_instance..]
+        .iter()
+        .map(|x| *x * delta_inverse)
+        .collect();
+    let z_at_tau = domain.evaluate_vanishing_polynomial(tau) * delta_inverse;
+    let h: Vec<Fr> = std::iter::successors(Some(z_at_tau), |power| Some(*power * tau))
+        .take(domain.size() - 1)
+        .collect();
+
+    let g1 = G1Projective::generator();
+    let g2 = G2Projective::generator();
+    ProvingKey {
+        vk: VerifyingKey {
+            alpha_g1: (g1 * alpha).into_affine(),
+            beta_g2: (g2 * beta).into_affine(),
+            gamma_g2: (g2 * gamma).into_affine(),
+            delta_g2: (g2 * delta).into_affine(),
+            gamma_abc_g1: batch_mul(g1, &gamma_abc),
+        },
+        beta_g1: (g1 * beta).into_affine(),
+        delta_g1: (g1 * delta).into_affine(),
+        a_query: batch_mul(g1, &a),
+        b_g1_query: batch_mul(g1, &b),
+        b_g2_query: batch_mul(g2, &b),
+        h_query: batch_mul(g1, &h),
+        l_query: batch_mul(g1, &l),
+    }
+}
+
+/// The coefficients of `h`, where `h * Z = A * B - C` for the values of the variables of `cs`
+fn quotient(cs: &ConstraintSystem, assignment: &[Fr]) -> Vec<Fr> {
+    let domain = domain_of(cs);
+    let evaluate = |combination: &crate::r1cs::LinearCombination| -> Fr {
+        combination
+            .0
+            .iter()
+            .map(|&(variable, coefficient)| coefficient * assignment[cs.index(variable)])
+            .sum()
+    };
+    let mut a = vec![Fr::zero(); domain.size()];
+    let mut b = a.clone();
+    let mut c = a.clone();
+    for (row, [x, y, z]) in cs.constraints.iter().enumerate() {
+        a[row] = evaluate(x);
+        b[row] = evaluate(y);
+        c[row] = evaluate(z);
+    }
+    let num_constraints = cs.constraints.len();
+    a[num_constraints..num_constraints + 1 + cs.inputs.len()]
+        .copy_from_slice(&assignment[..1 + cs.inputs.len()]);
+
+    // Over a coset, where the vanishing polynomial is a nonzero constant:
+    let coset = domain.get_coset(Fr::GENERATOR).unwrap();
+    for evaluations in [&mut a, &mut b, &mut c] {
+        domain.ifft_in_place(evaluations);
+        coset.fft_in_place(evaluations);
+    }
+    let z_inverse = (Fr::GENERATOR.pow([domain.size() as u64]) - Fr::one())
+        .inverse()
+        .unwrap();
+    let mut h: Vec<Fr> = a
+        .iter()
+        .zip(&b)
+        .zip(&c)
+        .map(|((a, b), c)| (*a * b - c) * z_inverse)
+        .collect();
+    coset.ifft_in_place(&mut h);
+    h.truncate(domain.size() - 1);
+    h
+}
+
+/// Prove that the values of the variables of `cs` meet its constraints,
+/// blinded with randomness from `rng`
+pub fn prove(pk: &ProvingKey, cs: &ConstraintSystem, rng: &mut impl Rng) -> Proof {
+    let assignment = cs.assignment();
+    let h = quotient(cs, &assignment);
+    let r = Fr::rand(rng);
+    let s = Fr::rand(rng);
+
+    let a =
+        G1Projective::msm_unchecked(&pk.a_query, &assignment) + pk.vk.alpha_g1 + pk.delta_g1 * r;
+    let b_g1 =
+        G1Projective::msm_unchecked(&pk.b_g1_query, &assignment) + pk.beta_g1 + pk.delta_g1 * s;
+    let b = G2Projective::msm_unchecked(&pk.b_g2_query, &assignment)
+        + pk.vk.beta_g2
+        + pk.vk.delta_g2 * s;
+    let witnesses = &assignment[1 + cs.inputs.len()..];
+    let c = G1Projective::msm_unchecked(&pk.l_query, witnesses)
+        + G1Projective::msm_unchecked(&pk.h_query, &h)
+        + a * s
+        + b_g1 * r
+        - pk.delta_g1 * (r * s);
+
+    Proof {
+        a: a.into_affine(),
+        b: b.into_affine(),
+        c: c.into_affine(),
+    }
+}
+
+/// Whether `proof` holds for `inputs`, the way the program checks it
+pub fn verify(vk: &VerifyingKey, inputs: &[Fr], proof: &Proof) -> bool {
+    if inputs.len() + 1 != vk.gamma_abc_g1.len() {
+        return false;
+    }
+    let vk_x = G1Projective::msm_unchecked(&vk.gamma_abc_g1[1..], inputs) + vk.gamma_abc_g1[0];
+    Bn254::multi_pairing(
+        [proof.a.neg(), vk.alpha_g1, vk_x.into_affine(), proof.c],
+        [proof.b, vk.beta_g2, vk.gamma_g2, vk.delta_g2],
+    )
+    .is_zero()
+}
+
+/// `x` as the syscalls take it: 32 bytes, big-endian
+pub fn field_bytes<F: PrimeField>(x: &F) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    let be = x.into_bigint().to_bytes_be();
+    bytes[32 - be.len()..].copy_from_slice(&be);
+    bytes
+}
+
+/// `point` as the syscalls take it: `x` then `y`
+pub fn g1_bytes(point: &G1Affine) -> [u8; 64] {
+    let mut bytes = [0; 64];
+    if let Some((x, y)) = point.xy() {
+        bytes[..32].copy_from_slice(&field_bytes(x));
+        bytes[32..].copy_from_slice(&field_bytes(y));
+    }
+    bytes
+}
+
+/// `point` as the syscalls take it: `x` then `y`, the imaginary part of each first
+pub fn g2_bytes(point: &G2Affine) -> [u8; 128] {
+    let mut bytes = [0; 128];
+    if let Some((x, y)) = point.xy() {
+        for (chunk, part) in bytes.chunks_mut(32).zip([x.c1, x.c0, y.c1, y.c0]) {
+            chunk.copy_from_slice(&field_bytes(&part));
+        }
+    }
+    bytes
+}
+
+impl Proof {
+    /// The proof as the program takes it: `a`, `b` then `c`
+    pub fn to_bytes(&self) -> [u8; 256] {
+        let mut bytes = [0; 256];
+        bytes[..64].copy_from_slice(&g1_bytes(&self.a));
+        bytes[64..192].copy_from_slice(&g2_bytes(&self.b));
+        bytes[192..].copy_from_slice(&g1_bytes(&self.c));
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    /// Knowing a square root of the input
+    fn square_root(input: u64, root: u64) -> ConstraintSystem {
+        let mut cs = ConstraintSystem::default();
+        let input = cs.input(Fr::from(input));
+        let root = cs.witness(Fr::from(root));
+        cs.enforce(root.into(), root.into(), input.into());
+        cs
+    }
+
+    #[test]
+    fn verifies_what_it_proves() {
+        let mut rng = StdRng::seed_from_u64(29);
+        let pk = setup(&square_root(0, 0), &mut rng);
+
+        let proof = prove(&pk, &square_root(49, 7), &mut rng);
+
+        assert!(verify(&pk.vk, &[Fr::from(49u64)], &proof));
+        assert!(!verify(&pk.vk, &[Fr::from(36u64)], &proof));
+        assert!(!verify(&pk.vk, &[], &proof));
+    }
+
+    #[test]
+    fn verifies_nothing_that_does_not_hold() {
+        let mut rng = StdRng::seed_from_u64(29);
+        let pk = setup(&square_root(0, 0), &mut rng);
+
+        let proof = prove(&pk, &square_root(48, 7), &mut rng);
+
+        assert!(!verify(&pk.vk, &[Fr::from(48u64)], &proof));
+        assert!(!verify(&pk.vk, &[Fr::from(49u64)], &proof));
+    }
+}
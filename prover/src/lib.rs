@@ -0,0 +1,37 @@
+//! What users prove about their private DD, which the program only verifies
+use ark_serialize::CanonicalDeserialize;
+
+pub mod circuit;
+pub mod groth16;
+pub mod poseidon;
+pub mod r1cs;
+pub mod tree;
+
+/// The proving key `prover setup` made for the spend circuit,
+/// along with the verifying key it wrote into the program
+pub fn spend_key() -> groth16::ProvingKey {
+    groth16::ProvingKey::deserialize_compressed_unchecked(&include_bytes!("../keys/spend.pk")[..])
+        .expect("The proving key is the one `prover setup` wrote")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groth16::{g1_bytes, g2_bytes};
+
+    #[test]
+    fn has_the_proving_key_of_the_verifying_key_of_the_program() {
+        use araza::verifying_key::{ALPHA_G1, BETA_G2, DELTA_G2, GAMMA_ABC_G1, GAMMA_G2};
+
+        let vk = spend_key().vk;
+
+        assert_eq!(g1_bytes(&vk.alpha_g1), ALPHA_G1);
+        assert_eq!(g2_bytes(&vk.beta_g2), BETA_G2);
+        assert_eq!(g2_bytes(&vk.gamma_g2), GAMMA_G2);
+        assert_eq!(g2_bytes(&vk.delta_g2), DELTA_G2);
+        assert_eq!(
+            vk.gamma_abc_g1.iter().map(g1_bytes).collect::<Vec<_>>(),
+            GAMMA_ABC_G1
+        );
+    }
+}
@@ -0,0 +1,377 @@
+use std::mem::size_of;
+use std::str::FromStr;
+
+use ark_bn254::Fr;
+use ark_ff::{PrimeField, UniformRand, Zero};
+use ark_serialize::CanonicalSerialize;
+use prover::circuit::{owner_of, recipient_of, Note, Spend};
+use prover::groth16::{field_bytes, g1_bytes, g2_bytes, VerifyingKey};
+use prover::tree::Tree;
+use prover::{groth16, spend_key};
+use rand::rngs::OsRng;
+use serde_json::{json, Value};
+use solana_sdk::instruction::Instruction;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+use spl_token_2022::extension::confidential_transfer::account_info::{
+    ApplyPendingBalanceAccountInfo, WithdrawAccountInfo,
+};
+use spl_token_2022::extension::confidential_transfer::{instruction, ConfidentialTransferAccount};
+use spl_token_2022::extension::{BaseStateWithExtensions, StateWithExtensions};
+use spl_token_2022::proof::ProofLocation;
+use spl_token_2022::solana_zk_token_sdk::encryption::auth_encryption::AeKey;
+use spl_token_2022::solana_zk_token_sdk::encryption::elgamal::ElGamalKeypair;
+use spl_token_2022::solana_zk_token_sdk::instruction::{PubkeyValidityData, WithdrawProofContext};
+use spl_token_2022::solana_zk_token_sdk::zk_token_proof_instruction::{
+    verify_withdraw, ContextStateInfo,
+};
+use spl_token_2022::solana_zk_token_sdk::zk_token_proof_state::ProofContextState;
+use spl_token_2022::state::Account;
+
+/// How many deposits and transfers an account takes in before its owner has to apply them
+const MAXIMUM_PENDING_BALANCE_CREDIT_COUNTER: u64 = 65536;
+
+/// Read the keypair of the owner of the confidential account from the env `OWNER_SECRET_KEY`
+fn owner_from_env() -> Result<Keypair, String> {
+    std::env::var("OWNER_SECRET_KEY")
+        .map_err(|_| "Missing env `OWNER_SECRET_KEY`".to_string())
+        .and_then(|key| {
+            serde_json::from_str(&key).map_err(|_| "Bad `OWNER_SECRET_KEY`".to_string())
+        })
+        .and_then(|key: Vec<u8>| {
+            Keypair::from_bytes(&key).map_err(|_| "Bad `OWNER_SECRET_KEY`".to_string())
+        })
+}
+
+/// Read the argument at `position`, named `name` for the error
+fn arg(position: usize, name: &str) -> Result<String, String> {
+    std::env::args()
+        .nth(position)
+        .ok_or_else(|| format!("Missing argument `{name}`"))
+}
+
+/// Read a public key from `text`, named `name` for the error
+fn pubkey_of(text: &str, name: &str) -> Result<Pubkey, String> {
+    Pubkey::from_str(text).map_err(|_| format!("Bad `{name}`"))
+}
+
+/// Read a public key from the argument at `position`
+fn pubkey_arg(position: usize, name: &str) -> Result<Pubkey, String> {
+    pubkey_of(&arg(position, name)?, name)
+}
+
+/// The keys `owner` encrypts the balances of `token_account` with,
+/// derived from their signature so that they never have to be stored
+fn keys_of(owner: &Keypair, token_account: &Pubkey) -> Result<(ElGamalKeypair, AeKey), String> {
+    let elgamal = ElGamalKeypair::new_from_signer(owner, token_account.as_ref())
+        .map_err(|err| format!("While deriving the ElGamal key: {err}"))?;
+    let ae = AeKey::new_from_signer(owner, token_account.as_ref())
+        .map_err(|err| format!("While deriving the AE key: {err}"))?;
+    Ok((elgamal, ae))
+}
+
+/// Read the confidential side of the token account from its hex-encoded data at `position`
+fn confidential_arg(position: usize) -> Result<ConfidentialTransferAccount, String> {
+    let data = hex::decode(arg(position, "account data")?)
+        .map_err(|_| "Bad `account data`".to_string())?;
+    let account = StateWithExtensions::<Account>::unpack(&data)
+        .map_err(|err| format!("While reading the token account: {err}"))?;
+    account
+        .get_extension::<ConfidentialTransferAccount>()
+        .copied()
+        .map_err(|_| "The token account is not confidential".to_string())
+}
+
+/// An instruction the way `@solana/web3.js` builds its `TransactionInstruction`, data in hex
+fn instruction_json(instruction: &Instruction) -> Value {
+    json!({
+        "programId": instruction.program_id.to_string(),
+        "keys": instruction.accounts.iter().map(|meta| json!({
+            "pubkey": meta.pubkey.to_string(),
+            "isSigner": meta.is_signer,
+            "isWritable": meta.is_writable,
+        })).collect::<Vec<_>>(),
+        "data": hex::encode(&instruction.data),
+    })
+}
+
+/// Let the token account of the owner hold confidential DD
+///
+/// The owner proves that they know the secret of the ElGamal key
+/// their balances will be encrypted to.
+fn configure_account() -> Result<Value, String> {
+    let owner = owner_from_env()?;
+    let token_account = pubkey_arg(2, "token account")?;
+    let mint = pubkey_arg(3, "mint")?;
+    let (elgamal, ae) = keys_of(&owner, &token_account)?;
+
+    let proof = PubkeyValidityData::new(&elgamal)
+        .map_err(|err| format!("While proving the ElGamal key: {err}"))?;
+    let instructions = instruction::configure_account(
+        &spl_token_2022::id(),
+        &token_account,
+        &mint,
+        ae.encrypt(0),
+        MAXIMUM_PENDING_BALANCE_CREDIT_COUNTER,
+        &owner.pubkey(),
+        &[],
+        ProofLocation::InstructionOffset(1.try_into().unwrap(), &proof),
+    )
+    .map_err(|err| format!("While configuring the account: {err}"))?;
+
+    Ok(json!({
+        "instructions": instructions.iter().map(instruction_json).collect::<Vec<_>>(),
+    }))
+}
+
+/// Fold the pending balance of the owner into their available one
+fn apply_pending_balance() -> Result<Value, String> {
+    let owner = owner_from_env()?;
+    let token_account = pubkey_arg(2, "token account")?;
+    let confidential = confidential_arg(3)?;
+    let (elgamal, ae) = keys_of(&owner, &token_account)?;
+
+    let info = ApplyPendingBalanceAccountInfo::new(&confidential);
+    let new_decryptable_available_balance = info
+        .new_decryptable_available_balance(elgamal.secret(), &ae)
+        .map_err(|err| format!("While decrypting the balance: {err}"))?;
+    let instruction = instruction::apply_pending_balance(
+        &spl_token_2022::id(),
+        &token_account,
+        info.pending_balance_credit_counter(),
+        new_decryptable_available_balance,
+        &owner.pubkey(),
+        &[],
+    )
+    .map_err(|err| format!("While applying the balance: {err}"))?;
+
+    Ok(json!({
+        "instructions": [instruction_json(&instruction)],
+    }))
+}
+
+/// Prove that the owner has `amount` of confidential DD to withdraw
+///
+/// The proof is verified into the context state account,
+/// which the owner has to create beforehand with the space we give.
+/// The program then takes the new balance of the owner, encrypted to themselves.
+fn withdraw() -> Result<Value, String> {
+    let owner = owner_from_env()?;
+    let token_account = pubkey_arg(2, "token account")?;
+    let confidential = confidential_arg(3)?;
+    let amount = u64::from_str(&arg(4, "amount")?).map_err(|_| "Bad `amount`".to_string())?;
+    let context_state_account = pubkey_arg(5, "context state account")?;
+    let (elgamal, ae) = keys_of(&owner, &token_account)?;
+
+    let info = WithdrawAccountInfo::new(&confidential);
+    let proof = info
+        .generate_proof_data(amount, &elgamal, &ae)
+        .map_err(|err| format!("While proving the withdrawal: {err}"))?;
+    let new_decryptable_available_balance = info
+        .new_decryptable_available_balance(amount, &ae)
+        .map_err(|err| format!("While decrypting the balance: {err}"))?;
+    let instruction = verify_withdraw(
+        Some(ContextStateInfo {
+            context_state_account: &context_state_account,
+            context_state_authority: &owner.pubkey(),
+        }),
+        &proof,
+    );
+
+    Ok(json!({
+        "instructions": [instruction_json(&instruction)],
+        "contextStateSpace": size_of::<ProofContextState<WithdrawProofContext>>(),
+        "newDecryptableAvailableBalance": new_decryptable_available_balance.to_bytes().to_vec(),
+    }))
+}
+
+/// The secret behind the shielded notes of `owner`,
+/// derived from their signature so that it never has to be stored
+fn shielded_secret(owner: &Keypair) -> Fr {
+    Fr::from_le_bytes_mod_order(owner.sign_message(b"araza shielded notes").as_ref())
+}
+
+/// Read a field element from its 32 big-endian bytes, the way the program takes it
+fn field(value: &Value, name: &str) -> Result<Fr, String> {
+    let bytes: [u8; 32] = serde_json::from_value::<Vec<u8>>(value.clone())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("Bad `{name}`"))?;
+    let element = Fr::from_be_bytes_mod_order(&bytes);
+    if field_bytes(&element) != bytes {
+        return Err(format!("`{name}` is not a field element"));
+    }
+    Ok(element)
+}
+
+/// A note the way the owner keeps it to spend it later
+fn note_json(note: &Note) -> Value {
+    json!({
+        "value": note.value,
+        "owner": field_bytes(&note.owner).to_vec(),
+        "blinding": field_bytes(&note.blinding).to_vec(),
+        "commitment": field_bytes(&note.commitment()).to_vec(),
+    })
+}
+
+/// The key the owner can be paid to in the shielded pool
+fn shielded_owner() -> Result<Value, String> {
+    let owner = owner_from_env()?;
+
+    Ok(json!({
+        "owner": field_bytes(&owner_of(shielded_secret(&owner))).to_vec(),
+    }))
+}
+
+/// Make a note of `value` DD for the owner to shield
+///
+/// The program only takes its value and what hides the rest of it.
+fn shield() -> Result<Value, String> {
+    let owner = owner_from_env()?;
+    let value = u64::from_str(&arg(2, "value")?).map_err(|_| "Bad `value`".to_string())?;
+    let note = Note {
+        value,
+        owner: owner_of(shielded_secret(&owner)),
+        blinding: Fr::rand(&mut OsRng),
+    };
+
+    Ok(json!({
+        "hiding": field_bytes(&note.hiding()).to_vec(),
+        "note": note_json(&note),
+    }))
+}
+
+/// Prove the spending of a note of the owner, as described on the standard input
+///
+/// The request gives every commitment in the pool in order as `leaves`,
+/// the `note` to spend with its `value`, `blinding` and `index`,
+/// the `value` and `owner` of the two `outputs`,
+/// and the `amount` that leaves the pool for the token account `recipient`, if any.
+fn spend() -> Result<Value, String> {
+    let owner = owner_from_env()?;
+    let request: Value =
+        serde_json::from_reader(std::io::stdin()).map_err(|_| "Bad request".to_string())?;
+    let amount = |value: &Value, name: &str| value.as_u64().ok_or_else(|| format!("Bad `{name}`"));
+
+    let leaves = request["leaves"]
+        .as_array()
+        .ok_or_else(|| "Bad `leaves`".to_string())?
+        .iter()
+        .map(|leaf| field(leaf, "leaves"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let secret = shielded_secret(&owner);
+    let note = Note {
+        value: amount(&request["note"]["value"], "note.value")?,
+        owner: owner_of(secret),
+        blinding: field(&request["note"]["blinding"], "note.blinding")?,
+    };
+    let index = amount(&request["note"]["index"], "note.index")?;
+    if leaves.get(index as usize) != Some(&note.commitment()) {
+        return Err("The note is not at `note.index`".to_string());
+    }
+    let tree = Tree::new(&leaves);
+    let outputs = ["outputs[0]", "outputs[1]"]
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            Ok(Note {
+                value: amount(&request["outputs"][i]["value"], name)?,
+                owner: field(&request["outputs"][i]["owner"], name)?,
+                blinding: Fr::rand(&mut OsRng),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let recipient = match request["recipient"].as_str() {
+        Some(address) => recipient_of(&pubkey_of(address, "recipient")?.to_bytes()),
+        None => Fr::zero(),
+    };
+
+    let spend = Spend {
+        secret,
+        note,
+        index,
+        path: tree.path(index as usize).unwrap(),
+        root: tree.root(),
+        outputs: [outputs[0], outputs[1]],
+        amount: amount(&request["amount"], "amount")?,
+        recipient,
+    };
+    let cs = spend.synthesize();
+    if !cs.is_satisfied() {
+        return Err("The outputs and the amount do not add up to the note".to_string());
+    }
+    let proof = groth16::prove(&spend_key(), &cs, &mut OsRng);
+    let public = spend.public_inputs();
+
+    Ok(json!({
+        "proof": proof.to_bytes().to_vec(),
+        "root": field_bytes(&public.root).to_vec(),
+        "nullifier": field_bytes(&public.nullifier).to_vec(),
+        "commitments": public.commitments.map(|commitment| field_bytes(&commitment).to_vec()),
+        "notes": spend.outputs.iter().map(note_json).collect::<Vec<_>>(),
+    }))
+}
+
+/// The verifying key as the program has it, in Rust
+fn verifying_key_source(vk: &VerifyingKey) -> String {
+    let bytes = |bytes: &[u8]| format!("{bytes:?}");
+    let gamma_abc: Vec<String> = vk
+        .gamma_abc_g1
+        .iter()
+        .map(|point| bytes(&g1_bytes(point)))
+        .collect();
+    format!(
+        "//! The verifying key of the spend circuit, written by `prover setup`: do not edit\n\n\
+         pub const ALPHA_G1: [u8; 64] = {};\n\
+         pub const BETA_G2: [u8; 128] = {};\n\
+         pub const GAMMA_G2: [u8; 128] = {};\n\
+         pub const DELTA_G2: [u8; 128] = {};\n\
+         pub const GAMMA_ABC_G1: [[u8; 64]; {}] = [{}];\n",
+        bytes(&g1_bytes(&vk.alpha_g1)),
+        bytes(&g2_bytes(&vk.beta_g2)),
+        bytes(&g2_bytes(&vk.gamma_g2)),
+        bytes(&g2_bytes(&vk.delta_g2)),
+        gamma_abc.len(),
+        gamma_abc.join(", "),
+    )
+}
+
+/// Make the keys of the spend circuit: the proving key for us and the verifying key for the program
+///
+/// Anyone who learns the randomness of the setup can forge proofs, and this one is ours alone,
+/// so a deployment that holds real DD needs a ceremony with several parties instead.
+fn setup() -> Result<Value, String> {
+    let proving_key = arg(2, "proving key")?;
+    let verifying_key = arg(3, "verifying key")?;
+
+    let cs = Spend::default().synthesize();
+    let pk = groth16::setup(&cs, &mut OsRng);
+    let mut bytes = Vec::new();
+    pk.serialize_compressed(&mut bytes)
+        .map_err(|err| format!("While serializing the proving key: {err}"))?;
+    std::fs::write(&proving_key, bytes)
+        .map_err(|err| format!("While writing `{proving_key}`: {err}"))?;
+    std::fs::write(&verifying_key, verifying_key_source(&pk.vk))
+        .map_err(|err| format!("While writing `{verifying_key}`: {err}"))?;
+
+    Ok(json!({
+        "constraints": cs.constraints.len(),
+        "inputs": cs.inputs.len(),
+    }))
+}
+
+fn main() {
+    let output = match std::env::args().nth(1).as_deref() {
+        Some("configure-account") => configure_account(),
+        Some("apply-pending-balance") => apply_pending_balance(),
+        Some("withdraw") => withdraw(),
+        Some("shielded-owner") => shielded_owner(),
+        Some("shield") => shield(),
+        Some("spend") => spend(),
+        Some("setup") => setup(),
+        Some(other) => Err(format!("Unknown command `{other}`")),
+        None => Err("Missing command".to_string()),
+    }
+    .unwrap();
+    println!("{output}");
+}
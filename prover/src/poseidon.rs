@@ -0,0 +1,80 @@
+//! The Poseidon hash the program gets from the `sol_poseidon` syscall,
+//! both computed and proven in the circuit
+use ark_bn254::Fr;
+use light_poseidon::parameters::bn254_x5::get_poseidon_parameters;
+use light_poseidon::{Poseidon, PoseidonHasher};
+
+use crate::r1cs::{ConstraintSystem, LinearCombination};
+
+/// The hash of `inputs`, the same the syscall gives for them in big-endian
+pub fn hash(inputs: &[Fr]) -> Fr {
+    Poseidon::<Fr>::new_circom(inputs.len())
+        .and_then(|mut poseidon| poseidon.hash(inputs))
+        .expect("Poseidon takes from 1 to 12 inputs")
+}
+
+/// Require the result to be the hash of `inputs`, and give it
+///
+/// Follows the permutation of `light_poseidon` round by round:
+/// only the S-boxes take constraints, three each, the rest stays linear.
+pub fn enforce_hash(cs: &mut ConstraintSystem, inputs: &[LinearCombination]) -> LinearCombination {
+    let params = u8::try_from(inputs.len() + 1)
+        .ok()
+        .and_then(|width| get_poseidon_parameters::<Fr>(width).ok())
+        .expect("Poseidon takes from 1 to 12 inputs");
+    let width = params.width;
+    let half_rounds = params.full_rounds / 2;
+
+    let mut state: Vec<LinearCombination> = std::iter::once(LinearCombination::zero())
+        .chain(inputs.iter().cloned())
+        .collect();
+    for round in 0..params.full_rounds + params.partial_rounds {
+        for (i, element) in state.iter_mut().enumerate() {
+            let constant = LinearCombination::constant(params.ark[round * width + i]);
+            *element = std::mem::take(element) + constant;
+        }
+
+        let is_full = round < half_rounds || round >= half_rounds + params.partial_rounds;
+        let boxed = if is_full { width } else { 1 };
+        for element in state.iter_mut().take(boxed) {
+            let x = std::mem::take(element);
+            let x2 = cs.product(x.clone(), x.clone());
+            let x4 = cs.product(x2.into(), x2.into());
+            *element = cs.product(x4.into(), x).into();
+        }
+
+        state = params
+            .mds
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .zip(&state)
+                    .fold(LinearCombination::zero(), |sum, (&m, element)| {
+                        sum + element.clone() * m
+                    })
+            })
+            .collect();
+    }
+    state.swap_remove(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proves_the_same_hash_it_computes() {
+        let inputs = [Fr::from(7u64), Fr::from(11u64)];
+        let mut cs = ConstraintSystem::default();
+        let variables: Vec<LinearCombination> = inputs
+            .iter()
+            .map(|&input| cs.witness(input).into())
+            .collect();
+
+        let result = enforce_hash(&mut cs, &variables);
+
+        assert_eq!(cs.value(&result), hash(&inputs));
+        assert!(cs.is_satisfied());
+        assert_eq!(cs.constraints.len(), 3 * (8 * 3 + 57));
+    }
+}
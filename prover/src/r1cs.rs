@@ -0,0 +1,203 @@
+//! A rank-1 constraint system, where every constraint says that `a * b = c`
+//! for linear combinations `a`, `b` and `c` of its variables
+use std::ops::{Add, Mul, Sub};
+
+use ark_bn254::Fr;
+use ark_ff::{BigInteger, One, PrimeField, Zero};
+
+/// A variable of the system, in the order the proving key lays them out
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Variable {
+    /// The constant one
+    One,
+    /// The `n`-th input, which the verifier sees
+    Input(usize),
+    /// The `n`-th witness, which only the prover knows
+    Witness(usize),
+}
+
+/// A sum of variables, each times a coefficient, sorted by variable
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinearCombination(pub Vec<(Variable, Fr)>);
+
+impl LinearCombination {
+    /// The combination of nothing, which is zero
+    pub fn zero() -> Self {
+        Self(Vec::new())
+    }
+
+    /// The constant `value`
+    pub fn constant(value: Fr) -> Self {
+        Self::from(Variable::One) * value
+    }
+}
+
+impl From<Variable> for LinearCombination {
+    fn from(variable: Variable) -> Self {
+        Self(vec![(variable, Fr::one())])
+    }
+}
+
+impl Add for LinearCombination {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        let mut terms = Vec::with_capacity(self.0.len() + other.0.len());
+        let (mut left, mut right) = (
+            self.0.into_iter().peekable(),
+            other.0.into_iter().peekable(),
+        );
+        loop {
+            let term = match (left.peek(), right.peek()) {
+                (Some(l), Some(r)) if l.0 == r.0 => {
+                    let (variable, coefficient) = left.next().unwrap();
+                    (variable, coefficient + right.next().unwrap().1)
+                }
+                (Some(l), Some(r)) if l.0 < r.0 => left.next().unwrap(),
+                (Some(_), Some(_)) | (None, Some(_)) => right.next().unwrap(),
+                (Some(_), None) => left.next().unwrap(),
+                (None, None) => break,
+            };
+            if !term.1.is_zero() {
+                terms.push(term);
+            }
+        }
+        Self(terms)
+    }
+}
+
+impl Sub for LinearCombination {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self + other * -Fr::one()
+    }
+}
+
+impl Mul<Fr> for LinearCombination {
+    type Output = Self;
+
+    fn mul(self, coefficient: Fr) -> Self {
+        if coefficient.is_zero() {
+            return Self::zero();
+        }
+        Self(
+            self.0
+                .into_iter()
+                .map(|(variable, c)| (variable, c * coefficient))
+                .collect(),
+        )
+    }
+}
+
+/// The constraints of a circuit, along with the values of its variables
+///
+/// The shape of the system depends on the circuit alone, never on the values,
+/// so that the one made for the setup fits the proofs made later.
+#[derive(Default)]
+pub struct ConstraintSystem {
+    pub inputs: Vec<Fr>,
+    pub witnesses: Vec<Fr>,
+    pub constraints: Vec<[LinearCombination; 3]>,
+}
+
+impl ConstraintSystem {
+    /// Add an input the verifier will see, worth `value`
+    pub fn input(&mut self, value: Fr) -> Variable {
+        self.inputs.push(value);
+        Variable::Input(self.inputs.len() - 1)
+    }
+
+    /// Add a witness only the prover knows, worth `value`
+    pub fn witness(&mut self, value: Fr) -> Variable {
+        self.witnesses.push(value);
+        Variable::Witness(self.witnesses.len() - 1)
+    }
+
+    /// Require that `a * b = c`
+    pub fn enforce(&mut self, a: LinearCombination, b: LinearCombination, c: LinearCombination) {
+        self.constraints.push([a, b, c]);
+    }
+
+    /// Require that `a = b`
+    pub fn enforce_equal(&mut self, a: LinearCombination, b: LinearCombination) {
+        self.enforce(a, Variable::One.into(), b);
+    }
+
+    /// A witness for the product of `a` and `b`
+    pub fn product(&mut self, a: LinearCombination, b: LinearCombination) -> Variable {
+        let product = self.witness(self.value(&a) * self.value(&b));
+        self.enforce(a, b, product.into());
+        product
+    }
+
+    /// A witness for `bit`, which can only be zero or one
+    pub fn boolean(&mut self, bit: bool) -> Variable {
+        let variable = self.witness(Fr::from(bit));
+        self.enforce(
+            variable.into(),
+            LinearCombination::from(variable) - Variable::One.into(),
+            LinearCombination::zero(),
+        );
+        variable
+    }
+
+    /// Require that `number` fits in `bits` bits
+    pub fn enforce_bits(&mut self, number: LinearCombination, bits: usize) {
+        let value = self.value(&number).into_bigint().to_bits_le();
+        let mut sum = LinearCombination::zero();
+        let mut power = Fr::one();
+        for &bit in &value[..bits] {
+            let bit = self.boolean(bit);
+            sum = sum + LinearCombination::from(bit) * power;
+            power = power + power;
+        }
+        self.enforce_equal(sum, number);
+    }
+
+    /// Where the proving key has `variable`, among all the variables
+    pub fn index(&self, variable: Variable) -> usize {
+        match variable {
+            Variable::One => 0,
+            Variable::Input(n) => 1 + n,
+            Variable::Witness(n) => 1 + self.inputs.len() + n,
+        }
+    }
+
+    /// How many variables there are, the constant one included
+    pub fn num_variables(&self) -> usize {
+        1 + self.inputs.len() + self.witnesses.len()
+    }
+
+    /// The values of all the variables, in the order of `index`
+    pub fn assignment(&self) -> Vec<Fr> {
+        let mut assignment = Vec::with_capacity(self.num_variables());
+        assignment.push(Fr::one());
+        assignment.extend_from_slice(&self.inputs);
+        assignment.extend_from_slice(&self.witnesses);
+        assignment
+    }
+
+    /// The value of `combination` under the current values of the variables
+    pub fn value(&self, combination: &LinearCombination) -> Fr {
+        combination
+            .0
+            .iter()
+            .map(|&(variable, coefficient)| {
+                coefficient
+                    * match variable {
+                        Variable::One => Fr::one(),
+                        Variable::Input(n) => self.inputs[n],
+                        Variable::Witness(n) => self.witnesses[n],
+                    }
+            })
+            .sum()
+    }
+
+    /// Whether the values of the variables meet every constraint
+    pub fn is_satisfied(&self) -> bool {
+        self.constraints
+            .iter()
+            .all(|[a, b, c]| self.value(a) * self.value(b) == self.value(c))
+    }
+}
@@ -0,0 +1,112 @@
+//! The Merkle tree of note commitments, rebuilt the way the program builds it
+use ark_bn254::Fr;
+use ark_ff::Zero;
+
+use crate::poseidon::hash;
+
+/// How deep the tree is, so it has room for `2^DEPTH` notes
+pub const DEPTH: usize = 20;
+
+/// The root of an empty tree of every height up to `DEPTH`, with empty leaves worth zero
+pub fn zeros() -> [Fr; DEPTH + 1] {
+    let mut zeros = [Fr::zero(); DEPTH + 1];
+    for level in 0..DEPTH {
+        zeros[level + 1] = hash(&[zeros[level], zeros[level]]);
+    }
+    zeros
+}
+
+/// Every node of the tree that is not an empty subtree, level by level from the leaves up
+pub struct Tree {
+    levels: Vec<Vec<Fr>>,
+}
+
+impl Tree {
+    /// The tree of `leaves`, inserted in this order
+    pub fn new(leaves: &[Fr]) -> Self {
+        let zeros = zeros();
+        let mut levels = vec![leaves.to_vec()];
+        for zero in &zeros[..DEPTH] {
+            let below = levels.last().unwrap();
+            let level = below
+                .chunks(2)
+                .map(|pair| hash(&[pair[0], pair.get(1).copied().unwrap_or(*zero)]))
+                .collect();
+            levels.push(level);
+        }
+        Self { levels }
+    }
+
+    /// The root the program remembers once all the leaves are in
+    pub fn root(&self) -> Fr {
+        self.levels[DEPTH]
+            .first()
+            .copied()
+            .unwrap_or_else(|| zeros()[DEPTH])
+    }
+
+    /// The siblings of the leaf at `index` from the bottom up, or nothing if there is no such leaf
+    pub fn path(&self, index: usize) -> Option<[Fr; DEPTH]> {
+        if index >= self.levels[0].len() {
+            return None;
+        }
+        let zeros = zeros();
+        let mut path = [Fr::zero(); DEPTH];
+        for (level, sibling) in path.iter_mut().enumerate() {
+            let position = (index >> level) ^ 1;
+            *sibling = self.levels[level]
+                .get(position)
+                .copied()
+                .unwrap_or(zeros[level]);
+        }
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groth16::field_bytes;
+
+    #[test]
+    fn is_as_deep_as_the_tree_of_the_program() {
+        assert_eq!(DEPTH, araza::shielded::DEPTH);
+    }
+
+    #[test]
+    fn hashes_the_way_the_program_does() {
+        let leaves = [Fr::from(1u64), Fr::from(2u64), Fr::from(3u64)];
+        let [a, b, c] = leaves.map(|leaf| field_bytes(&leaf));
+        let hash =
+            |left: &[u8; 32], right: &[u8; 32]| araza::shielded::hash_pair(left, right).unwrap();
+
+        let mut root = hash(&hash(&a, &b), &hash(&c, &[0; 32]));
+        for zero in &zeros()[2..DEPTH] {
+            root = hash(&root, &field_bytes(zero));
+        }
+
+        assert_eq!(field_bytes(&Tree::new(&leaves).root()), root);
+    }
+
+    #[test]
+    fn leads_every_leaf_to_the_root() {
+        let leaves: Vec<Fr> = (1..=5u64).map(Fr::from).collect();
+        let tree = Tree::new(&leaves);
+
+        for (index, &leaf) in leaves.iter().enumerate() {
+            let path = tree.path(index).unwrap();
+            let root = path
+                .iter()
+                .enumerate()
+                .fold(leaf, |node, (level, &sibling)| {
+                    if index >> level & 1 == 0 {
+                        hash(&[node, sibling])
+                    } else {
+                        hash(&[sibling, node])
+                    }
+                });
+            assert_eq!(root, tree.root());
+        }
+        assert_eq!(tree.path(leaves.len()), None);
+    }
+}
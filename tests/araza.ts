@@ -1,9 +1,15 @@
+import { execFileSync } from 'node:child_process'
+
 import * as anchor from '@coral-xyz/anchor'
 import type { Program } from '@coral-xyz/anchor'
 
 import {
 	createAssociatedTokenAccount,
 	createMint,
+	createReallocateInstruction,
+	ExtensionType,
+	getExtensionTypes,
+	getMint,
 	mintTo,
 	TOKEN_2022_PROGRAM_ID,
 } from '@solana/spl-token'
@@ -41,6 +47,142 @@ const [ddMint, _ddMintBump] = anchor.web3.PublicKey.findProgramAddressSync(
 	program.programId,
 )
 
+/** Any valid Ristretto point will do as an auditor key; this one is the basepoint */
+const auditorElgamalPubkey = Buffer.from(
+	'e2f2ae0a6abc4e71a884a961c500515f58e30b6aa582dd8db6a65945e08d2d76',
+	'hex',
+)
+
+/** The program that verifies the proofs of confidential transfers */
+const ZK_TOKEN_PROOF_PROGRAM_ID = new anchor.web3.PublicKey(
+	'ZkTokenProof1111111111111111111111111111111',
+)
+
+/** An instruction as the prover prints it */
+type ProvenInstruction = {
+	programId: string
+	keys: { pubkey: string; isSigner: boolean; isWritable: boolean }[]
+	data: string
+}
+
+/**
+ * Run the prover for the owner, who alone can decrypt their balances
+ * and spend their notes, handing it `input` if the command reads any
+ */
+const runProver = (args: string[], input?: string) =>
+	JSON.parse(
+		execFileSync(
+			'cargo',
+			['run', '--quiet', '--package', 'prover', '--', ...args],
+			{
+				env: {
+					...process.env,
+					OWNER_SECRET_KEY: JSON.stringify([...owner.secretKey]),
+				},
+				input,
+			},
+		).toString(),
+	)
+
+/** Run a command of the prover that takes only arguments */
+const prove = (...args: string[]) => runProver(args)
+
+/** A note of the shielded pool, as the prover makes it for its owner */
+type Note = {
+	value: number
+	owner: number[]
+	blinding: number[]
+	commitment: number[]
+}
+
+/**
+ * Have the owner prove they spend `note`,
+ * at `index` among all the `leaves` of the pool
+ */
+const proveSpend = (request: {
+	leaves: number[][]
+	note: Note & { index: number }
+	outputs: { value: number; owner: number[] }[]
+	amount: number
+	recipient?: string
+}) => runProver(['spend'], JSON.stringify(request))
+
+/** Enough compute for the pool to verify a proof and append two notes */
+const spendBudget = anchor.web3.ComputeBudgetProgram.setComputeUnitLimit({
+	units: 1_400_000,
+})
+
+/** Send what the prover made, paid and signed by the owner */
+const sendProven = (
+	instructions: ProvenInstruction[],
+	...before: anchor.web3.TransactionInstruction[]
+) =>
+	anchor.web3.sendAndConfirmTransaction(
+		provider.connection,
+		new anchor.web3.Transaction().add(
+			...before,
+			...instructions.map(
+				({ programId, keys, data }) =>
+					new anchor.web3.TransactionInstruction({
+						programId: new anchor.web3.PublicKey(programId),
+						keys: keys.map((key) => ({
+							...key,
+							pubkey: new anchor.web3.PublicKey(key.pubkey),
+						})),
+						data: Buffer.from(data, 'hex'),
+					}),
+			),
+		),
+		[owner],
+	)
+
+/** The raw data of `account`, as the prover reads it */
+const accountData = async (account: PublicKey) => {
+	const info = await provider.connection.getAccountInfo(account)
+	return (info?.data ?? Buffer.alloc(0)).toString('hex')
+}
+
+/**
+ * Have the owner prove they can withdraw `amount` of their confidential DD,
+ * verified into a fresh context account, which the program is then given
+ */
+const proveWithdrawal = async (amount: number) => {
+	const context = anchor.web3.Keypair.generate()
+	const { instructions, contextStateSpace, newDecryptableAvailableBalance } =
+		prove(
+			'withdraw',
+			ownersDdAccount.toBase58(),
+			await accountData(ownersDdAccount),
+			`${amount}`,
+			context.publicKey.toBase58(),
+		)
+	// The proof alone nearly fills a transaction,
+	// so the account is made in one of its own:
+	const created = await anchor.web3.sendAndConfirmTransaction(
+		provider.connection,
+		new anchor.web3.Transaction().add(
+			anchor.web3.SystemProgram.createAccount({
+				fromPubkey: owner.publicKey,
+				newAccountPubkey: context.publicKey,
+				space: contextStateSpace,
+				lamports:
+					await provider.connection.getMinimumBalanceForRentExemption(
+						contextStateSpace,
+					),
+				programId: ZK_TOKEN_PROOF_PROGRAM_ID,
+			}),
+		),
+		[owner, context],
+	)
+	console.log('Made the proof context at', created)
+	const verified = await sendProven(instructions)
+	console.log('Verified the withdraw proof at', verified)
+	return {
+		proofContext: context.publicKey,
+		newDecryptableAvailableBalance: newDecryptableAvailableBalance as number[],
+	}
+}
+
 before(async () => {
 	const airdrop = await provider.connection.requestAirdrop(
 		owner.publicKey,
@@ -79,7 +221,16 @@ describe('araza', () => {
 			.rpc()
 		console.log('Initialized at', tx)
 		await untilConfirmed(provider, tx)
-		// with two transactions:
+		// and make DD confidential:
+		const tx1 = await program.methods
+			.configureConfidential([...auditorElgamalPubkey])
+			.accounts({
+				signer: provider.wallet.publicKey,
+			})
+			.rpc()
+		await untilConfirmed(provider, tx1)
+		console.log('Made confidential at', tx1)
+		// with three transactions:
 		const tx2 = await program.methods
 			.configure()
 			.accounts({
@@ -88,11 +239,78 @@ describe('araza', () => {
 				tokenProgram: TOKEN_2022_PROGRAM_ID,
 			})
 			.rpc()
-		// then both of those should succeed.
+		// then all of those should succeed.
 		await untilConfirmed(provider, tx2)
 		console.log('Configured at', tx2)
 	})
 
+	it('mints DD with confidential transfers audited by the treasurer', async () => {
+		// Given the program configured above,
+		// when we look at the DD mint:
+		const mint = await provider.connection.getAccountInfo(ddMint)
+		const parsed = await getMint(
+			provider.connection,
+			ddMint,
+			void null,
+			TOKEN_2022_PROGRAM_ID,
+		)
+
+		// Then it should be a Token-2022 mint:
+		expect(mint?.owner.toBase58()).to.be.eq(TOKEN_2022_PROGRAM_ID.toBase58())
+		// With confidential transfers on:
+		expect(getExtensionTypes(parsed.tlvData)).to.include(
+			ExtensionType.ConfidentialTransferMint,
+		)
+
+		// And the program should remember the auditor key:
+		const [statePda] = anchor.web3.PublicKey.findProgramAddressSync(
+			[],
+			program.programId,
+		)
+		const state = await program.account.programState.fetch(statePda)
+		expect(Buffer.from(state.auditorElgamalPubkey)).to.deep.eq(
+			auditorElgamalPubkey,
+		)
+	})
+
+	it('leaves a state and a mint that are up to date alone', async () => {
+		// Given the program configured above,
+		const [statePda] = anchor.web3.PublicKey.findProgramAddressSync(
+			[],
+			program.programId,
+		)
+		const stateBefore = await provider.connection.getAccountInfo(statePda)
+		const mintBefore = await provider.connection.getAccountInfo(ddMint)
+
+		// When we migrate its state:
+		const migrated = await program.methods
+			.migrateState()
+			.accounts({
+				signer: provider.wallet.publicKey,
+			})
+			.rpc()
+		await untilConfirmed(provider, migrated)
+		// And try to make DD confidential once again:
+		const rejection = await program.methods
+			.configureConfidential([...Buffer.alloc(32)])
+			.accounts({
+				signer: provider.wallet.publicKey,
+			})
+			.rpc()
+			.then(
+				() => null,
+				(error: Error) => error,
+			)
+
+		// Then the second one should be refused:
+		expect(rejection?.message).to.include('AlreadyMinted')
+		// And neither should have changed a thing:
+		const stateAfter = await provider.connection.getAccountInfo(statePda)
+		const mintAfter = await provider.connection.getAccountInfo(ddMint)
+		expect(stateAfter?.data).to.deep.eq(stateBefore?.data)
+		expect(mintAfter?.data).to.deep.eq(mintBefore?.data)
+	})
+
 	it('gives the user some USDC', async () => {
 		// When we associate an account with user's:
 		ownersUsdcAccount = await createAssociatedTokenAccount(
@@ -221,6 +439,256 @@ describe('araza', () => {
 		)
 	})
 
+	it('moves DD in and out of the confidential balance with proofs', async () => {
+		// Given the DD account of the user, set up to hold confidential DD:
+		const configured = await sendProven(
+			prove(
+				'configure-account',
+				ownersDdAccount.toBase58(),
+				ddMint.toBase58(),
+			).instructions,
+			createReallocateInstruction(
+				ownersDdAccount,
+				owner.publicKey,
+				[ExtensionType.ConfidentialTransferAccount],
+				owner.publicKey,
+				[],
+				TOKEN_2022_PROGRAM_ID,
+			),
+		)
+		console.log('Made the DD account confidential at', configured)
+		// And given a public balance of some DD:
+		const ddBalanceBefore =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+
+		// When they move some of it to their confidential balance:
+		const deposited = await program.methods
+			.depositConfidential(new BN(40_000000))
+			.accounts({
+				user: owner.publicKey,
+				tokenAccount: ownersDdAccount,
+			})
+			.signers([owner])
+			.rpc()
+		await untilConfirmed(provider, deposited)
+		// And apply it, to be able to spend it:
+		const applied = await sendProven(
+			prove(
+				'apply-pending-balance',
+				ownersDdAccount.toBase58(),
+				await accountData(ownersDdAccount),
+			).instructions,
+		)
+		console.log('Applied the pending balance at', applied)
+
+		// Then the public balance should go down by as much:
+		const ddBalanceDeposited =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+		expect(ddBalanceDeposited.value.uiAmount).to.be.eq(
+			(ddBalanceBefore.value.uiAmount ?? 0) - 40,
+		)
+
+		// When they withdraw more than they proved they have:
+		const tooLittle = await proveWithdrawal(10_000000)
+		const rejection = await program.methods
+			.withdrawConfidential(
+				new BN(40_000000),
+				tooLittle.newDecryptableAvailableBalance,
+			)
+			.accounts({
+				user: owner.publicKey,
+				tokenAccount: ownersDdAccount,
+				proofContext: tooLittle.proofContext,
+			})
+			.signers([owner])
+			.rpc()
+			.then(
+				() => null,
+				(error: Error) => error,
+			)
+
+		// Then the withdraw should be rejected:
+		expect(rejection).to.be.instanceOf(Error)
+		// And the public balance should stay as it was:
+		const ddBalanceRejected =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+		expect(ddBalanceRejected.value.uiAmount).to.be.eq(
+			ddBalanceDeposited.value.uiAmount,
+		)
+
+		// When they withdraw all of it, with a proof for as much:
+		const proof = await proveWithdrawal(40_000000)
+		const withdrawn = await program.methods
+			.withdrawConfidential(
+				new BN(40_000000),
+				proof.newDecryptableAvailableBalance,
+			)
+			.accounts({
+				user: owner.publicKey,
+				tokenAccount: ownersDdAccount,
+				proofContext: proof.proofContext,
+			})
+			.signers([owner])
+			.rpc()
+		await untilConfirmed(provider, withdrawn)
+
+		// Then the public balance should be back to where it was:
+		const ddBalanceAfter =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+		expect(ddBalanceAfter.value.uiAmount).to.be.eq(
+			ddBalanceBefore.value.uiAmount,
+		)
+	}).timeout(120_000)
+
+	it('moves DD through the shielded pool, spending every note once', async () => {
+		// Given the shielded pool:
+		const configured = await program.methods
+			.configureShielded()
+			.accounts({
+				signer: provider.wallet.publicKey,
+				tokenProgram: TOKEN_2022_PROGRAM_ID,
+			})
+			.rpc()
+		await untilConfirmed(provider, configured)
+		console.log('Opened the shielded pool at', configured)
+		// And given a public balance of some DD:
+		const ddBalanceBefore =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+		const { owner: shieldedOwner } = prove('shielded-owner')
+
+		// When the user shields some of it:
+		const shielded = prove('shield', '30000000')
+		const shieldTx = await program.methods
+			.shield(new BN(30_000000), shielded.hiding)
+			.accounts({
+				user: owner.publicKey,
+				fromAccount: ownersDdAccount,
+				tokenProgram: TOKEN_2022_PROGRAM_ID,
+			})
+			.signers([owner])
+			.rpc()
+		await untilConfirmed(provider, shieldTx)
+		const leaves: number[][] = [shielded.note.commitment]
+
+		// Then the public balance should go down by as much:
+		const ddBalanceShielded =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+		expect(ddBalanceShielded.value.uiAmount).to.be.eq(
+			(ddBalanceBefore.value.uiAmount ?? 0) - 30,
+		)
+
+		// When they split the note in two in private:
+		const split = proveSpend({
+			leaves,
+			note: { ...shielded.note, index: 0 },
+			outputs: [
+				{ value: 10_000000, owner: shieldedOwner },
+				{ value: 20_000000, owner: shieldedOwner },
+			],
+			amount: 0,
+		})
+		const splitTx = await program.methods
+			.privateTransfer(
+				split.proof,
+				split.root,
+				split.nullifier,
+				split.commitments,
+			)
+			.accounts({
+				user: owner.publicKey,
+			})
+			.preInstructions([spendBudget])
+			.signers([owner])
+			.rpc()
+		await untilConfirmed(provider, splitTx)
+		leaves.push(...split.commitments)
+		console.log('Transferred in private at', splitTx)
+
+		// Then the note should be spent for good:
+		const respent = await program.methods
+			.privateTransfer(
+				split.proof,
+				split.root,
+				split.nullifier,
+				split.commitments,
+			)
+			.accounts({
+				user: owner.publicKey,
+			})
+			.preInstructions([spendBudget])
+			.signers([owner])
+			.rpc()
+			.then(
+				() => null,
+				(error: Error) => error,
+			)
+		expect(respent).to.be.instanceOf(Error)
+
+		// When they unshield a note for more than its proof says:
+		const [small, large] = split.notes as Note[]
+		const unshieldLarge = proveSpend({
+			leaves,
+			note: { ...large, index: 2 },
+			outputs: [
+				{ value: 0, owner: shieldedOwner },
+				{ value: 0, owner: shieldedOwner },
+			],
+			amount: 20_000000,
+			recipient: ownersDdAccount.toBase58(),
+		})
+		const unshield = (amount: number, spend: typeof unshieldLarge) =>
+			program.methods
+				.unshield(
+					new BN(amount),
+					spend.proof,
+					spend.root,
+					spend.nullifier,
+					spend.commitments,
+				)
+				.accounts({
+					user: owner.publicKey,
+					toAccount: ownersDdAccount,
+					tokenProgram: TOKEN_2022_PROGRAM_ID,
+				})
+				.preInstructions([spendBudget])
+				.signers([owner])
+				.rpc()
+		const forged = await unshield(30_000000, unshieldLarge).then(
+			() => null,
+			(error: Error) => error,
+		)
+
+		// Then the proof should be rejected:
+		expect(forged?.message).to.include('InvalidProof')
+
+		// When they unshield both notes with the right amounts:
+		const unshieldedLarge = await unshield(20_000000, unshieldLarge)
+		await untilConfirmed(provider, unshieldedLarge)
+		leaves.push(...unshieldLarge.commitments)
+		const unshieldedSmall = await unshield(
+			10_000000,
+			proveSpend({
+				leaves,
+				note: { ...small, index: 1 },
+				outputs: [
+					{ value: 0, owner: shieldedOwner },
+					{ value: 0, owner: shieldedOwner },
+				],
+				amount: 10_000000,
+				recipient: ownersDdAccount.toBase58(),
+			}),
+		)
+		await untilConfirmed(provider, unshieldedSmall)
+		console.log('Unshielded at', unshieldedSmall)
+
+		// Then the public balance should be back to where it was:
+		const ddBalanceAfter =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+		expect(ddBalanceAfter.value.uiAmount).to.be.eq(
+			ddBalanceBefore.value.uiAmount,
+		)
+	}).timeout(300_000)
+
 	it('offers DD for fiat exchange', async () => {
 		// Given some DD:
 		const ddBalanceBefore =
@@ -262,9 +730,10 @@ describe('araza', () => {
 
 		// When we release the funds:
 		const tx = await program.methods
-			.releaseFunds()
+			.releaseFunds([...Buffer.alloc(32, 7)])
 			.accounts({
 				user: owner.publicKey,
+				buyer: owner.publicKey,
 				treasurer: treasurer.publicKey,
 				beneficiary: ownersDdAccount,
 				tokenProgram: TOKEN_2022_PROGRAM_ID,
@@ -287,4 +756,227 @@ describe('araza', () => {
 			await provider.connection.getAccountInfo(escrowAccount)
 		expect(escrowAccountInfo).to.be.null
 	}).timeout(10_000)
+
+	it('counts the settled deal in the user stats', async () => {
+		// Given the deal released above, where the user has sold to themselves:
+		const [userStats] = anchor.web3.PublicKey.findProgramAddressSync(
+			[Buffer.from('stats'), owner.publicKey.toBuffer()],
+			program.programId,
+		)
+
+		// When we look up their stats:
+		const stats = await program.account.userStats.fetch(userStats)
+
+		// Then the deal should be counted once, with its whole volume:
+		expect(stats.dealsCompleted.toNumber()).to.be.eq(1)
+		expect(stats.volume.toNumber()).to.be.eq(100_000000)
+		expect(stats.cancellations.toNumber()).to.be.eq(0)
+		expect(stats.disputesLost.toNumber()).to.be.eq(0)
+	})
+
+	it('returns the escrow on cancel', async () => {
+		// Given some DD offered for fiat:
+		const offered = await program.methods
+			.offerDd(new BN(50_000000))
+			.accounts({
+				user: owner.publicKey,
+				fromAccount: ownersDdAccount,
+				tokenProgram: TOKEN_2022_PROGRAM_ID,
+			})
+			.signers([owner])
+			.rpc()
+		await untilConfirmed(provider, offered)
+		const ddBalanceBefore =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+
+		// When the treasurer calls the offer off:
+		const tx = await program.methods
+			.cancel()
+			.accounts({
+				user: owner.publicKey,
+				treasurer: treasurer.publicKey,
+				refundAccount: ownersDdAccount,
+				tokenProgram: TOKEN_2022_PROGRAM_ID,
+			})
+			.signers([treasurer])
+			.rpc()
+		await untilConfirmed(provider, tx)
+
+		// Then the user should get their DD back:
+		const ddBalanceAfter =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+		expect(ddBalanceAfter.value.uiAmount).to.be.eq(
+			(ddBalanceBefore.value.uiAmount ?? 0) + 50,
+		)
+
+		// And the cancellation should be on their record:
+		const [userStats] = anchor.web3.PublicKey.findProgramAddressSync(
+			[Buffer.from('stats'), owner.publicKey.toBuffer()],
+			program.programId,
+		)
+		const stats = await program.account.userStats.fetch(userStats)
+		expect(stats.cancellations.toNumber()).to.be.eq(1)
+	}).timeout(10_000)
+
+	it('holds the bonds behind the fiat offers until they are returned', async () => {
+		// Given some DD:
+		const ddBalanceBefore =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+
+		// When we post a bond:
+		const {
+			signature: tx,
+			pubkeys: { bond },
+		} = await program.methods
+			.postBond(new BN(2_000000))
+			.accounts({
+				user: owner.publicKey,
+				fromAccount: ownersDdAccount,
+				tokenProgram: TOKEN_2022_PROGRAM_ID,
+			})
+			.signers([owner])
+			.rpcAndKeys()
+		await untilConfirmed(provider, tx)
+
+		// Then the bond should hold exactly that much:
+		const bondBalance = await provider.connection.getTokenAccountBalance(bond)
+		expect(bondBalance.value.uiAmount).to.be.eq(2)
+
+		// And when we post one more for another offer:
+		const topUp = await program.methods
+			.postBond(new BN(3_000000))
+			.accounts({
+				user: owner.publicKey,
+				fromAccount: ownersDdAccount,
+				tokenProgram: TOKEN_2022_PROGRAM_ID,
+			})
+			.signers([owner])
+			.rpc()
+		await untilConfirmed(provider, topUp)
+
+		// Then the same bond should hold both:
+		const toppedUp = await provider.connection.getTokenAccountBalance(bond)
+		expect(toppedUp.value.uiAmount).to.be.eq(5)
+
+		// And when the treasurer returns it:
+		const tx2 = await program.methods
+			.returnBond()
+			.accounts({
+				user: owner.publicKey,
+				treasurer: treasurer.publicKey,
+				refundAccount: ownersDdAccount,
+				tokenProgram: TOKEN_2022_PROGRAM_ID,
+			})
+			.signers([treasurer])
+			.rpc()
+		await untilConfirmed(provider, tx2)
+
+		// Then the user should have all their DD back:
+		const ddBalanceAfter =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+		expect(ddBalanceAfter.value.uiAmount).to.be.eq(
+			ddBalanceBefore.value.uiAmount,
+		)
+		// And the bond account should be closed:
+		const bondAccountInfo = await provider.connection.getAccountInfo(bond)
+		expect(bondAccountInfo).to.be.null
+	}).timeout(10_000)
+
+	it('slashes the bond of a buyer who never paid', async () => {
+		// Given a bond posted by the buyer:
+		const {
+			signature: tx,
+			pubkeys: { bond },
+		} = await program.methods
+			.postBond(new BN(2_000000))
+			.accounts({
+				user: owner.publicKey,
+				fromAccount: ownersDdAccount,
+				tokenProgram: TOKEN_2022_PROGRAM_ID,
+			})
+			.signers([owner])
+			.rpcAndKeys()
+		await untilConfirmed(provider, tx)
+		const [userStats] = anchor.web3.PublicKey.findProgramAddressSync(
+			[Buffer.from('stats'), owner.publicKey.toBuffer()],
+			program.programId,
+		)
+		const statsBefore = await program.account.userStats.fetch(userStats)
+
+		const ddBalanceBefore =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+
+		// When the treasurer slashes the part that backed one deal in favor of the seller,
+		// who is the same user here:
+		const tx2 = await program.methods
+			.slashBond(new BN(500000))
+			.accounts({
+				user: owner.publicKey,
+				seller: owner.publicKey,
+				treasurer: treasurer.publicKey,
+				beneficiary: ownersDdAccount,
+				tokenProgram: TOKEN_2022_PROGRAM_ID,
+			})
+			.signers([treasurer])
+			.rpc()
+		await untilConfirmed(provider, tx2)
+
+		// Then the buyer should have the missed deal on their record:
+		const statsAfter = await program.account.userStats.fetch(userStats)
+		expect(statsAfter.cancellations.toNumber()).to.be.eq(
+			statsBefore.cancellations.toNumber() + 1,
+		)
+		// And only that part should have left the bond:
+		const ddBalanceAfter =
+			await provider.connection.getTokenAccountBalance(ownersDdAccount)
+		expect(ddBalanceAfter.value.uiAmount).to.be.eq(
+			ddBalanceBefore.value.uiAmount + 0.5,
+		)
+		const bondBalance = await provider.connection.getTokenAccountBalance(bond)
+		expect(bondBalance.value.uiAmount).to.be.eq(1.5)
+	}).timeout(10_000)
+
+	it('releases the escrow one portion at a time', async () => {
+		// Given some DD offered for fiat:
+		const {
+			signature: offered,
+			pubkeys: { escrow },
+		} = await program.methods
+			.offerDd(new BN(60_000000))
+			.accounts({
+				user: owner.publicKey,
+				fromAccount: ownersDdAccount,
+				tokenProgram: TOKEN_2022_PROGRAM_ID,
+			})
+			.signers([owner])
+			.rpcAndKeys()
+		await untilConfirmed(provider, offered)
+		const releasePortion = (amount: number) =>
+			program.methods
+				.releasePortion(new BN(amount), [...Buffer.alloc(32, 7)])
+				.accounts({
+					user: owner.publicKey,
+					buyer: owner.publicKey,
+					treasurer: treasurer.publicKey,
+					beneficiary: ownersDdAccount,
+					tokenProgram: TOKEN_2022_PROGRAM_ID,
+				})
+				.signers([treasurer])
+				.rpc()
+
+		// When a part of it goes to one buyer:
+		await untilConfirmed(provider, await releasePortion(20_000000))
+
+		// Then the rest should stay in escrow:
+		const escrowBalance =
+			await provider.connection.getTokenAccountBalance(escrow)
+		expect(escrowBalance.value.uiAmount).to.be.eq(40)
+
+		// And when the rest goes to another:
+		await untilConfirmed(provider, await releasePortion(40_000000))
+
+		// Then the escrow account should be closed:
+		const escrowAccountInfo = await provider.connection.getAccountInfo(escrow)
+		expect(escrowAccountInfo).to.be.null
+	}).timeout(10_000)
 })